@@ -0,0 +1,118 @@
+//! Bandwidth saved by compressing clips before sealing, and what it costs.
+//!
+//! Run with `cargo bench -p clipsync-common`; the sizes are printed before the
+//! timings.
+
+use clipsync_common::{decrypt_item, encrypt_item, to_msgpack, ClipboardItem, EncryptionKey};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+/// About 1 MiB of server log lines
+fn log_text() -> ClipboardItem {
+    let text: String = (0..12_000)
+        .map(|i| {
+            format!(
+                "2026-03-14T09:{:02}:{:02}.{:03}Z INFO  http: GET /api/v1/items/{} status=200 latency={}ms\n",
+                i / 3600 % 60,
+                i / 60 % 60,
+                i % 1000,
+                i * 7 % 5000,
+                i % 37
+            )
+        })
+        .collect();
+    ClipboardItem::text(text)
+}
+
+/// A pretty-printed JSON dump of about 600 KiB
+fn json_dump() -> ClipboardItem {
+    let records: Vec<String> = (0..4_000)
+        .map(|i| {
+            format!(
+                "  {{\n    \"id\": {},\n    \"name\": \"user-{}\",\n    \"email\": \"user{}@example.com\",\n    \"active\": {},\n    \"roles\": [\"reader\", \"writer\"]\n  }}",
+                i,
+                i,
+                i,
+                i % 3 == 0
+            )
+        })
+        .collect();
+    ClipboardItem::text(format!("[\n{}\n]", records.join(",\n")))
+}
+
+/// A 1280x800 PNG with flat panels, text-like stripes and a gradient, like a
+/// screenshot of an application window
+fn screenshot() -> ClipboardItem {
+    let (width, height) = (1280u32, 800u32);
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let rgba = if y < 40 {
+                [45, 45, 48, 255]
+            } else if x < 240 {
+                [30, 30, 30, 255]
+            } else if y % 18 < 10 && (x * 7 + y * 3) % 11 < 6 {
+                [220, 220, 220, 255]
+            } else {
+                [(x / 5) as u8, (y / 4) as u8, 120, 255]
+            };
+            pixels.extend_from_slice(&rgba);
+        }
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .expect("encoding an in-memory PNG cannot fail");
+    ClipboardItem::image(png, "image/png")
+}
+
+fn payloads() -> Vec<(&'static str, ClipboardItem)> {
+    vec![
+        ("log text", log_text()),
+        ("json dump", json_dump()),
+        ("png screenshot", screenshot()),
+    ]
+}
+
+fn report_sizes(key: &EncryptionKey) {
+    println!(
+        "{:<16} {:>12} {:>12} {:>8}",
+        "payload", "plain", "sealed", "saved"
+    );
+    for (name, item) in payloads() {
+        let plain = to_msgpack(&item).unwrap().len();
+        let sealed = encrypt_item(key, &item).unwrap().size();
+        println!(
+            "{:<16} {:>12} {:>12} {:>7.1}%",
+            name,
+            plain,
+            sealed,
+            100.0 * (1.0 - sealed as f64 / plain as f64)
+        );
+    }
+}
+
+fn bench_compression(c: &mut Criterion) {
+    let key = EncryptionKey::derive("benchmark secret");
+    report_sizes(&key);
+
+    let mut group = c.benchmark_group("seal");
+    for (name, item) in payloads() {
+        let sealed = encrypt_item(&key, &item).unwrap();
+        group.throughput(Throughput::Bytes(to_msgpack(&item).unwrap().len() as u64));
+        group.bench_function(format!("{name}/encrypt"), |b| {
+            b.iter(|| encrypt_item(&key, &item).unwrap())
+        });
+        group.bench_function(format!("{name}/decrypt"), |b| {
+            b.iter(|| decrypt_item(&key, &sealed).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_compression);
+criterion_main!(benches);
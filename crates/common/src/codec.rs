@@ -0,0 +1,257 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+/// Wire encoding of protocol messages, negotiated when joining a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// JSON in text frames; every client understands it
+    #[default]
+    Json,
+    /// MessagePack in binary frames, carrying byte fields without base64
+    MessagePack,
+    /// A codec this build does not know, advertised by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+impl Codec {
+    /// Codecs this build can speak, most preferred first
+    pub const SUPPORTED: &'static [Codec] = &[Codec::MessagePack, Codec::Json];
+
+    /// The most preferred codec that the peer also offers
+    pub fn negotiate(offered: &[Codec]) -> Self {
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .find(|codec| offered.contains(codec))
+            .unwrap_or_default()
+    }
+}
+
+/// A message encoded for one WebSocket frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Errors produced while encoding or decoding messages
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("MessagePack encoding error: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack decoding error: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+}
+
+/// Encode a value in the given codec
+pub fn encode<T: Serialize>(value: &T, codec: Codec) -> Result<Frame, CodecError> {
+    Ok(match codec {
+        Codec::MessagePack => Frame::Binary(to_msgpack(value)?),
+        // Never negotiated, so fall back to what every peer understands
+        Codec::Json | Codec::Unknown => Frame::Text(serde_json::to_string(value)?),
+    })
+}
+
+/// Encode a value as MessagePack, with struct fields named so the format can evolve
+pub fn to_msgpack<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+    Ok(rmp_serde::to_vec_named(value)?)
+}
+
+/// Decode a MessagePack value
+pub fn from_msgpack<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+    Ok(rmp_serde::from_slice(bytes)?)
+}
+
+/// Serde helper for byte fields: base64 strings in human-readable formats
+/// such as JSON, raw bytes in binary ones such as MessagePack
+pub(crate) mod bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    /// Most bytes preallocated for a sequence, whose length hint comes from the peer
+    const MAX_PREALLOCATED: usize = 4096;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        // Accept either form regardless of the format, since buffered enum
+        // content does not report whether the original format is binary
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("bytes or a base64 string")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            STANDARD.decode(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(MAX_PREALLOCATED));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+/// Serde helper for lists of byte fields, each encoded as in [`bytes`]
+pub(crate) mod byte_chunks {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    struct ChunkRef<'a>(#[serde(with = "super::bytes")] &'a [u8]);
+
+    #[derive(Deserialize)]
+    struct Chunk(#[serde(with = "super::bytes")] Vec<u8>);
+
+    pub fn serialize<S: Serializer>(chunks: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(chunks.iter().map(|chunk| ChunkRef(chunk)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        // serde's own Vec impl already caps what it preallocates from the hint
+        let chunks = Vec::<Chunk>::deserialize(deserializer)?;
+        Ok(chunks.into_iter().map(|Chunk(chunk)| chunk).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Capabilities, ClientMessage, ServerMessage};
+    use crate::types::{ClipboardEntry, ClipboardFile, ClipboardFlavor, ClipboardItem};
+
+    #[test]
+    fn test_negotiation_prefers_messagepack() {
+        assert_eq!(
+            Codec::negotiate(&[Codec::Json, Codec::MessagePack]),
+            Codec::MessagePack
+        );
+        assert_eq!(Codec::negotiate(&[Codec::Json]), Codec::Json);
+        assert_eq!(Codec::negotiate(&[]), Codec::Json);
+    }
+
+    #[test]
+    fn test_bytes_are_raw_in_messagepack_and_base64_in_json() {
+        let image = vec![0xffu8; 3000];
+        let msg =
+            ClientMessage::new_clip(ClipboardItem::image(image.clone(), "image/png"), None, 1);
+
+        let Frame::Binary(packed) = encode(&msg, Codec::MessagePack).unwrap() else {
+            panic!("expected a binary frame");
+        };
+        let Frame::Text(json) = encode(&msg, Codec::Json).unwrap() else {
+            panic!("expected a text frame");
+        };
+
+        // Base64 costs a third on top of the raw bytes
+        assert!(packed.len() < image.len() + 150);
+        assert!(json.len() > image.len() * 4 / 3);
+
+        for decoded in [
+            from_msgpack::<ClientMessage>(&packed).unwrap(),
+            ClientMessage::from_json(&json).unwrap(),
+        ] {
+            let ClientMessage::NewClip { item, .. } = decoded else {
+                panic!("expected NewClip");
+            };
+            assert_eq!(item.decode_image_data(), Some(image.clone()));
+        }
+    }
+
+    #[test]
+    fn test_flavor_and_file_bytes_are_raw_in_messagepack() {
+        let content = vec![0xffu8; 3000];
+        for item in [
+            ClipboardItem::Rich(vec![
+                ClipboardFlavor::text("text/plain", "hi"),
+                ClipboardFlavor::binary("image/png", content.clone()),
+            ]),
+            ClipboardItem::files(vec![ClipboardFile::new("a.bin", &content, None)]),
+        ] {
+            let packed = to_msgpack(&item).unwrap();
+            assert!(packed.len() < content.len() + 150);
+            assert_eq!(from_msgpack::<ClipboardItem>(&packed).unwrap(), item);
+
+            let json = serde_json::to_string(&item).unwrap();
+            assert_eq!(serde_json::from_str::<ClipboardItem>(&json).unwrap(), item);
+        }
+    }
+
+    #[test]
+    fn test_declared_lengths_are_not_trusted() {
+        // Arrays claiming u32::MAX elements but holding one
+        let packed = [0xdd, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(bytes::deserialize(&mut rmp_serde::Deserializer::new(&packed[..])).is_err());
+        let packed = [0xdd, 0xff, 0xff, 0xff, 0xff, 0xc4, 0x01, 0x01];
+        assert!(byte_chunks::deserialize(&mut rmp_serde::Deserializer::new(&packed[..])).is_err());
+
+        let packed = [0x92, 0x01, 0x02];
+        assert_eq!(
+            bytes::deserialize(&mut rmp_serde::Deserializer::new(&packed[..])).unwrap(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_server_messages_round_trip_through_messagepack() {
+        let entry = ClipboardEntry::new(ClipboardItem::Encrypted {
+            nonce: vec![1; 24],
+            ciphertext: vec![2; 64],
+            compression: None,
+        });
+        let msg = ServerMessage::joined(
+            vec![entry],
+            Codec::MessagePack,
+            Capabilities::current(),
+            Vec::new(),
+        );
+
+        let Frame::Binary(packed) = encode(&msg, Codec::MessagePack).unwrap() else {
+            panic!("expected a binary frame");
+        };
+        let ServerMessage::Joined { history, codec, .. } = from_msgpack(&packed).unwrap() else {
+            panic!("expected Joined");
+        };
+        assert_eq!(codec, Codec::MessagePack);
+        assert_eq!(
+            history[0].item,
+            ClipboardItem::Encrypted {
+                nonce: vec![1; 24],
+                ciphertext: vec![2; 64],
+                compression: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use thiserror::Error;
+
+/// Serialized items larger than this are compressed before they are sealed
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Upper bound on a decompressed item, so a small payload cannot expand
+/// without limit
+pub const MAX_DECOMPRESSED_SIZE: usize = 512 * 1024 * 1024;
+
+/// zstd level; favours speed since clips are compressed on every copy
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression schemes for clipboard payloads, advertised when joining
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    /// A scheme this build does not know, advertised by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+impl Compression {
+    /// Schemes this build can decompress
+    pub const SUPPORTED: &'static [Compression] = &[Compression::Zstd];
+}
+
+/// Errors produced while decompressing a payload
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("unsupported compression scheme")]
+    Unsupported,
+    #[error("decompressed payload is larger than {MAX_DECOMPRESSED_SIZE} bytes")]
+    TooLarge,
+    #[error("corrupt compressed payload: {0}")]
+    Corrupt(#[from] std::io::Error),
+}
+
+/// Compress `data` if it is over the threshold and compression actually
+/// shrinks it, as it rarely does for already-compressed images
+pub fn compress(data: &[u8]) -> Option<(Compression, Vec<u8>)> {
+    if data.len() <= COMPRESSION_THRESHOLD {
+        return None;
+    }
+
+    let compressed = zstd::bulk::compress(data, ZSTD_LEVEL).ok()?;
+    (compressed.len() < data.len()).then_some((Compression::Zstd, compressed))
+}
+
+/// Reverse `compress`
+pub fn decompress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    match compression {
+        Compression::Zstd => {
+            let mut decoded = Vec::new();
+            zstd::stream::Decoder::new(data)?
+                .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                .read_to_end(&mut decoded)?;
+            if decoded.len() > MAX_DECOMPRESSED_SIZE {
+                return Err(CompressionError::TooLarge);
+            }
+            Ok(decoded)
+        }
+        Compression::Unknown => Err(CompressionError::Unsupported),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_only_large_compressible_payloads_are_compressed() {
+        let log = "GET /health 200 0.4ms\n".repeat(500);
+        let (compression, compressed) = compress(log.as_bytes()).unwrap();
+        assert!(compressed.len() < log.len() / 10);
+        assert_eq!(
+            decompress(compression, &compressed).unwrap(),
+            log.as_bytes()
+        );
+
+        assert!(compress(b"short").is_none());
+
+        // Data that is already dense does not shrink, so it is left alone
+        let noise: Vec<u8> = (0..256u32)
+            .flat_map(|i| Sha256::digest(i.to_le_bytes()))
+            .collect();
+        assert!(compress(&noise).is_none());
+    }
+
+    #[test]
+    fn test_corrupt_payload_is_rejected() {
+        assert!(matches!(
+            decompress(Compression::Zstd, b"not zstd at all"),
+            Err(CompressionError::Corrupt(_))
+        ));
+        assert!(matches!(
+            decompress(Compression::Unknown, b""),
+            Err(CompressionError::Unsupported)
+        ));
+    }
+}
@@ -1,4 +1,21 @@
+use crate::codec::{self, CodecError};
+use crate::compress::{self, CompressionError};
+use crate::types::ClipboardItem;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::fmt;
+use thiserror::Error;
+
+/// HKDF salt for the clipboard encryption key
+const KEY_SALT: &[u8] = b"clipsync";
+/// HKDF context string; bump the version if the sealing format changes
+const KEY_INFO: &[u8] = b"clipsync clipboard encryption v2";
+/// HKDF context string for the content hash key
+const HASH_KEY_INFO: &[u8] = b"clipsync content hash v1";
 
 /// Hash a shared secret to create a room identifier
 pub fn hash_secret(secret: &str) -> String {
@@ -7,9 +24,147 @@ pub fn hash_secret(secret: &str) -> String {
     format!("{:x}", hasher.finalize())
 }
 
+/// Whether `hash` is a room identifier as `hash_secret` makes them:
+/// 64 lowercase hex digits
+pub fn is_secret_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Schemes for sealing clipboard items, advertised when joining
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encryption {
+    /// XChaCha20-Poly1305 with an HKDF-derived key (see `EncryptionKey`)
+    XChaCha20Poly1305,
+    /// A scheme this build does not know, advertised by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+/// Errors produced while sealing or opening clipboard items
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("clipboard item is not encrypted")]
+    NotEncrypted,
+    #[error("invalid encrypted payload encoding")]
+    InvalidEncoding,
+    #[error("encryption failed")]
+    EncryptionFailed,
+    #[error("decryption failed (wrong secret or tampered payload)")]
+    DecryptionFailed,
+    #[error("failed to serialize clipboard item: {0}")]
+    Serialization(#[from] CodecError),
+    #[error("failed to decompress clipboard item: {0}")]
+    Compression(#[from] CompressionError),
+}
+
+/// Symmetric key used to seal clipboard items end-to-end.
+///
+/// Derived from the shared secret with HKDF, so it is independent of the
+/// room hash that the server sees.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher_key: [u8; 32],
+    hash_key: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// Derive the clipboard encryption key from a shared secret
+    pub fn derive(secret: &str) -> Self {
+        let hk = Hkdf::<Sha256>::new(Some(KEY_SALT), secret.as_bytes());
+        let mut cipher_key = [0u8; 32];
+        let mut hash_key = [0u8; 32];
+        hk.expand(KEY_INFO, &mut cipher_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        hk.expand(HASH_KEY_INFO, &mut hash_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self {
+            cipher_key,
+            hash_key,
+        }
+    }
+
+    /// Keyed hash of an item's plaintext.
+    ///
+    /// Lets the server recognise repeated content without learning anything
+    /// about it, since only holders of the shared secret can compute it.
+    pub fn content_hash(&self, item: &ClipboardItem) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.hash_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&serde_json::to_vec(item).unwrap_or_default());
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new((&self.cipher_key).into())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Seal a clipboard item so only holders of the shared secret can read it.
+///
+/// Large items are compressed first, since ciphertext does not compress.
+pub fn encrypt_item(
+    key: &EncryptionKey,
+    item: &ClipboardItem,
+) -> Result<ClipboardItem, CryptoError> {
+    let plaintext = codec::to_msgpack(item)?;
+    let (compression, plaintext) = match compress::compress(&plaintext) {
+        Some((compression, compressed)) => (Some(compression), compressed),
+        None => (None, plaintext),
+    };
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = key
+        .cipher()
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| CryptoError::EncryptionFailed)?;
+
+    Ok(ClipboardItem::Encrypted {
+        nonce: nonce.to_vec(),
+        ciphertext,
+        compression,
+    })
+}
+
+/// Open a sealed clipboard item, verifying that it was not tampered with
+pub fn decrypt_item(
+    key: &EncryptionKey,
+    item: &ClipboardItem,
+) -> Result<ClipboardItem, CryptoError> {
+    let ClipboardItem::Encrypted {
+        nonce,
+        ciphertext,
+        compression,
+    } = item
+    else {
+        return Err(CryptoError::NotEncrypted);
+    };
+
+    if nonce.len() != 24 {
+        return Err(CryptoError::InvalidEncoding);
+    }
+
+    let plaintext = key
+        .cipher()
+        .decrypt(XNonce::from_slice(nonce), ciphertext.as_slice())
+        .map_err(|_| CryptoError::DecryptionFailed)?;
+    let plaintext = match compression {
+        Some(compression) => compress::decompress(*compression, &plaintext)?,
+        None => plaintext,
+    };
+
+    Ok(codec::from_msgpack(&plaintext)?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compress::Compression;
 
     #[test]
     fn test_hash_secret() {
@@ -26,5 +181,134 @@ mod tests {
 
         // Hash should be 64 characters (256 bits in hex)
         assert_eq!(hash1.len(), 64);
+        assert!(is_secret_hash(&hash1));
+        assert!(!is_secret_hash(&hash1.to_uppercase()));
+        assert!(!is_secret_hash(&hash1[..63]));
+        assert!(!is_secret_hash(&format!("{}é", &hash1[..62])));
+    }
+
+    #[test]
+    fn test_key_is_independent_of_room_hash() {
+        let secret = "my-secret-key";
+        let key = EncryptionKey::derive(secret);
+        let room_hash = hash_secret(secret);
+
+        let key_hex: String = key
+            .cipher_key
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_ne!(key_hex, room_hash);
+        assert_ne!(key.cipher_key, key.hash_key);
+        assert_eq!(key.cipher_key, EncryptionKey::derive(secret).cipher_key);
+    }
+
+    #[test]
+    fn test_content_hash_is_keyed() {
+        let key = EncryptionKey::derive("my-secret-key");
+        let item = ClipboardItem::text("hello");
+
+        assert_eq!(key.content_hash(&item), key.content_hash(&item));
+        assert_ne!(
+            key.content_hash(&item),
+            key.content_hash(&ClipboardItem::text("hello!"))
+        );
+        assert_ne!(
+            key.content_hash(&item),
+            EncryptionKey::derive("different-secret").content_hash(&item)
+        );
+        assert_ne!(key.content_hash(&item), item.content_hash());
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let key = EncryptionKey::derive("my-secret-key");
+        let items = [
+            ClipboardItem::text("hello from another machine"),
+            ClipboardItem::image(vec![0x89, b'P', b'N', b'G', 0, 1, 2, 3], "image/png"),
+        ];
+
+        for item in items {
+            let sealed = encrypt_item(&key, &item).unwrap();
+            assert!(sealed.is_encrypted());
+            assert_eq!(decrypt_item(&key, &sealed).unwrap(), item);
+        }
+    }
+
+    #[test]
+    fn test_large_items_are_compressed_before_sealing() {
+        let key = EncryptionKey::derive("my-secret-key");
+        let log = ClipboardItem::text("2026-01-01 INFO request served\n".repeat(2000));
+
+        let sealed = encrypt_item(&key, &log).unwrap();
+        assert_eq!(sealed.compression(), Some(Compression::Zstd));
+        assert!(sealed.size() < log.size() / 10);
+        assert_eq!(decrypt_item(&key, &sealed).unwrap(), log);
+
+        let small = encrypt_item(&key, &ClipboardItem::text("hello")).unwrap();
+        assert_eq!(small.compression(), None);
+    }
+
+    #[test]
+    fn test_ciphertext_hides_plaintext() {
+        let key = EncryptionKey::derive("my-secret-key");
+        let item = ClipboardItem::text("super secret password");
+
+        let sealed1 = encrypt_item(&key, &item).unwrap();
+        let sealed2 = encrypt_item(&key, &item).unwrap();
+
+        // Fresh nonce every time, so identical items do not look identical
+        assert_ne!(sealed1, sealed2);
+        let json = serde_json::to_string(&sealed1).unwrap();
+        assert!(!json.contains("super secret password"));
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_is_rejected() {
+        let key = EncryptionKey::derive("my-secret-key");
+        let sealed = encrypt_item(&key, &ClipboardItem::text("hello")).unwrap();
+
+        let ClipboardItem::Encrypted {
+            nonce,
+            mut ciphertext,
+            compression,
+        } = sealed
+        else {
+            panic!("expected encrypted item");
+        };
+        ciphertext[0] ^= 0x01;
+        let tampered = ClipboardItem::Encrypted {
+            nonce,
+            ciphertext,
+            compression,
+        };
+
+        assert!(matches!(
+            decrypt_item(&key, &tampered),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_wrong_key_is_rejected() {
+        let sealed = encrypt_item(
+            &EncryptionKey::derive("my-secret-key"),
+            &ClipboardItem::text("hello"),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            decrypt_item(&EncryptionKey::derive("different-secret"), &sealed),
+            Err(CryptoError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_plaintext_item_fails() {
+        let key = EncryptionKey::derive("my-secret-key");
+        assert!(matches!(
+            decrypt_item(&key, &ClipboardItem::text("hello")),
+            Err(CryptoError::NotEncrypted)
+        ));
     }
 }
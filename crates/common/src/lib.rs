@@ -1,7 +1,17 @@
+pub mod codec;
+pub mod compress;
 pub mod crypto;
 pub mod protocol;
+pub mod transfer;
 pub mod types;
+#[cfg(feature = "ws-deflate")]
+pub mod ws_deflate;
 
+pub use codec::*;
+pub use compress::*;
 pub use crypto::*;
 pub use protocol::*;
+pub use transfer::*;
 pub use types::*;
+#[cfg(feature = "ws-deflate")]
+pub use ws_deflate::*;
@@ -1,6 +1,158 @@
-use crate::types::{ClipboardEntry, ClipboardItem};
+use crate::codec::{self, Codec, CodecError, Frame};
+use crate::compress::Compression;
+use crate::crypto::Encryption;
+use crate::types::{new_clip_id, ClipboardEntry, ClipboardItem, ItemFormat};
 use serde::{Deserialize, Serialize};
 
+/// Version of the protocol spoken by this build, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build can talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// What a client or server can do, exchanged when joining a room.
+///
+/// Every field defaults when missing so either side can grow new
+/// capabilities without breaking older peers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Protocol version spoken; 0 for clients that predate the handshake
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// Codecs the peer can decode, most preferred first; JSON if empty
+    #[serde(default)]
+    pub codecs: Vec<Codec>,
+    /// Kinds of clipboard item the peer can handle
+    #[serde(default)]
+    pub formats: Vec<ItemFormat>,
+    /// Largest item the peer accepts, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_item_size: Option<u64>,
+    /// Compression schemes the peer can decompress
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+    /// Schemes the peer can seal and open clipboard items with
+    #[serde(default)]
+    pub encryption: Vec<Encryption>,
+}
+
+impl Capabilities {
+    /// Everything this build supports
+    pub fn current() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            codecs: Codec::SUPPORTED.to_vec(),
+            formats: ItemFormat::SUPPORTED.to_vec(),
+            max_item_size: None,
+            compression: Compression::SUPPORTED.to_vec(),
+            encryption: vec![Encryption::XChaCha20Poly1305],
+        }
+    }
+
+    pub fn supports_format(&self, format: ItemFormat) -> bool {
+        self.formats.contains(&format)
+    }
+
+    /// Whether an item of `size` bytes is within the peer's limit
+    pub fn accepts_size(&self, size: usize) -> bool {
+        self.max_item_size.is_none_or(|max| size as u64 <= max)
+    }
+
+    /// Why a peer with these capabilities cannot take part, if it cannot
+    pub fn incompatibility(&self) -> Option<String> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION {
+            Some(format!(
+                "protocol version {} is older than the oldest supported version {}",
+                self.protocol_version, MIN_PROTOCOL_VERSION
+            ))
+        } else if !self.encryption.contains(&Encryption::XChaCha20Poly1305) {
+            Some("end-to-end encryption (xchacha20poly1305) is required".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// Why the server rejected a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message needs a joined room
+    NotJoined,
+    /// The message could not be decoded or is not allowed, e.g. an unencrypted clip
+    InvalidMessage,
+    /// The item is larger than the server accepts
+    PayloadTooLarge,
+    /// Too many messages; the request can be retried later
+    RateLimited,
+    /// The client may not join this room
+    Unauthorized,
+    /// The client's protocol version or capabilities are not supported
+    VersionMismatch,
+    /// The room has no space for another device
+    RoomFull,
+    /// The requested item is not, or no longer, on the server
+    NotFound,
+    /// A chunked upload could not be completed
+    TransferFailed,
+    /// The server ended the session, e.g. at an operator's request
+    Disconnected,
+    /// A code this build does not know, sent by a newer server
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// The code as it appears on the wire
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NotJoined => "not_joined",
+            Self::InvalidMessage => "invalid_message",
+            Self::PayloadTooLarge => "payload_too_large",
+            Self::RateLimited => "rate_limited",
+            Self::Unauthorized => "unauthorized",
+            Self::VersionMismatch => "version_mismatch",
+            Self::RoomFull => "room_full",
+            Self::NotFound => "not_found",
+            Self::TransferFailed => "transfer_failed",
+            Self::Disconnected => "disconnected",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Whether the connection cannot be used any more; reconnecting will not
+    /// help until the user changes something
+    pub fn is_fatal(self) -> bool {
+        matches!(
+            self,
+            Self::Unauthorized | Self::VersionMismatch | Self::RoomFull
+        )
+    }
+}
+
+/// Longest device name or platform the server passes on, in characters
+pub const MAX_DEVICE_LABEL_LEN: usize = 64;
+
+/// A device connected to a room, as the other devices see it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    /// Friendly name chosen by the device, such as its host name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Operating system the device runs, such as "macos"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+}
+
+impl DeviceInfo {
+    /// What to call the device: its name, or its ID if it has none
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.device_id)
+    }
+}
+
 /// Messages sent from client to server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload")]
@@ -9,11 +161,64 @@ pub enum ClientMessage {
     Join {
         secret_hash: String,
         device_id: String,
+        #[serde(default)]
+        capabilities: Capabilities,
+        /// Sequence number of the last entry the device has seen, so only
+        /// newer history is sent back
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<u64>,
+        /// Friendly name shown to the other devices in the room
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        /// Operating system shown to the other devices in the room
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        platform: Option<String>,
     },
     /// Send a new clipboard item
-    NewClip { item: ClipboardItem, timestamp: u64 },
-    /// Request full history
-    RequestHistory,
+    NewClip {
+        /// Unique ID of the clip, reused when retransmitting it
+        #[serde(default = "new_clip_id")]
+        id: String,
+        item: ClipboardItem,
+        /// Keyed hash of the plaintext (see `EncryptionKey::content_hash`)
+        #[serde(default)]
+        content_hash: Option<String>,
+        /// Kind of the plaintext item
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        format: Option<ItemFormat>,
+        /// Sealed plainer version for devices that cannot handle `format`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fallback: Option<ClipboardItem>,
+        timestamp: u64,
+    },
+    /// Upload one chunk of a large sealed item, sent before the `NewClip`
+    /// that references it
+    UploadChunk {
+        hash: String,
+        index: u32,
+        total: u32,
+        #[serde(with = "crate::codec::bytes")]
+        data: Vec<u8>,
+    },
+    /// Ask which chunks of an upload the server is still missing, e.g. after a reconnect
+    QueryUpload { hash: String, total: u32 },
+    /// Download a blob referenced by history, starting at chunk `from_index`
+    FetchBlob {
+        hash: String,
+        #[serde(default)]
+        from_index: u32,
+    },
+    /// Request history, all of it by default. `since` and `before` bound it
+    /// by sequence number, and `limit` keeps only the newest entries of that
+    /// range, so a client can page back from its oldest entry
+    RequestHistory {
+        #[serde(default)]
+        since: Option<u64>,
+        #[serde(default)]
+        before: Option<u64>,
+        #[serde(default)]
+        limit: Option<u32>,
+    },
     /// Heartbeat/ping
     Ping,
 }
@@ -22,30 +227,100 @@ pub enum ClientMessage {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "payload")]
 pub enum ServerMessage {
-    /// Successfully joined a room, with recent history
-    Joined { history: Vec<ClipboardEntry> },
+    /// Successfully joined a room, with recent history. Every later message
+    /// on the connection, in both directions, may use `codec`
+    Joined {
+        history: Vec<ClipboardEntry>,
+        #[serde(default)]
+        codec: Codec,
+        /// What the server supports
+        #[serde(default)]
+        capabilities: Capabilities,
+        /// The other devices connected to the room
+        #[serde(default)]
+        devices: Vec<DeviceInfo>,
+    },
     /// A new clipboard item was received from another device
     ClipReceived { entry: ClipboardEntry },
-    /// Full history response
-    History { entries: Vec<ClipboardEntry> },
-    /// Acknowledgment of received clip
-    Ack { timestamp: u64 },
-    /// Error message
-    Error { message: String },
+    /// History response, oldest entry first
+    History {
+        entries: Vec<ClipboardEntry>,
+        /// Older entries in the requested range were left out by `limit`
+        #[serde(default)]
+        more: bool,
+    },
+    /// The clip with this ID is stored, or already was
+    Ack { id: String },
+    /// Progress of an upload, sent for every stored chunk
+    UploadProgress {
+        hash: String,
+        received: u32,
+        total: u32,
+    },
+    /// Chunks of an upload the server does not have yet
+    UploadStatus { hash: String, missing: Vec<u32> },
+    /// One chunk of a requested blob
+    BlobChunk {
+        hash: String,
+        index: u32,
+        total: u32,
+        #[serde(with = "crate::codec::bytes")]
+        data: Vec<u8>,
+    },
+    /// A request failed, or the connection cannot continue if `fatal`
+    Error {
+        #[serde(default)]
+        code: ErrorCode,
+        message: String,
+        /// Sent alongside the code so clients can react to codes they do not know
+        #[serde(default)]
+        fatal: bool,
+    },
+    /// Another device connected to the room, or rejoined with new details
+    DeviceJoined { device: DeviceInfo },
+    /// Another device left the room
+    DeviceLeft { device_id: String },
+    /// The server is shutting down and is about to close the connection;
+    /// reconnect after the given delay rather than straight away
+    ServerShuttingDown { reconnect_after_ms: u64 },
     /// Pong response to ping
     Pong,
 }
 
 impl ClientMessage {
-    pub fn join(secret_hash: String, device_id: String) -> Self {
+    pub fn join(secret_hash: String, device: DeviceInfo) -> Self {
         Self::Join {
             secret_hash,
-            device_id,
+            device_id: device.device_id,
+            capabilities: Capabilities::current(),
+            since: None,
+            name: device.name,
+            platform: device.platform,
         }
     }
 
-    pub fn new_clip(item: ClipboardItem, timestamp: u64) -> Self {
-        Self::NewClip { item, timestamp }
+    /// Name of the message type, as it appears on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Join { .. } => "Join",
+            Self::NewClip { .. } => "NewClip",
+            Self::UploadChunk { .. } => "UploadChunk",
+            Self::QueryUpload { .. } => "QueryUpload",
+            Self::FetchBlob { .. } => "FetchBlob",
+            Self::RequestHistory { .. } => "RequestHistory",
+            Self::Ping => "Ping",
+        }
+    }
+
+    pub fn new_clip(item: ClipboardItem, content_hash: Option<String>, timestamp: u64) -> Self {
+        Self::NewClip {
+            id: new_clip_id(),
+            item,
+            content_hash,
+            format: None,
+            fallback: None,
+            timestamp,
+        }
     }
 
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
@@ -55,20 +330,58 @@ impl ClientMessage {
     pub fn from_json(s: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(s)
     }
+
+    pub fn encode(&self, codec: Codec) -> Result<Frame, CodecError> {
+        codec::encode(self, codec)
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, CodecError> {
+        codec::from_msgpack(bytes)
+    }
 }
 
 impl ServerMessage {
-    pub fn joined(history: Vec<ClipboardEntry>) -> Self {
-        Self::Joined { history }
+    pub fn joined(
+        history: Vec<ClipboardEntry>,
+        codec: Codec,
+        capabilities: Capabilities,
+        devices: Vec<DeviceInfo>,
+    ) -> Self {
+        Self::Joined {
+            history,
+            codec,
+            capabilities,
+            devices,
+        }
+    }
+
+    /// Name of the message type, as it appears on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Joined { .. } => "Joined",
+            Self::ClipReceived { .. } => "ClipReceived",
+            Self::History { .. } => "History",
+            Self::Ack { .. } => "Ack",
+            Self::UploadProgress { .. } => "UploadProgress",
+            Self::UploadStatus { .. } => "UploadStatus",
+            Self::BlobChunk { .. } => "BlobChunk",
+            Self::Error { .. } => "Error",
+            Self::DeviceJoined { .. } => "DeviceJoined",
+            Self::DeviceLeft { .. } => "DeviceLeft",
+            Self::ServerShuttingDown { .. } => "ServerShuttingDown",
+            Self::Pong => "Pong",
+        }
     }
 
     pub fn clip_received(entry: ClipboardEntry) -> Self {
         Self::ClipReceived { entry }
     }
 
-    pub fn error(message: impl Into<String>) -> Self {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
         Self::Error {
+            code,
             message: message.into(),
+            fatal: code.is_fatal(),
         }
     }
 
@@ -79,4 +392,97 @@ impl ServerMessage {
     pub fn from_json(s: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(s)
     }
+
+    pub fn encode(&self, codec: Codec) -> Result<Frame, CodecError> {
+        codec::encode(self, codec)
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, CodecError> {
+        codec::from_msgpack(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_without_capabilities_is_incompatible() {
+        let json = r#"{"type":"Join","payload":{"secret_hash":"h","device_id":"d"}}"#;
+        let ClientMessage::Join { capabilities, .. } = ClientMessage::from_json(json).unwrap()
+        else {
+            panic!("expected Join");
+        };
+        assert!(capabilities.incompatibility().is_some());
+        assert!(Capabilities::current().incompatibility().is_none());
+
+        let unencrypted = Capabilities {
+            encryption: Vec::new(),
+            ..Capabilities::current()
+        };
+        assert!(unencrypted.incompatibility().is_some());
+    }
+
+    #[test]
+    fn test_errors_carry_code_and_fatality() {
+        let json = ServerMessage::error(ErrorCode::VersionMismatch, "too old")
+            .to_json()
+            .unwrap();
+        assert!(json.contains(r#""code":"version_mismatch""#));
+        assert_eq!(ErrorCode::VersionMismatch.as_str(), "version_mismatch");
+        assert!(json.contains(r#""fatal":true"#));
+
+        // Unknown codes still tell the client whether to give up
+        let json =
+            r#"{"type":"Error","payload":{"code":"quota_exceeded","message":"x","fatal":true}}"#;
+        let ServerMessage::Error { code, fatal, .. } = ServerMessage::from_json(json).unwrap()
+        else {
+            panic!("expected Error");
+        };
+        assert_eq!(code, ErrorCode::Unknown);
+        assert!(fatal);
+        assert!(!ErrorCode::RateLimited.is_fatal());
+    }
+
+    #[test]
+    fn test_presence_is_optional_on_the_wire() {
+        // Joins from clients that predate presence carry no name or platform
+        let json = r#"{"type":"Join","payload":{"secret_hash":"h","device_id":"d"}}"#;
+        let ClientMessage::Join { name, platform, .. } = ClientMessage::from_json(json).unwrap()
+        else {
+            panic!("expected Join");
+        };
+        assert_eq!((name, platform), (None, None));
+
+        let device = DeviceInfo {
+            device_id: "device-1".into(),
+            name: Some("laptop".into()),
+            platform: Some("linux".into()),
+        };
+        let json = ClientMessage::join("h".into(), device.clone())
+            .to_json()
+            .unwrap();
+        assert!(json.contains(r#""name":"laptop""#));
+        assert_eq!(device.label(), "laptop");
+        assert_eq!(
+            DeviceInfo {
+                name: None,
+                ..device
+            }
+            .label(),
+            "device-1"
+        );
+    }
+
+    #[test]
+    fn test_unknown_capabilities_from_newer_peers_are_tolerated() {
+        let json = r#"{"protocol_version":7,"codecs":["cbor","json"],"formats":["text","video"],"encryption":["xchacha20poly1305","aes-gcm-siv"],"future":true}"#;
+        let capabilities: Capabilities = serde_json::from_str(json).unwrap();
+
+        assert_eq!(Codec::negotiate(&capabilities.codecs), Codec::Json);
+        assert!(capabilities.supports_format(ItemFormat::Text));
+        assert!(!capabilities.supports_format(ItemFormat::Rich));
+        assert!(capabilities.incompatibility().is_none());
+        assert!(capabilities.accepts_size(usize::MAX));
+    }
 }
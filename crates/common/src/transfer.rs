@@ -0,0 +1,214 @@
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Size of the pieces large items are split into on the wire
+pub const TRANSFER_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Sealed items whose serialized form is larger than this are uploaded in
+/// chunks and referenced by hash instead of being sent inline
+pub const INLINE_ITEM_LIMIT: usize = TRANSFER_CHUNK_SIZE;
+
+/// Identify a blob by the SHA-256 of its bytes
+pub fn blob_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Split a blob into chunks of at most `TRANSFER_CHUNK_SIZE` bytes
+pub fn split_chunks(data: &[u8]) -> Vec<Vec<u8>> {
+    data.chunks(TRANSFER_CHUNK_SIZE)
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+/// Errors produced while reassembling a chunked blob
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TransferError {
+    #[error("invalid chunk index, count or length")]
+    InvalidChunk,
+    #[error("blob is larger than allowed")]
+    TooLarge,
+    #[error("blob is incomplete")]
+    Incomplete,
+    #[error("blob does not match its hash")]
+    HashMismatch,
+}
+
+/// Collects the chunks of one blob, in any order and across reconnects
+#[derive(Debug)]
+pub struct ChunkAssembler {
+    hash: String,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    bytes: usize,
+}
+
+impl ChunkAssembler {
+    pub fn new(hash: impl Into<String>, total: u32) -> Result<Self, TransferError> {
+        if total == 0 {
+            return Err(TransferError::InvalidChunk);
+        }
+        Ok(Self {
+            hash: hash.into(),
+            chunks: vec![None; total as usize],
+            received: 0,
+            bytes: 0,
+        })
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn total(&self) -> u32 {
+        self.chunks.len() as u32
+    }
+
+    /// Number of distinct chunks received so far
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    /// Bytes of the chunks received so far
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.total()
+    }
+
+    /// Indexes of the chunks still missing, in order
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.total())
+            .filter(|&i| self.chunks[i as usize].is_none())
+            .collect()
+    }
+
+    /// Store a chunk; duplicates are ignored.
+    ///
+    /// Chunks are cut as [`split_chunks`] does: every one but the last holds
+    /// exactly `TRANSFER_CHUNK_SIZE` bytes, and none is empty.
+    pub fn insert(&mut self, index: u32, total: u32, data: &[u8]) -> Result<(), TransferError> {
+        if self.check(index, total, data)? {
+            self.chunks[index as usize] = Some(data.to_vec());
+            self.received += 1;
+            self.bytes += data.len();
+        }
+        Ok(())
+    }
+
+    /// Whether [`insert`](Self::insert) would store this chunk: an error if
+    /// it is invalid, `false` if it is a duplicate
+    pub fn check(&self, index: u32, total: u32, data: &[u8]) -> Result<bool, TransferError> {
+        if total != self.total() || index >= total {
+            return Err(TransferError::InvalidChunk);
+        }
+        let last = index + 1 == total;
+        if data.is_empty()
+            || data.len() > TRANSFER_CHUNK_SIZE
+            || (!last && data.len() != TRANSFER_CHUNK_SIZE)
+        {
+            return Err(TransferError::InvalidChunk);
+        }
+        Ok(self.chunks[index as usize].is_none())
+    }
+
+    /// Concatenate the chunks and check them against the blob hash
+    pub fn finish(self) -> Result<Vec<u8>, TransferError> {
+        if !self.is_complete() {
+            return Err(TransferError::Incomplete);
+        }
+
+        let data: Vec<u8> = self.chunks.into_iter().flatten().flatten().collect();
+        if blob_hash(&data) != self.hash {
+            return Err(TransferError::HashMismatch);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_chunks_reassemble_in_any_order() {
+        let data = blob(TRANSFER_CHUNK_SIZE * 2 + 17);
+        let chunks = split_chunks(&data);
+        assert_eq!(chunks.len(), 3);
+
+        let total = chunks.len() as u32;
+        let mut assembler = ChunkAssembler::new(blob_hash(&data), total).unwrap();
+        for index in [2, 0, 0, 1] {
+            assembler
+                .insert(index, total, &chunks[index as usize])
+                .unwrap();
+        }
+
+        assert!(assembler.is_complete());
+        assert_eq!(assembler.received(), 3);
+        assert_eq!(assembler.bytes(), data.len());
+        assert_eq!(assembler.finish().unwrap(), data);
+    }
+
+    #[test]
+    fn test_missing_chunks_are_reported_for_resume() {
+        let data = blob(TRANSFER_CHUNK_SIZE * 3);
+        let chunks = split_chunks(&data);
+        let mut assembler = ChunkAssembler::new(blob_hash(&data), 3).unwrap();
+        assembler.insert(1, 3, &chunks[1]).unwrap();
+
+        assert_eq!(assembler.missing(), vec![0, 2]);
+        assert!(matches!(
+            ChunkAssembler::new(blob_hash(&data), 3).unwrap().finish(),
+            Err(TransferError::Incomplete)
+        ));
+    }
+
+    #[test]
+    fn test_bad_chunks_are_rejected() {
+        let data = blob(10);
+        let chunks = split_chunks(&data);
+        let mut assembler = ChunkAssembler::new("not the hash", 1).unwrap();
+
+        assert_eq!(
+            assembler.insert(1, 1, &chunks[0]),
+            Err(TransferError::InvalidChunk)
+        );
+        assert_eq!(
+            assembler.insert(0, 2, &chunks[0]),
+            Err(TransferError::InvalidChunk)
+        );
+
+        assembler.insert(0, 1, &chunks[0]).unwrap();
+        assert_eq!(assembler.finish(), Err(TransferError::HashMismatch));
+        assert!(ChunkAssembler::new("hash", 0).is_err());
+    }
+
+    #[test]
+    fn test_chunks_must_be_cut_like_split_chunks() {
+        let mut assembler = ChunkAssembler::new("hash", 2).unwrap();
+        let full = blob(TRANSFER_CHUNK_SIZE);
+
+        for (index, len) in [
+            (0, TRANSFER_CHUNK_SIZE - 1),
+            (0, TRANSFER_CHUNK_SIZE + 1),
+            (1, TRANSFER_CHUNK_SIZE + 1),
+            (1, 0),
+        ] {
+            assert_eq!(
+                assembler.insert(index, 2, &blob(len)),
+                Err(TransferError::InvalidChunk)
+            );
+        }
+        assert_eq!(assembler.received(), 0);
+
+        assembler.insert(0, 2, &full).unwrap();
+        assembler.insert(1, 2, &full[..10]).unwrap();
+        assert!(assembler.is_complete());
+    }
+}
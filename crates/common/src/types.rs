@@ -1,4 +1,9 @@
+use crate::compress::Compression;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Size of the pieces file content is split into
+pub const FILE_CHUNK_SIZE: usize = 256 * 1024;
 
 /// Represents a clipboard item that can be synced
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -8,11 +13,61 @@ pub enum ClipboardItem {
     Text(String),
     /// Image data with MIME type
     Image {
-        /// Base64-encoded image data
-        data: String,
+        /// Raw image bytes (base64 in JSON)
+        #[serde(with = "crate::codec::bytes")]
+        data: Vec<u8>,
         /// MIME type (e.g., "image/png", "image/jpeg")
         mime_type: String,
     },
+    /// Several representations of the same copy, ordered richest first
+    Rich(Vec<ClipboardFlavor>),
+    /// Files copied in a file manager, with their content
+    Files(Vec<ClipboardFile>),
+    /// Item sealed with the room's encryption key; opaque to the server
+    Encrypted {
+        /// XChaCha20-Poly1305 nonce (base64 in JSON)
+        #[serde(with = "crate::codec::bytes")]
+        nonce: Vec<u8>,
+        /// Ciphertext of the serialized item (base64 in JSON)
+        #[serde(with = "crate::codec::bytes")]
+        ciphertext: Vec<u8>,
+        /// How the serialized item was compressed before sealing, if it was
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<Compression>,
+    },
+    /// Large sealed item stored by the server and fetched in chunks on demand
+    Reference {
+        /// SHA-256 of the serialized sealed item (see `blob_hash`)
+        hash: String,
+        /// Size of the serialized sealed item in bytes
+        size: u64,
+    },
+}
+
+/// Kinds of clipboard item a device can handle, advertised when joining
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemFormat {
+    Text,
+    Image,
+    Rich,
+    Files,
+    /// References to large items, fetched in chunks with `FetchBlob`
+    Reference,
+    /// A format this build does not know, advertised by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+impl ItemFormat {
+    /// Every format this build can handle
+    pub const SUPPORTED: &'static [ItemFormat] = &[
+        ItemFormat::Text,
+        ItemFormat::Image,
+        ItemFormat::Rich,
+        ItemFormat::Files,
+        ItemFormat::Reference,
+    ];
 }
 
 impl ClipboardItem {
@@ -24,50 +79,295 @@ impl ClipboardItem {
     /// Create an image clipboard item
     pub fn image(data: Vec<u8>, mime_type: impl Into<String>) -> Self {
         Self::Image {
-            data: base64::Engine::encode(&base64::engine::general_purpose::STANDARD, data),
+            data,
             mime_type: mime_type.into(),
         }
     }
 
+    /// Create an item from the flavors of one copy, ordered richest first.
+    ///
+    /// A lone plain-text or image flavor becomes a `Text` or `Image` item, so
+    /// simple copies stay readable by clients that predate rich items.
+    pub fn from_flavors(mut flavors: Vec<ClipboardFlavor>) -> Option<Self> {
+        if flavors.len() > 1 {
+            return Some(Self::Rich(flavors));
+        }
+
+        let flavor = flavors.pop()?;
+        Some(if flavor.mime_type.starts_with("image/") {
+            Self::Image {
+                data: flavor.data,
+                mime_type: flavor.mime_type,
+            }
+        } else if let Some(text) = flavor
+            .as_text()
+            .filter(|_| flavor.mime_type == "text/plain")
+        {
+            Self::Text(text.to_string())
+        } else {
+            Self::Rich(vec![flavor])
+        })
+    }
+
+    /// Create a files clipboard item
+    pub fn files(files: Vec<ClipboardFile>) -> Self {
+        Self::Files(files)
+    }
+
+    /// All representations of this item, richest first (none for files, sealed items or references)
+    pub fn flavors(&self) -> Vec<ClipboardFlavor> {
+        match self {
+            Self::Text(text) => vec![ClipboardFlavor::text("text/plain", text.clone())],
+            Self::Image { data, mime_type } => {
+                vec![ClipboardFlavor::binary(mime_type.clone(), data.clone())]
+            }
+            Self::Rich(flavors) => flavors.clone(),
+            Self::Files(_) | Self::Encrypted { .. } | Self::Reference { .. } => Vec::new(),
+        }
+    }
+
+    /// The kind of this item; sealed items hide theirs
+    pub fn format(&self) -> Option<ItemFormat> {
+        match self {
+            Self::Text(_) => Some(ItemFormat::Text),
+            Self::Image { .. } => Some(ItemFormat::Image),
+            Self::Rich(_) => Some(ItemFormat::Rich),
+            Self::Files(_) => Some(ItemFormat::Files),
+            Self::Reference { .. } => Some(ItemFormat::Reference),
+            Self::Encrypted { .. } => None,
+        }
+    }
+
+    /// A plainer version for devices that cannot handle this item's format:
+    /// the plain text (or image) of a rich item, or the names of copied files
+    pub fn fallback(&self) -> Option<ClipboardItem> {
+        match self {
+            Self::Rich(flavors) => {
+                let flavor = flavors
+                    .iter()
+                    .find(|f| f.mime_type == "text/plain")
+                    .or_else(|| flavors.iter().find(|f| f.mime_type.starts_with("image/")))?;
+                Self::from_flavors(vec![flavor.clone()])
+            }
+            Self::Files(files) => Some(Self::Text(
+                files
+                    .iter()
+                    .map(|file| file.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )),
+            _ => None,
+        }
+    }
+
+    /// The richest flavor whose MIME type is in `supported`
+    pub fn preferred_flavor(&self, supported: &[&str]) -> Option<ClipboardFlavor> {
+        self.flavors()
+            .into_iter()
+            .find(|flavor| supported.contains(&flavor.mime_type.as_str()))
+    }
+
     /// Get the size of the clipboard item in bytes (approximate)
     pub fn size(&self) -> usize {
         match self {
             Self::Text(s) => s.len(),
             Self::Image { data, mime_type } => data.len() + mime_type.len(),
+            Self::Rich(flavors) => flavors.iter().map(ClipboardFlavor::size).sum(),
+            Self::Files(files) => files.iter().map(ClipboardFile::encoded_size).sum(),
+            Self::Encrypted {
+                nonce, ciphertext, ..
+            } => nonce.len() + ciphertext.len(),
+            Self::Reference { size, .. } => *size as usize,
+        }
+    }
+
+    /// SHA-256 of the serialized item, used to recognise identical content locally
+    pub fn content_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_vec(self).unwrap_or_default());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Whether this item has been sealed with the room's encryption key
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Self::Encrypted { .. })
+    }
+
+    /// How a sealed item's content was compressed, if it was
+    pub fn compression(&self) -> Option<Compression> {
+        match self {
+            Self::Encrypted { compression, .. } => *compression,
+            _ => None,
         }
     }
 
-    /// Decode image data from base64
+    /// Hash of the blob this item stands in for, if it is a reference
+    pub fn reference_hash(&self) -> Option<&str> {
+        match self {
+            Self::Reference { hash, .. } => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// The raw bytes of an image item
     pub fn decode_image_data(&self) -> Option<Vec<u8>> {
         match self {
-            Self::Image { data, .. } => {
-                base64::Engine::decode(&base64::engine::general_purpose::STANDARD, data).ok()
-            }
+            Self::Image { data, .. } => Some(data.clone()),
             _ => None,
         }
     }
 }
 
+/// One representation of a clip, identified by its MIME type
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ClipboardFlavor {
+    /// MIME type (e.g., "text/plain", "text/html", "text/rtf", "image/png")
+    pub mime_type: String,
+    /// Raw bytes; UTF-8 text for textual flavors (`text/*`)
+    #[serde(with = "crate::codec::bytes")]
+    pub data: Vec<u8>,
+}
+
+impl ClipboardFlavor {
+    /// Create a textual flavor
+    pub fn text(mime_type: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            mime_type: mime_type.into(),
+            data: content.into().into_bytes(),
+        }
+    }
+
+    /// Create a binary flavor
+    pub fn binary(mime_type: impl Into<String>, data: Vec<u8>) -> Self {
+        Self {
+            mime_type: mime_type.into(),
+            data,
+        }
+    }
+
+    /// Whether the data is text
+    pub fn is_text(&self) -> bool {
+        self.mime_type.starts_with("text/")
+    }
+
+    /// The content of a textual flavor, if it is valid UTF-8
+    pub fn as_text(&self) -> Option<&str> {
+        self.is_text()
+            .then(|| std::str::from_utf8(&self.data).ok())
+            .flatten()
+    }
+
+    /// Approximate size in bytes
+    pub fn size(&self) -> usize {
+        self.mime_type.len() + self.data.len()
+    }
+}
+
+/// A copied file and its content
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ClipboardFile {
+    /// File name, without any directory components
+    pub name: String,
+    /// Size of the content in bytes
+    pub size: u64,
+    /// Unix permission bits, if the sender has them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<u32>,
+    /// Content in pieces of at most `FILE_CHUNK_SIZE` bytes
+    #[serde(with = "crate::codec::byte_chunks")]
+    pub chunks: Vec<Vec<u8>>,
+}
+
+impl ClipboardFile {
+    /// Create a file entry, splitting the content into chunks
+    pub fn new(name: impl Into<String>, content: &[u8], permissions: Option<u32>) -> Self {
+        Self {
+            name: name.into(),
+            size: content.len() as u64,
+            permissions,
+            chunks: content
+                .chunks(FILE_CHUNK_SIZE)
+                .map(<[u8]>::to_vec)
+                .collect(),
+        }
+    }
+
+    /// Reassemble the content, checking it against the advertised size
+    pub fn content(&self) -> Option<Vec<u8>> {
+        let content = self.chunks.concat();
+        (content.len() as u64 == self.size).then_some(content)
+    }
+
+    /// The name, if it is safe to use as a single path component
+    pub fn safe_name(&self) -> Option<&str> {
+        let name = self.name.as_str();
+        let unsafe_name =
+            name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', ':', '\0']);
+        (!unsafe_name).then_some(name)
+    }
+
+    /// Approximate size of the serialized entry in bytes
+    pub fn encoded_size(&self) -> usize {
+        self.name.len() + self.chunks.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+/// A fresh ID for a clip, unique across devices
+pub fn new_clip_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
 /// A clipboard item with metadata
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClipboardEntry {
+    /// Assigned by the sending device and kept when the clip is retransmitted;
+    /// entries stored before IDs existed get a fresh one when loaded
+    #[serde(default = "new_clip_id")]
+    pub id: String,
+    /// Position in the room's history, assigned by the server and increasing
+    /// with every stored clip; 0 until stored
+    #[serde(default)]
+    pub seq: u64,
     /// The clipboard content
     pub item: ClipboardItem,
     /// Unix timestamp in milliseconds
     pub timestamp: u64,
     /// Optional device identifier
     pub device_id: Option<String>,
+    /// Friendly name of the device that produced the clip, set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    /// Operating system of the device that produced the clip, set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_platform: Option<String>,
+    /// Keyed hash of the plaintext, used by the server to drop repeated clips
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// Kind of the plaintext item, so the server can relay `fallback` to
+    /// devices that cannot handle it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<ItemFormat>,
+    /// Sealed plainer version of the item (see `ClipboardItem::fallback`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<ClipboardItem>,
 }
 
 impl ClipboardEntry {
     pub fn new(item: ClipboardItem) -> Self {
         Self {
+            id: new_clip_id(),
+            seq: 0,
             item,
             timestamp: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_millis() as u64,
             device_id: None,
+            device_name: None,
+            device_platform: None,
+            content_hash: None,
+            format: None,
+            fallback: None,
         }
     }
 
@@ -76,3 +376,102 @@ impl ClipboardEntry {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_flavor_keeps_legacy_variant() {
+        assert_eq!(
+            ClipboardItem::from_flavors(vec![ClipboardFlavor::text("text/plain", "hi")]),
+            Some(ClipboardItem::text("hi"))
+        );
+        assert_eq!(
+            ClipboardItem::from_flavors(vec![ClipboardFlavor::binary("image/png", vec![1, 2])]),
+            Some(ClipboardItem::image(vec![1, 2], "image/png"))
+        );
+        assert_eq!(ClipboardItem::from_flavors(Vec::new()), None);
+    }
+
+    #[test]
+    fn test_preferred_flavor_is_richest_supported() {
+        let item = ClipboardItem::from_flavors(vec![
+            ClipboardFlavor::text("text/html", "<b>hi</b>"),
+            ClipboardFlavor::text("text/rtf", "{\\rtf1 \\b hi}"),
+            ClipboardFlavor::text("text/plain", "hi"),
+        ])
+        .unwrap();
+
+        let html = item.preferred_flavor(&["text/plain", "text/html"]).unwrap();
+        assert_eq!(html.as_text(), Some("<b>hi</b>"));
+
+        let plain = item.preferred_flavor(&["text/plain"]).unwrap();
+        assert_eq!(plain.as_text(), Some("hi"));
+
+        assert!(item.preferred_flavor(&["image/png"]).is_none());
+        assert_eq!(
+            ClipboardItem::text("hi")
+                .preferred_flavor(&["text/plain"])
+                .map(|f| f.data),
+            Some(b"hi".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_file_content_is_chunked_and_reassembled() {
+        let content: Vec<u8> = (0..FILE_CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let file = ClipboardFile::new("data.bin", &content, Some(0o644));
+
+        assert_eq!(file.chunks.len(), 3);
+        assert_eq!(file.size, content.len() as u64);
+        assert_eq!(file.content(), Some(content));
+
+        let mut truncated = file.clone();
+        truncated.chunks.pop();
+        assert_eq!(truncated.content(), None);
+    }
+
+    #[test]
+    fn test_unsafe_file_names_are_rejected() {
+        let named = |name: &str| ClipboardFile::new(name, b"x", None);
+
+        assert_eq!(named("report.pdf").safe_name(), Some("report.pdf"));
+        for name in ["", ".", "..", "../etc/passwd", "a/b", "a\\b", "C:evil"] {
+            assert_eq!(named(name).safe_name(), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_fallback_drops_what_old_devices_cannot_handle() {
+        let rich = ClipboardItem::Rich(vec![
+            ClipboardFlavor::text("text/html", "<b>hi</b>"),
+            ClipboardFlavor::text("text/plain", "hi"),
+        ]);
+        assert_eq!(rich.format(), Some(ItemFormat::Rich));
+        assert_eq!(rich.fallback(), Some(ClipboardItem::text("hi")));
+
+        let files = ClipboardItem::files(vec![
+            ClipboardFile::new("a.txt", b"a", None),
+            ClipboardFile::new("b.txt", b"b", None),
+        ]);
+        assert_eq!(files.fallback(), Some(ClipboardItem::text("a.txt\nb.txt")));
+
+        assert_eq!(ClipboardItem::text("hi").fallback(), None);
+        let html_only = ClipboardItem::Rich(vec![ClipboardFlavor::text("text/html", "<b>hi</b>")]);
+        assert_eq!(html_only.fallback(), None);
+    }
+
+    #[test]
+    fn test_rich_item_round_trips_through_json() {
+        let item = ClipboardItem::Rich(vec![
+            ClipboardFlavor::text("text/html", "<i>x</i>"),
+            ClipboardFlavor::binary("image/png", vec![0x89, b'P', b'N', b'G']),
+        ]);
+
+        let json = serde_json::to_string(&item).unwrap();
+        let parsed: ClipboardItem = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, item);
+        assert_eq!(parsed.flavors()[1].data, vec![0x89, b'P', b'N', b'G']);
+    }
+}
@@ -0,0 +1,785 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The `permessage-deflate` extension as offered by clients and accepted by
+/// servers. Every message is compressed on its own, so neither side keeps a
+/// compression window between messages.
+pub const DEFLATE_EXTENSION: &str =
+    "permessage-deflate; client_no_context_takeover; server_no_context_takeover";
+
+/// Largest inflated message, matching tungstenite's default message size limit
+pub const MAX_INFLATED_SIZE: usize = 64 << 20;
+
+/// Messages smaller than this are not worth compressing
+const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// Frames queued for the transport beyond this make writers wait
+const WRITE_HIGH_WATER: usize = 256 * 1024;
+
+/// Longest handshake response a client looks through for the extension
+const MAX_HANDSHAKE_SIZE: usize = 64 * 1024;
+
+/// Empty block that ends every compressed message and is left off on the wire
+const SYNC_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+
+/// Whether `Sec-WebSocket-Extensions` request headers offer `permessage-deflate`
+/// with parameters a server can accept
+pub fn offers_deflate<'a>(headers: impl IntoIterator<Item = &'a str>) -> bool {
+    headers
+        .into_iter()
+        .flat_map(|header| header.split(','))
+        .any(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            let is_deflate = params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("permessage-deflate"));
+            is_deflate
+                && params.all(|param| {
+                    let (name, value) = match param.split_once('=') {
+                        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                        None => (param, None),
+                    };
+                    match name.to_ascii_lowercase().as_str() {
+                        "client_no_context_takeover"
+                        | "server_no_context_takeover"
+                        | "client_max_window_bits" => true,
+                        // Outgoing messages always use the largest window
+                        "server_max_window_bits" => value == Some("15"),
+                        _ => false,
+                    }
+                })
+        })
+}
+
+/// Whether a handshake response accepted `permessage-deflate`
+fn response_accepts_deflate(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let switching = lines
+        .next()
+        .is_some_and(|status| status.split(' ').nth(1) == Some("101"));
+    switching
+        && lines
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-extensions"))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|extension| {
+                extension
+                    .split(';')
+                    .next()
+                    .is_some_and(|name| name.trim().eq_ignore_ascii_case("permessage-deflate"))
+            })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// A client waiting for the handshake response, which says whether the
+    /// server accepted the extension
+    Handshake,
+    Deflate,
+    Plain,
+}
+
+/// Header of one WebSocket frame
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// Length of the header itself
+    header_len: usize,
+    payload_len: usize,
+}
+
+impl FrameHeader {
+    /// Parse the header at the start of `buf`, or `None` if it is incomplete
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let (&first, &second) = (buf.first()?, buf.get(1)?);
+        let mut pos = 2;
+        let payload_len = match second & 0x7f {
+            126 => {
+                pos += 2;
+                u16::from_be_bytes(buf.get(2..4)?.try_into().unwrap()) as u64
+            }
+            127 => {
+                pos += 8;
+                u64::from_be_bytes(buf.get(2..10)?.try_into().unwrap())
+            }
+            len => len as u64,
+        };
+        let mask = if second & 0x80 != 0 {
+            let key = buf.get(pos..pos + 4)?.try_into().unwrap();
+            pos += 4;
+            Some(key)
+        } else {
+            None
+        };
+        Some(Self {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            opcode: first & 0x0f,
+            mask,
+            header_len: pos,
+            payload_len: usize::try_from(payload_len).unwrap_or(usize::MAX),
+        })
+    }
+
+    fn is_data(&self) -> bool {
+        matches!(self.opcode, OP_TEXT | OP_BINARY)
+    }
+}
+
+/// Append a complete frame, masking the payload with `mask` if there is one
+fn write_frame(out: &mut Vec<u8>, rsv1: bool, opcode: u8, mask: Option<[u8; 4]>, payload: &[u8]) {
+    out.push(0x80 | if rsv1 { 0x40 } else { 0 } | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => out.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(key) => {
+            out.extend_from_slice(&key);
+            out.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        }
+        None => out.extend_from_slice(payload),
+    }
+}
+
+/// Payload of a frame, unmasked
+fn unmasked(header: &FrameHeader, payload: &[u8]) -> Vec<u8> {
+    match header.mask {
+        Some(key) => payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ key[i % 4])
+            .collect(),
+        None => payload.to_vec(),
+    }
+}
+
+fn invalid_data(message: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Compress one message on its own
+fn deflate(compress: &mut Compress, data: &[u8]) -> io::Result<Vec<u8>> {
+    compress.reset();
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        compress
+            .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+            .map_err(invalid_data)?;
+        if compress.total_in() as usize == data.len() && out.len() < out.capacity() {
+            break;
+        }
+        out.reserve(out.capacity());
+    }
+    if out.ends_with(&SYNC_TAIL) {
+        out.truncate(out.len() - SYNC_TAIL.len());
+    }
+    Ok(out)
+}
+
+/// Inflate one message, whether or not the peer kept its window from the
+/// previous one.
+///
+/// The output never grows past one byte over `MAX_INFLATED_SIZE`, which is
+/// enough to tell that a message is too large without inflating the rest.
+fn inflate(decompress: &mut Decompress, mut data: Vec<u8>) -> io::Result<Vec<u8>> {
+    data.extend_from_slice(&SYNC_TAIL);
+    let start = decompress.total_in();
+    let mut out = Vec::with_capacity((data.len() * 4).min(MAX_INFLATED_SIZE));
+    loop {
+        if out.len() > MAX_INFLATED_SIZE {
+            return Err(invalid_data("inflated message is too large"));
+        }
+        if out.len() == out.capacity() {
+            let grow = out
+                .capacity()
+                .max(1024)
+                .min(MAX_INFLATED_SIZE + 1 - out.len());
+            out.reserve_exact(grow);
+        }
+        let consumed = (decompress.total_in() - start) as usize;
+        let produced = out.len();
+        let status = decompress
+            .decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+            .map_err(invalid_data)?;
+
+        let now_consumed = (decompress.total_in() - start) as usize;
+        let stalled = now_consumed == consumed && out.len() == produced;
+        if status == Status::StreamEnd {
+            // The peer ended its stream; the next message starts a new one
+            decompress.reset(false);
+            break;
+        }
+        if out.len() < out.capacity() && (now_consumed == data.len() || stalled) {
+            break;
+        }
+    }
+    if out.len() > MAX_INFLATED_SIZE {
+        return Err(invalid_data("inflated message is too large"));
+    }
+    Ok(out)
+}
+
+/// A compressed message arriving in several frames
+struct Fragments {
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    data: Vec<u8>,
+}
+
+/// The WebSocket `permessage-deflate` extension (RFC 7692) as a layer between
+/// the transport and a WebSocket library that does not implement it.
+///
+/// Compressed frames are inflated before the library reads them, and the
+/// library's data frames are compressed on their way out, with the RSV1 bit
+/// set and cleared accordingly. Messages that do not shrink go out as they
+/// are, and without the extension everything passes through untouched.
+pub struct DeflateStream<S> {
+    inner: S,
+    mode: Mode,
+    compress: Compress,
+    decompress: Decompress,
+    /// Bytes read from the transport that do not form a whole frame yet
+    read_raw: Vec<u8>,
+    /// Frames ready for the reader, and how many of their bytes it has taken
+    read_ready: Vec<u8>,
+    read_pos: usize,
+    fragments: Option<Fragments>,
+    /// Bytes written by the library that do not form a whole frame yet
+    write_raw: Vec<u8>,
+    /// Frames ready for the transport, and how many of their bytes it has taken
+    write_ready: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S> DeflateStream<S> {
+    fn new(inner: S, mode: Mode) -> Self {
+        Self {
+            inner,
+            mode,
+            compress: Compress::new(Compression::fast(), false),
+            decompress: Decompress::new(false),
+            read_raw: Vec::new(),
+            read_ready: Vec::new(),
+            read_pos: 0,
+            fragments: None,
+            write_raw: Vec::new(),
+            write_ready: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// Wrap a client connection before the handshake. Messages are compressed
+    /// if the request offered [`DEFLATE_EXTENSION`] and the server accepted it.
+    pub fn client(inner: S) -> Self {
+        Self::new(inner, Mode::Handshake)
+    }
+
+    /// Wrap a server connection after the handshake, with or without the extension
+    pub fn server(inner: S, deflate: bool) -> Self {
+        Self::new(inner, if deflate { Mode::Deflate } else { Mode::Plain })
+    }
+
+    /// Whether messages are being compressed
+    pub fn is_deflating(&self) -> bool {
+        self.mode == Mode::Deflate
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Move whole frames from `read_raw` to `read_ready`, inflating them
+    fn process_read(&mut self) -> io::Result<()> {
+        if self.mode == Mode::Handshake {
+            match self.read_raw.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(end) => {
+                    let head: Vec<u8> = self.read_raw.drain(..end + 4).collect();
+                    self.mode = if response_accepts_deflate(&head) {
+                        Mode::Deflate
+                    } else {
+                        Mode::Plain
+                    };
+                    self.read_ready.extend_from_slice(&head);
+                }
+                // Leave the garbage to the WebSocket library to reject
+                None if self.read_raw.len() > MAX_HANDSHAKE_SIZE => self.mode = Mode::Plain,
+                None => return Ok(()),
+            }
+        }
+        if self.mode == Mode::Plain {
+            self.read_ready.append(&mut self.read_raw);
+            return Ok(());
+        }
+
+        let mut pos = 0;
+        while let Some(header) = FrameHeader::parse(&self.read_raw[pos..]) {
+            if header.payload_len > MAX_INFLATED_SIZE {
+                return Err(invalid_data("frame is too large"));
+            }
+            let end = pos + header.header_len + header.payload_len;
+            if self.read_raw.len() < end {
+                break;
+            }
+            let frame = &self.read_raw[pos..end];
+            let payload = &frame[header.header_len..];
+
+            if header.is_data() && self.fragments.is_some() {
+                return Err(invalid_data(
+                    "new message in the middle of a fragmented one",
+                ));
+            }
+            if header.opcode == OP_CONTINUATION && header.rsv1 {
+                return Err(invalid_data("continuation frame with RSV1 set"));
+            }
+
+            if header.is_data() && header.rsv1 {
+                let data = unmasked(&header, payload);
+                if header.fin {
+                    let inflated = inflate(&mut self.decompress, data)?;
+                    write_frame(
+                        &mut self.read_ready,
+                        false,
+                        header.opcode,
+                        header.mask,
+                        &inflated,
+                    );
+                } else {
+                    self.fragments = Some(Fragments {
+                        opcode: header.opcode,
+                        mask: header.mask,
+                        data,
+                    });
+                }
+            } else if let (OP_CONTINUATION, Some(fragments)) =
+                (header.opcode, self.fragments.as_mut())
+            {
+                fragments.data.extend(unmasked(&header, payload));
+                if fragments.data.len() > MAX_INFLATED_SIZE {
+                    return Err(invalid_data("message is too large"));
+                }
+                if header.fin {
+                    let Fragments { opcode, mask, data } = self.fragments.take().unwrap();
+                    let inflated = inflate(&mut self.decompress, data)?;
+                    write_frame(&mut self.read_ready, false, opcode, mask, &inflated);
+                }
+            } else {
+                // Control frames, and messages the peer did not compress
+                self.read_ready.extend_from_slice(frame);
+            }
+            pos = end;
+        }
+        self.read_raw.drain(..pos);
+        Ok(())
+    }
+
+    /// Move whole frames from `write_raw` to `write_ready`, compressing them
+    fn process_write(&mut self) -> io::Result<()> {
+        let mut pos = 0;
+        while let Some(header) = FrameHeader::parse(&self.write_raw[pos..]) {
+            let end = pos.saturating_add(header.header_len + header.payload_len);
+            if self.write_raw.len() < end {
+                break;
+            }
+            let frame = &self.write_raw[pos..end];
+            let payload = &frame[header.header_len..];
+
+            let compressible = header.is_data()
+                && header.fin
+                && !header.rsv1
+                && header.payload_len >= MIN_COMPRESSED_SIZE;
+            let compressed = if compressible {
+                let data = unmasked(&header, payload);
+                Some(deflate(&mut self.compress, &data)?).filter(|c| c.len() < data.len())
+            } else {
+                None
+            };
+            match compressed {
+                Some(compressed) => write_frame(
+                    &mut self.write_ready,
+                    true,
+                    header.opcode,
+                    header.mask,
+                    &compressed,
+                ),
+                None => self.write_ready.extend_from_slice(frame),
+            }
+            pos = end;
+        }
+        self.write_raw.drain(..pos);
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    /// Write every ready frame to the transport
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_ready.len() {
+            let written = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_ready[self.write_pos..])
+            )?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += written;
+        }
+        self.write_ready.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.mode == Mode::Plain && this.read_ready.is_empty() && this.read_raw.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        loop {
+            if this.read_pos < this.read_ready.len() {
+                let available = &this.read_ready[this.read_pos..];
+                let len = available.len().min(buf.remaining());
+                buf.put_slice(&available[..len]);
+                this.read_pos += len;
+                if this.read_pos == this.read_ready.len() {
+                    this.read_ready.clear();
+                    this.read_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; 16 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                if this.fragments.is_some() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed in the middle of a compressed message",
+                    )));
+                }
+                // End of stream: pass on what is left so the reader sees the truncation
+                if this.read_raw.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.read_ready.append(&mut this.read_raw);
+                continue;
+            }
+            this.read_raw.extend_from_slice(chunk.filled());
+            this.process_read()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // The handshake request goes out before the mode is known
+        if this.mode != Mode::Deflate {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        if this.write_ready.len() - this.write_pos >= WRITE_HIGH_WATER {
+            ready!(this.poll_drain(cx))?;
+        }
+        this.write_raw.extend_from_slice(buf);
+        this.process_write()?;
+        // Start sending right away; a flush finishes the job
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    /// Answer a client handshake on `server`, accepting the extension if asked
+    /// to, and return the server end of the connection
+    async fn accept(mut server: DuplexStream, deflate: bool) -> DeflateStream<DuplexStream> {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(server.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        let header = |wanted: &'static str| {
+            request.lines().filter_map(move |line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case(wanted).then(|| value.trim())
+            })
+        };
+        let key = header("Sec-WebSocket-Key").next().unwrap();
+        let accepted = deflate && offers_deflate(header("Sec-WebSocket-Extensions"));
+
+        let mut response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n",
+            derive_accept_key(key.as_bytes())
+        );
+        if accepted {
+            response.push_str(&format!(
+                "Sec-WebSocket-Extensions: {}\r\n",
+                DEFLATE_EXTENSION
+            ));
+        }
+        response.push_str("\r\n");
+        server.write_all(response.as_bytes()).await.unwrap();
+        DeflateStream::server(server, accepted)
+    }
+
+    /// Connect a client offering the extension to a server that accepts it or not
+    async fn connect(
+        deflate: bool,
+    ) -> (
+        WebSocketStream<DeflateStream<DuplexStream>>,
+        WebSocketStream<DeflateStream<DuplexStream>>,
+    ) {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let server = tokio::spawn(accept(server, deflate));
+
+        let mut request = "ws://localhost/ws".into_client_request().unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Extensions",
+            DEFLATE_EXTENSION.parse().unwrap(),
+        );
+        let (client, _) = tokio_tungstenite::client_async(request, DeflateStream::client(client))
+            .await
+            .unwrap();
+        let server = server.await.unwrap();
+        assert_eq!(server.is_deflating(), deflate);
+        assert_eq!(client.get_ref().is_deflating(), deflate);
+
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_messages_round_trip_compressed() {
+        let (mut client, mut server) = connect(true).await;
+        let log = "GET /index.html 200 0.003s\n".repeat(4000);
+        let binary: Vec<u8> = (0..100_000u32).map(|i| (i % 7) as u8).collect();
+
+        client.send(Message::Text(log.clone())).await.unwrap();
+        client.send(Message::Binary(binary.clone())).await.unwrap();
+        client.send(Message::Text("short".into())).await.unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Text(log.clone())
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Binary(binary)
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Text("short".into())
+        );
+
+        server.send(Message::Text(log.clone())).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::Text(log));
+    }
+
+    #[tokio::test]
+    async fn test_declined_offer_leaves_frames_alone() {
+        let (mut client, mut server) = connect(false).await;
+        let log = "GET /index.html 200 0.003s\n".repeat(100);
+
+        client.send(Message::Text(log.clone())).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::Text(log));
+    }
+
+    /// Append a frame that may be a fragment of a message
+    fn write_fragment(out: &mut Vec<u8>, fin: bool, rsv1: bool, opcode: u8, payload: &[u8]) {
+        let start = out.len();
+        write_frame(out, rsv1, opcode, Some([1, 2, 3, 4]), payload);
+        if !fin {
+            out[start] &= !0x80;
+        }
+    }
+
+    fn compressed(data: &[u8]) -> Vec<u8> {
+        deflate(&mut Compress::new(Compression::fast(), false), data).unwrap()
+    }
+
+    /// A server reading the frames a client writes to the returned end
+    async fn raw_client() -> (DuplexStream, WebSocketStream<DeflateStream<DuplexStream>>) {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let server = DeflateStream::server(server, true);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_messages_are_inflated_around_control_frames() {
+        let (mut client, mut server) = raw_client().await;
+        let log = "GET /index.html 200 0.003s\n".repeat(100);
+        let payload = compressed(log.as_bytes());
+        let (first, rest) = payload.split_at(payload.len() / 3);
+        let (second, third) = rest.split_at(rest.len() / 2);
+
+        let mut frames = Vec::new();
+        write_fragment(&mut frames, false, true, OP_TEXT, first);
+        write_fragment(&mut frames, true, false, 0x9, b"ping");
+        write_fragment(&mut frames, false, false, OP_CONTINUATION, second);
+        write_fragment(&mut frames, true, false, 0x9, b"again");
+        write_fragment(&mut frames, true, false, OP_CONTINUATION, third);
+        // Frames split across reads are put back together
+        for piece in frames.chunks(7) {
+            client.write_all(piece).await.unwrap();
+        }
+
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Ping(b"ping".to_vec())
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Ping(b"again".to_vec())
+        );
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::Text(log));
+    }
+
+    #[test]
+    fn test_new_message_while_fragments_are_pending_is_an_error() {
+        let payload = compressed(&[b'x'; 1000]);
+        for (rsv1, opcode) in [(true, OP_TEXT), (false, OP_BINARY)] {
+            let mut stream = DeflateStream::server(Vec::<u8>::new(), true);
+            write_fragment(&mut stream.read_raw, false, true, OP_TEXT, &payload);
+            write_fragment(&mut stream.read_raw, true, rsv1, opcode, &payload);
+            assert_eq!(
+                stream.process_read().unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+
+        let mut stream = DeflateStream::server(Vec::<u8>::new(), true);
+        write_fragment(&mut stream.read_raw, false, true, OP_TEXT, &payload);
+        write_fragment(&mut stream.read_raw, true, true, OP_CONTINUATION, &payload);
+        assert!(stream.process_read().is_err());
+    }
+
+    #[test]
+    fn test_inflate_bombs_stop_at_the_size_limit() {
+        // Sync-flushed pieces join into one stream without compressing it all
+        let mut piece = compressed(&vec![0; 1 << 20]);
+        piece.extend_from_slice(&SYNC_TAIL);
+        let pieces = (MAX_INFLATED_SIZE >> 20) + 1;
+        let mut bomb = piece.repeat(pieces);
+        bomb.truncate(bomb.len() - SYNC_TAIL.len());
+        assert!(bomb.len() < MAX_INFLATED_SIZE / 100);
+
+        let mut decompress = Decompress::new(false);
+        let err = inflate(&mut decompress, bomb.clone()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Exactly at the limit is still fine
+        let at_limit = bomb.len() - piece.len();
+        let mut decompress = Decompress::new(false);
+        let inflated = inflate(&mut decompress, bomb[..at_limit].to_vec()).unwrap();
+        assert_eq!(inflated.len(), MAX_INFLATED_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frames_at_eof_are_errors() {
+        let payload = compressed(&[b'x'; 1000]);
+
+        // Part of a frame
+        let (mut client, mut server) = raw_client().await;
+        let mut frames = Vec::new();
+        write_fragment(&mut frames, true, true, OP_TEXT, &payload);
+        client.write_all(&frames[..frames.len() - 1]).await.unwrap();
+        drop(client);
+        assert!(server.next().await.unwrap().is_err());
+
+        // Whole frames, but not the whole message
+        let (mut client, mut server) = raw_client().await;
+        let mut frames = Vec::new();
+        write_fragment(&mut frames, false, true, OP_TEXT, &payload);
+        client.write_all(&frames).await.unwrap();
+        drop(client);
+        assert!(server.next().await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_compressed_frames_are_smaller_and_flagged() {
+        let mut stream = DeflateStream::server(Vec::<u8>::new(), true);
+        let log = "GET /index.html 200 0.003s\n".repeat(100);
+        write_frame(&mut stream.write_raw, false, OP_TEXT, None, log.as_bytes());
+        write_frame(&mut stream.write_raw, false, OP_TEXT, None, b"short");
+        stream.process_write().unwrap();
+
+        let first = FrameHeader::parse(&stream.write_ready).unwrap();
+        assert!(first.rsv1);
+        assert!(first.payload_len < log.len() / 10);
+        let second =
+            FrameHeader::parse(&stream.write_ready[first.header_len + first.payload_len..])
+                .unwrap();
+        assert!(!second.rsv1);
+        assert_eq!(second.payload_len, 5);
+    }
+
+    #[test]
+    fn test_offers_are_checked_for_supported_parameters() {
+        assert!(offers_deflate([DEFLATE_EXTENSION]));
+        assert!(offers_deflate([
+            "permessage-deflate; client_max_window_bits"
+        ]));
+        assert!(offers_deflate([
+            "x-webkit-deflate-frame, permessage-deflate"
+        ]));
+        assert!(!offers_deflate([
+            "permessage-deflate; server_max_window_bits=10"
+        ]));
+        assert!(!offers_deflate(["x-webkit-deflate-frame"]));
+        assert!(!offers_deflate([]));
+    }
+}
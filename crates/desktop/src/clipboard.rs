@@ -1,58 +1,241 @@
 use arboard::{Clipboard, ImageData};
-use clipsync_common::ClipboardItem;
+use clipsync_common::{ClipboardFlavor, ClipboardItem};
 use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+use crate::files::FileTransfer;
+
+/// Flavors this client can put on the clipboard, richest first. arboard has
+/// no RTF support, so RTF is neither read nor written.
+const WRITABLE_FLAVORS: &[&str] = &["text/html", "image/png", "text/plain"];
 
 pub struct ClipboardManager {
     clipboard: Clipboard,
+    files: FileTransfer,
+    /// Fingerprint of the clipboard seen by the last `read_if_changed`
+    last_fingerprint: Option<u64>,
+}
+
+/// Raw clipboard contents, before any encoding
+struct Snapshot {
+    /// Copied files; when present, the other flavors only describe their paths
+    files: Option<Vec<PathBuf>>,
+    html: Option<String>,
+    image: Option<ImageData<'static>>,
+    text: Option<String>,
+}
+
+impl Snapshot {
+    fn is_empty(&self) -> bool {
+        self.files.is_none() && self.html.is_none() && self.image.is_none() && self.text.is_none()
+    }
+
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        if let Some(paths) = &self.files {
+            // Size and modification time stand in for the content, which may be large
+            for path in paths {
+                path.hash(&mut hasher);
+                if let Ok(metadata) = std::fs::metadata(path) {
+                    metadata.len().hash(&mut hasher);
+                    metadata.modified().ok().hash(&mut hasher);
+                }
+            }
+        }
+        self.html.hash(&mut hasher);
+        self.text.hash(&mut hasher);
+        if let Some(img) = &self.image {
+            img.width.hash(&mut hasher);
+            img.height.hash(&mut hasher);
+            img.bytes.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Build a clipboard item from every flavor that was present
+    fn into_item(self, files: &FileTransfer) -> anyhow::Result<Option<ClipboardItem>> {
+        if let Some(paths) = self.files {
+            return Ok(files.read(&paths)?.map(ClipboardItem::files));
+        }
+
+        let mut flavors = Vec::new();
+        if let Some(html) = self.html {
+            flavors.push(ClipboardFlavor::text("text/html", html));
+        }
+        if let Some(img) = &self.image {
+            flavors.push(ClipboardFlavor::binary("image/png", image_to_png(img)?));
+        }
+        if let Some(text) = self.text {
+            flavors.push(ClipboardFlavor::text("text/plain", text));
+        }
+        Ok(ClipboardItem::from_flavors(flavors))
+    }
 }
 
 impl ClipboardManager {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(files: FileTransfer) -> anyhow::Result<Self> {
         Ok(Self {
             clipboard: Clipboard::new()?,
+            files,
+            last_fingerprint: None,
         })
     }
 
+    /// Read the clipboard only if it changed since the previous call.
+    ///
+    /// A cheap fingerprint is taken first, so unchanged contents are never
+    /// read in full, images never re-encoded to PNG and files never re-read.
+    pub fn read_if_changed(&mut self) -> anyhow::Result<Option<ClipboardItem>> {
+        let Some(fingerprint) = self.probe() else {
+            return Ok(None);
+        };
+        if !self.update_fingerprint(fingerprint) {
+            return Ok(None);
+        }
+        self.read()
+    }
+
+    /// Fingerprint the clipboard without reading all of it: the platform's
+    /// change counter where it keeps one, otherwise a hash of the flavors that
+    /// are cheap to read. The image is only read when there is nothing else to
+    /// tell one copy from the next.
+    fn probe(&mut self) -> Option<u64> {
+        if let Some(count) = change_count() {
+            return Some(count);
+        }
+        let mut snapshot = self.cheap_snapshot();
+        if snapshot.is_empty() {
+            snapshot.image = self.clipboard.get_image().ok();
+        }
+        (!snapshot.is_empty()).then(|| snapshot.fingerprint())
+    }
+
+    /// Record a new fingerprint, returning whether it differs from the last one
+    fn update_fingerprint(&mut self, fingerprint: u64) -> bool {
+        let changed = self.last_fingerprint != Some(fingerprint);
+        self.last_fingerprint = Some(fingerprint);
+        changed
+    }
+
     /// Read the current clipboard content
     pub fn read(&mut self) -> anyhow::Result<Option<ClipboardItem>> {
-        // Try to read image first
-        if let Ok(img) = self.clipboard.get_image() {
-            // Convert image to PNG bytes
-            let png_data = image_to_png(&img)?;
-            return Ok(Some(ClipboardItem::image(png_data, "image/png")));
+        match self.snapshot() {
+            Some(snapshot) => snapshot.into_item(&self.files),
+            None => Ok(None),
         }
+    }
+
+    /// Grab every flavor the platform exposes, or `None` if the clipboard is empty
+    fn snapshot(&mut self) -> Option<Snapshot> {
+        let mut snapshot = self.cheap_snapshot();
+        if snapshot.files.is_none() {
+            snapshot.image = self.clipboard.get_image().ok();
+        }
+        (!snapshot.is_empty()).then_some(snapshot)
+    }
 
-        // Try to read text
-        if let Ok(text) = self.clipboard.get_text() {
-            if !text.is_empty() {
-                return Ok(Some(ClipboardItem::text(text)));
+    /// Grab the copied file list, or else the HTML and text, leaving out the image
+    fn cheap_snapshot(&mut self) -> Snapshot {
+        if let Ok(paths) = self.clipboard.get().file_list() {
+            if !paths.is_empty() {
+                return Snapshot {
+                    files: Some(paths),
+                    html: None,
+                    image: None,
+                    text: None,
+                };
             }
         }
 
-        Ok(None)
+        Snapshot {
+            files: None,
+            html: self.clipboard.get().html().ok().filter(|h| !h.is_empty()),
+            image: None,
+            text: self.clipboard.get_text().ok().filter(|t| !t.is_empty()),
+        }
     }
 
-    /// Write content to clipboard
+    /// Write content to clipboard, using the richest flavor this platform can hold.
+    ///
+    /// HTML goes on with its plain-text alternative; any other flavor goes on
+    /// alone, since arboard cannot set an image and text together.
     pub fn write(&mut self, item: &ClipboardItem) -> anyhow::Result<()> {
         match item {
-            ClipboardItem::Text(text) => {
-                self.clipboard.set_text(text)?;
+            ClipboardItem::Encrypted { .. } => {
+                return Err(anyhow::anyhow!(
+                    "Cannot write a sealed item to the clipboard"
+                ));
             }
-            ClipboardItem::Image { mime_type, .. } => {
-                if mime_type.starts_with("image/") {
-                    let bytes = item
-                        .decode_image_data()
-                        .ok_or_else(|| anyhow::anyhow!("Failed to decode image data"))?;
-
-                    let img = png_to_image(&bytes)?;
-                    self.clipboard.set_image(img)?;
-                }
+            ClipboardItem::Files(files) => {
+                // Pasting in a file manager copies from the download directory
+                let paths = self.files.save(files)?;
+                self.clipboard.set().file_list(&paths)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let flavor = item
+            .preferred_flavor(WRITABLE_FLAVORS)
+            .ok_or_else(|| anyhow::anyhow!("No supported clipboard format in item"))?;
+
+        match flavor.mime_type.as_str() {
+            "text/html" => {
+                let html = flavor
+                    .as_text()
+                    .ok_or_else(|| anyhow::anyhow!("HTML is not valid UTF-8"))?;
+                // Keep the plain-text flavor as the fallback for apps that cannot paste HTML
+                let alt_text = item
+                    .preferred_flavor(&["text/plain"])
+                    .and_then(|plain| plain.as_text().map(str::to_string));
+                self.clipboard.set_html(html.to_string(), alt_text)?;
+            }
+            "image/png" => {
+                let img = png_to_image(&flavor.data)?;
+                self.clipboard.set_image(img)?;
+            }
+            _ => {
+                let text = flavor
+                    .as_text()
+                    .ok_or_else(|| anyhow::anyhow!("Text is not valid UTF-8"))?;
+                self.clipboard.set_text(text)?;
             }
         }
         Ok(())
     }
 }
 
+/// The counter Windows bumps on every clipboard change
+#[cfg(target_os = "windows")]
+fn change_count() -> Option<u64> {
+    use windows_sys::Win32::System::DataExchange::GetClipboardSequenceNumber;
+
+    // Zero when this session has no access to the clipboard
+    let count = unsafe { GetClipboardSequenceNumber() };
+    (count != 0).then_some(count as u64)
+}
+
+/// The counter macOS bumps on every change to the general pasteboard
+#[cfg(target_os = "macos")]
+fn change_count() -> Option<u64> {
+    use objc2::rc::Retained;
+    use objc2::runtime::AnyObject;
+    use objc2::{class, msg_send};
+
+    let pasteboard: Option<Retained<AnyObject>> =
+        unsafe { msg_send![class!(NSPasteboard), generalPasteboard] };
+    let count: isize = unsafe { msg_send![&*pasteboard?, changeCount] };
+    Some(count as u64)
+}
+
+/// X11 and Wayland keep no change counter
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn change_count() -> Option<u64> {
+    None
+}
+
 /// Convert ImageData to PNG bytes
 fn image_to_png(img: &ImageData) -> anyhow::Result<Vec<u8>> {
     use std::io::Cursor;
@@ -1,6 +1,55 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+/// What happens to clips received from other devices while sync is paused
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InboundPausePolicy {
+    /// Ignore them entirely
+    #[default]
+    Drop,
+    /// Keep the newest one and write it to the clipboard on resume
+    Queue,
+    /// Write them to the clipboard as usual; only outbound sync is paused
+    Apply,
+}
+
+/// How local clipboard changes are detected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardWatchMode {
+    /// Use change notifications, falling back to polling if they are unavailable
+    #[default]
+    Events,
+    /// Always poll the clipboard every `clipboard_poll_interval_ms`
+    Poll,
+}
+
+fn default_poll_interval_ms() -> u64 {
+    500
+}
+
+fn default_max_file_sync_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    15
+}
+
+fn default_heartbeat_timeout_secs() -> u64 {
+    10
+}
+
+fn default_websocket_deflate() -> bool {
+    true
+}
+
+/// A fresh random device ID
+pub fn new_device_id() -> String {
+    format!("device-{}", uuid::Uuid::new_v4())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server_url: Option<String>,
@@ -9,6 +58,41 @@ pub struct Config {
     pub autostart: bool,
     #[serde(default)]
     pub start_minimized: bool,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub inbound_while_paused: InboundPausePolicy,
+    #[serde(default)]
+    pub clipboard_watch_mode: ClipboardWatchMode,
+    #[serde(default = "default_poll_interval_ms")]
+    pub clipboard_poll_interval_ms: u64,
+    /// PEM bundle of CA certificates to trust for `wss://` servers
+    #[serde(default)]
+    pub tls_ca_bundle: Option<PathBuf>,
+    /// SHA-256 fingerprint of a pinned (e.g. self-signed) server certificate
+    #[serde(default)]
+    pub tls_pinned_sha256: Option<String>,
+    /// Offer the server WebSocket permessage-deflate compression
+    #[serde(default = "default_websocket_deflate")]
+    pub websocket_deflate: bool,
+    /// Where received files are saved (defaults to a ClipSync folder in Downloads)
+    #[serde(default)]
+    pub download_dir: Option<PathBuf>,
+    /// Largest total size of copied files that will be synced
+    #[serde(default = "default_max_file_sync_bytes")]
+    pub max_file_sync_bytes: u64,
+    /// Identifies this installation to the server; created on first start
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// Name shown to the other devices in the room (defaults to the host name)
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// Seconds between pings to the server, 0 to turn heartbeats off
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    /// Seconds to wait for an answer before reconnecting
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,
 }
 
 impl Default for Config {
@@ -18,6 +102,19 @@ impl Default for Config {
             shared_secret: None,
             autostart: false,
             start_minimized: false,
+            paused: false,
+            inbound_while_paused: InboundPausePolicy::default(),
+            clipboard_watch_mode: ClipboardWatchMode::default(),
+            clipboard_poll_interval_ms: default_poll_interval_ms(),
+            tls_ca_bundle: None,
+            tls_pinned_sha256: None,
+            websocket_deflate: default_websocket_deflate(),
+            download_dir: None,
+            max_file_sync_bytes: default_max_file_sync_bytes(),
+            device_id: None,
+            device_name: None,
+            heartbeat_interval_secs: default_heartbeat_interval_secs(),
+            heartbeat_timeout_secs: default_heartbeat_timeout_secs(),
         }
     }
 }
@@ -35,6 +132,24 @@ impl Config {
         Ok(config)
     }
 
+    /// Give this installation a device ID if it has none yet, returning
+    /// whether one was created and needs saving
+    pub fn ensure_device_id(&mut self) -> bool {
+        if self.device_id.is_some() {
+            return false;
+        }
+        self.device_id = Some(new_device_id());
+        true
+    }
+
+    /// Name to show the other devices: the configured one, or the host name
+    pub fn device_name(&self) -> String {
+        self.device_name
+            .clone()
+            .filter(|name| !name.trim().is_empty())
+            .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned())
+    }
+
     pub fn save(&self) -> anyhow::Result<()> {
         let path = Self::config_path()?;
 
@@ -54,3 +169,30 @@ impl Config {
         Ok(config_dir.join("clipsync").join("config.json"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_id_is_created_once() {
+        // Configs saved by older versions have no device ID
+        let mut config: Config =
+            serde_json::from_str(r#"{"server_url":null,"shared_secret":null}"#).unwrap();
+        assert!(config.ensure_device_id());
+        let id = config.device_id.clone().unwrap();
+        assert!(id.starts_with("device-"));
+
+        assert!(!config.ensure_device_id());
+        assert_eq!(config.device_id, Some(id));
+    }
+
+    #[test]
+    fn test_blank_device_name_falls_back_to_host_name() {
+        let config = Config {
+            device_name: Some("  ".to_string()),
+            ..Config::default()
+        };
+        assert!(!config.device_name().trim().is_empty());
+    }
+}
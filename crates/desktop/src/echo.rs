@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+/// How many remotely applied content hashes to remember
+const MAX_REMOTE_HASHES: usize = 16;
+
+/// Content hashes of clips written to the clipboard on behalf of other devices.
+///
+/// The clipboard monitor checks this before sending, so a clip received from
+/// the server is not mistaken for a local copy and bounced back to the room.
+#[derive(Debug, Default)]
+pub struct EchoGuard {
+    hashes: VecDeque<String>,
+}
+
+impl EchoGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember the hash of content applied from a remote clip
+    pub fn record_remote(&mut self, hash: String) {
+        if self.hashes.contains(&hash) {
+            return;
+        }
+        if self.hashes.len() >= MAX_REMOTE_HASHES {
+            self.hashes.pop_front();
+        }
+        self.hashes.push_back(hash);
+    }
+
+    /// Whether `hash` belongs to a remote clip.
+    ///
+    /// A match is consumed, so copying the same content locally later on is
+    /// still synced.
+    pub fn take_remote(&mut self, hash: &str) -> bool {
+        match self.hashes.iter().position(|h| h == hash) {
+            Some(pos) => {
+                self.hashes.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_hash_is_suppressed_once() {
+        let mut guard = EchoGuard::new();
+        guard.record_remote("abc".to_string());
+
+        assert!(guard.take_remote("abc"));
+        assert!(!guard.take_remote("abc"));
+    }
+
+    #[test]
+    fn test_local_hash_is_not_suppressed() {
+        let mut guard = EchoGuard::new();
+        guard.record_remote("abc".to_string());
+
+        assert!(!guard.take_remote("def"));
+        assert!(guard.take_remote("abc"));
+    }
+
+    #[test]
+    fn test_oldest_hash_is_forgotten_when_full() {
+        let mut guard = EchoGuard::new();
+        for i in 0..=MAX_REMOTE_HASHES {
+            guard.record_remote(format!("hash-{}", i));
+        }
+
+        assert!(!guard.take_remote("hash-0"));
+        assert!(guard.take_remote(&format!("hash-{}", MAX_REMOTE_HASHES)));
+    }
+}
@@ -0,0 +1,305 @@
+use clipsync_common::ClipboardFile;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// Copied files that exceed the size cap, so they are not synced
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("Copied files total {total} bytes, over the {max} byte limit")]
+pub struct TooLarge {
+    pub total: u64,
+    pub max: u64,
+}
+
+/// Reads copied files for sending and saves received ones
+#[derive(Debug, Clone)]
+pub struct FileTransfer {
+    /// Received files are written to a fresh subdirectory of this directory
+    pub download_dir: PathBuf,
+    /// Maximum total size of the files in one copy
+    pub max_bytes: u64,
+}
+
+impl FileTransfer {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            download_dir: config
+                .download_dir
+                .clone()
+                .unwrap_or_else(default_download_dir),
+            max_bytes: config.max_file_sync_bytes,
+        }
+    }
+
+    /// Read the copied files, or `None` if there are none.
+    ///
+    /// Directories and other non-regular files are skipped. Fails with
+    /// [`TooLarge`] if the files exceed the size cap.
+    pub fn read(&self, paths: &[PathBuf]) -> anyhow::Result<Option<Vec<ClipboardFile>>> {
+        let mut regular = Vec::new();
+        let mut total = 0u64;
+        for path in paths {
+            let metadata = std::fs::metadata(path)?;
+            if !metadata.is_file() {
+                tracing::debug!("Skipping copied non-file {}", path.display());
+                continue;
+            }
+            total += metadata.len();
+            regular.push((path, metadata));
+        }
+
+        if regular.is_empty() {
+            return Ok(None);
+        }
+        if total > self.max_bytes {
+            return Err(TooLarge {
+                total,
+                max: self.max_bytes,
+            }
+            .into());
+        }
+
+        let mut files = Vec::with_capacity(regular.len());
+        for (path, metadata) in regular {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                tracing::debug!("Skipping copied file without a UTF-8 name");
+                continue;
+            };
+            let content = std::fs::read(path)?;
+            files.push(ClipboardFile::new(name, &content, permissions(&metadata)));
+        }
+        Ok(Some(files))
+    }
+
+    /// Write received files to a new directory and return their paths
+    pub fn save(&self, files: &[ClipboardFile]) -> anyhow::Result<Vec<PathBuf>> {
+        // The bytes actually received, not the sizes the sender declared
+        let total: u64 = files
+            .iter()
+            .flat_map(|f| &f.chunks)
+            .map(|chunk| chunk.len() as u64)
+            .sum();
+        if total > self.max_bytes {
+            return Err(anyhow::anyhow!(
+                "Received files total {} bytes, over the {} byte limit",
+                total,
+                self.max_bytes
+            ));
+        }
+
+        // One directory per clip, so files with the same name never overwrite each other
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let dir = self.download_dir.join(timestamp.to_string());
+        std::fs::create_dir_all(&dir)?;
+
+        let mut paths = Vec::with_capacity(files.len());
+        let mut taken = HashSet::new();
+        for file in files {
+            let Some(name) = file.safe_name() else {
+                tracing::warn!("Skipping received file with unsafe name {:?}", file.name);
+                continue;
+            };
+            let content = file
+                .content()
+                .ok_or_else(|| anyhow::anyhow!("Corrupt content for received file {}", name))?;
+
+            let path = dir.join(unique_name(name, &mut taken));
+            std::fs::write(&path, content)?;
+            set_permissions(&path, file.permissions)?;
+            paths.push(path);
+        }
+
+        tracing::info!(
+            "Saved {} received file(s) to {}",
+            paths.len(),
+            dir.display()
+        );
+        Ok(paths)
+    }
+}
+
+/// `name`, or `name (2)`, `name (3)` and so on if an earlier file in the same
+/// clip took it. Names are compared ignoring case, as some filesystems do.
+fn unique_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    let mut candidate = name.to_string();
+    let mut n = 2;
+    while !taken.insert(candidate.to_lowercase()) {
+        candidate = match extension {
+            Some(extension) => format!("{} ({}).{}", stem, n, extension),
+            None => format!("{} ({})", stem, n),
+        };
+        n += 1;
+    }
+    candidate
+}
+
+fn default_download_dir() -> PathBuf {
+    dirs::download_dir()
+        .or_else(dirs::home_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("ClipSync")
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn permissions(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_permissions(path: &Path, permissions: Option<u32>) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match permissions {
+        // Only plain rwx bits; never setuid, setgid or sticky
+        Some(mode) => std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_permissions(_path: &Path, _permissions: Option<u32>) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(name: &str, max_bytes: u64) -> FileTransfer {
+        let dir = std::env::temp_dir().join(format!(
+            "clipsync-files-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        FileTransfer {
+            download_dir: dir,
+            max_bytes,
+        }
+    }
+
+    #[test]
+    fn test_files_round_trip_through_download_dir() {
+        let transfer = transfer("round-trip", 1024);
+        let files = vec![
+            ClipboardFile::new("notes.txt", b"hello", Some(0o640)),
+            ClipboardFile::new("empty", b"", None),
+        ];
+
+        let paths = transfer.save(&files).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(std::fs::read(&paths[0]).unwrap(), b"hello");
+
+        let read = transfer.read(&paths).unwrap().unwrap();
+        assert_eq!(read[0].name, "notes.txt");
+        assert_eq!(read[0].content(), Some(b"hello".to_vec()));
+        #[cfg(unix)]
+        assert_eq!(read[0].permissions, Some(0o640));
+        assert_eq!(read[1].name, "empty");
+
+        let _ = std::fs::remove_dir_all(&transfer.download_dir);
+    }
+
+    #[test]
+    fn test_size_cap_applies_both_ways() {
+        let transfer = transfer("cap", 4);
+        let files = vec![ClipboardFile::new("big.bin", b"too large", None)];
+        assert!(transfer.save(&files).is_err());
+
+        std::fs::create_dir_all(&transfer.download_dir).unwrap();
+        let path = transfer.download_dir.join("big.bin");
+        std::fs::write(&path, b"too large").unwrap();
+        let err = transfer.read(&[path]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TooLarge>(),
+            Some(&TooLarge { total: 9, max: 4 })
+        );
+
+        let _ = std::fs::remove_dir_all(&transfer.download_dir);
+    }
+
+    #[test]
+    fn test_copy_without_regular_files_is_not_an_error() {
+        let transfer = transfer("no-files", 4);
+        std::fs::create_dir_all(&transfer.download_dir).unwrap();
+
+        let dir = transfer.download_dir.clone();
+        assert_eq!(transfer.read(&[dir]).unwrap(), None);
+
+        let _ = std::fs::remove_dir_all(&transfer.download_dir);
+    }
+
+    #[test]
+    fn test_duplicate_names_in_a_clip_are_suffixed() {
+        let transfer = transfer("duplicates", 1024);
+        let files = vec![
+            ClipboardFile::new("notes.txt", b"first", None),
+            ClipboardFile::new("Notes.txt", b"second", None),
+            ClipboardFile::new("notes.txt", b"third", None),
+            ClipboardFile::new(".env", b"fourth", None),
+            ClipboardFile::new(".env", b"fifth", None),
+        ];
+
+        let paths = transfer.save(&files).unwrap();
+        let names: Vec<_> = paths
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "notes.txt",
+                "Notes (2).txt",
+                "notes (3).txt",
+                ".env",
+                ".env (2)"
+            ]
+        );
+        assert_eq!(std::fs::read(&paths[2]).unwrap(), b"third");
+
+        let _ = std::fs::remove_dir_all(&transfer.download_dir);
+    }
+
+    #[test]
+    fn test_declared_sizes_do_not_count_toward_the_cap() {
+        let transfer = transfer("declared", 1024);
+        let mut huge = ClipboardFile::new("a", b"x", None);
+        huge.size = u64::MAX;
+        let files = vec![huge, ClipboardFile::new("b", b"y", None)];
+
+        // Under the cap by what arrived, then refused for not matching its size
+        let err = transfer.save(&files).unwrap_err();
+        assert!(err.to_string().contains("Corrupt content"));
+
+        let _ = std::fs::remove_dir_all(&transfer.download_dir);
+    }
+
+    #[test]
+    fn test_unsafe_names_are_not_written() {
+        let transfer = transfer("unsafe", 1024);
+        let files = vec![ClipboardFile::new("../escape.txt", b"x", None)];
+
+        assert!(transfer.save(&files).unwrap().is_empty());
+        assert!(!transfer
+            .download_dir
+            .parent()
+            .unwrap()
+            .join("escape.txt")
+            .exists());
+
+        let _ = std::fs::remove_dir_all(&transfer.download_dir);
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Application-level pings that tell a dead connection from a quiet one.
+///
+/// A half-open TCP connection looks just like an idle one until something is
+/// sent over it, so the server is pinged at a fixed interval and the
+/// connection is given up on when nothing comes back in time.
+#[derive(Debug)]
+pub struct Heartbeat {
+    /// Time between pings; zero turns heartbeats off
+    interval: Duration,
+    /// How long to wait for an answer
+    timeout: Duration,
+    /// When the last ping was sent, or the session started
+    last_ping: Instant,
+    /// When the ping still waiting for its pong was sent
+    outstanding: Option<Instant>,
+    /// When anything was last received from the server
+    last_heard: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            timeout,
+            last_ping: now,
+            outstanding: None,
+            last_heard: now,
+        }
+    }
+
+    /// Start over for a new session
+    pub fn reset(&mut self, now: Instant) {
+        self.last_ping = now;
+        self.outstanding = None;
+        self.last_heard = now;
+    }
+
+    /// When the next ping is due, unless heartbeats are off or one is
+    /// still unanswered
+    pub fn next_ping(&self) -> Option<Instant> {
+        if self.interval.is_zero() || self.outstanding.is_some() {
+            return None;
+        }
+        Some(self.last_ping + self.interval)
+    }
+
+    pub fn ping_sent(&mut self, now: Instant) {
+        self.last_ping = now;
+        self.outstanding = Some(now);
+    }
+
+    /// Note that the server sent something, which proves the connection is
+    /// alive even if the pong is held up behind other messages
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = now;
+    }
+
+    /// Record the answer to the outstanding ping and return the round-trip time
+    pub fn pong(&mut self, now: Instant) -> Option<Duration> {
+        let sent = self.outstanding.take()?;
+        Some(now.saturating_duration_since(sent))
+    }
+
+    /// When the connection is presumed dead if nothing arrives before then
+    pub fn deadline(&self) -> Option<Instant> {
+        self.outstanding
+            .map(|sent| sent.max(self.last_heard) + self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(15);
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_pong_measures_latency_and_schedules_next_ping() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(INTERVAL, TIMEOUT);
+        heartbeat.reset(start);
+        assert_eq!(heartbeat.next_ping(), Some(start + INTERVAL));
+        assert_eq!(heartbeat.deadline(), None);
+
+        let sent = start + INTERVAL;
+        heartbeat.ping_sent(sent);
+        assert_eq!(heartbeat.next_ping(), None);
+        assert_eq!(heartbeat.deadline(), Some(sent + TIMEOUT));
+
+        assert_eq!(
+            heartbeat.pong(sent + Duration::from_millis(40)),
+            Some(Duration::from_millis(40))
+        );
+        assert_eq!(heartbeat.next_ping(), Some(sent + INTERVAL));
+        assert_eq!(heartbeat.deadline(), None);
+
+        // A late pong for a ping that was already answered is ignored
+        assert_eq!(heartbeat.pong(sent + INTERVAL), None);
+    }
+
+    #[test]
+    fn test_other_traffic_extends_the_deadline() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(INTERVAL, TIMEOUT);
+        heartbeat.reset(start);
+        heartbeat.ping_sent(start);
+
+        let busy = start + Duration::from_secs(8);
+        heartbeat.heard(busy);
+        assert_eq!(heartbeat.deadline(), Some(busy + TIMEOUT));
+    }
+
+    #[test]
+    fn test_zero_interval_disables_pings() {
+        let heartbeat = Heartbeat::new(Duration::ZERO, TIMEOUT);
+        assert_eq!(heartbeat.next_ping(), None);
+        assert_eq!(heartbeat.deadline(), None);
+    }
+}
@@ -1,15 +1,23 @@
 mod clipboard;
 mod config;
+mod echo;
+mod files;
+mod heartbeat;
 mod sync;
+mod tls;
+mod transport;
+mod watcher;
 
+use clipsync_common::DeviceInfo;
 use std::sync::Arc;
-use tauri::menu::{Menu, MenuItem};
+use tauri::menu::{IsMenuItem, Menu, MenuItem, PredefinedMenuItem};
 use tauri::tray::{TrayIconBuilder, TrayIconEvent};
-use tauri::{AppHandle, Manager, State, WindowEvent};
+use tauri::{AppHandle, Emitter, Listener, Manager, State, WindowEvent};
 use tokio::sync::RwLock;
 
-use crate::config::Config;
+use crate::config::{Config, InboundPausePolicy};
 use crate::sync::SyncManager;
+use crate::tls::TlsOptions;
 
 pub struct AppState {
     sync_manager: Arc<RwLock<SyncManager>>,
@@ -20,6 +28,8 @@ pub struct AppState {
 async fn connect_to_server(
     server_url: String,
     shared_secret: String,
+    tls_ca_bundle: Option<String>,
+    tls_pinned_sha256: Option<String>,
     state: State<'_, Arc<RwLock<AppState>>>,
     app: tauri::AppHandle,
 ) -> Result<(), String> {
@@ -28,20 +38,23 @@ async fn connect_to_server(
     let app_state = state.read().await;
 
     // Save config
-    {
+    let tls = {
         let mut config = app_state.config.write().await;
         config.server_url = Some(server_url.clone());
         config.shared_secret = Some(shared_secret.clone());
+        config.tls_ca_bundle = tls_ca_bundle.filter(|p| !p.is_empty()).map(Into::into);
+        config.tls_pinned_sha256 = tls_pinned_sha256.filter(|p| !p.is_empty());
         config.save().map_err(|e| {
             tracing::error!("Failed to save config: {}", e);
             e.to_string()
         })?;
-    }
+        TlsOptions::from_config(&config)
+    };
 
     // Connect
     let mut sync_manager = app_state.sync_manager.write().await;
     sync_manager
-        .connect(server_url, shared_secret, app)
+        .connect(server_url, shared_secret, tls, app)
         .await
         .map_err(|e| {
             tracing::error!("Connection failed: {}", e);
@@ -60,13 +73,77 @@ async fn disconnect_from_server(state: State<'_, Arc<RwLock<AppState>>>) -> Resu
 }
 
 #[tauri::command]
-async fn toggle_sync(paused: bool, state: State<'_, Arc<RwLock<AppState>>>) -> Result<(), String> {
+async fn toggle_sync(paused: bool, app: AppHandle) -> Result<(), String> {
+    set_sync_paused(&app, paused).await
+}
+
+#[tauri::command]
+async fn set_inbound_while_paused(
+    policy: InboundPausePolicy,
+    state: State<'_, Arc<RwLock<AppState>>>,
+) -> Result<(), String> {
     let app_state = state.read().await;
-    let mut sync_manager = app_state.sync_manager.write().await;
-    sync_manager.set_paused(paused);
+    app_state
+        .sync_manager
+        .write()
+        .await
+        .set_inbound_policy(policy);
+
+    let mut config = app_state.config.write().await;
+    config.inbound_while_paused = policy;
+    config.save().map_err(|e| e.to_string())?;
     Ok(())
 }
 
+/// Pause or resume sync, persist the choice and reflect it in the tray and UI
+async fn set_sync_paused(app: &AppHandle, paused: bool) -> Result<(), String> {
+    let state = app.state::<Arc<RwLock<AppState>>>();
+    let app_state = state.read().await;
+    let devices = {
+        let mut sync_manager = app_state.sync_manager.write().await;
+        sync_manager.set_paused(paused);
+        sync_manager.devices()
+    };
+
+    {
+        let mut config = app_state.config.write().await;
+        config.paused = paused;
+        config.save().map_err(|e| e.to_string())?;
+    }
+
+    update_tray(app, paused, &devices);
+    let _ = app.emit("pause-changed", serde_json::json!({ "paused": paused }));
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_device(state: State<'_, Arc<RwLock<AppState>>>) -> Result<DeviceInfo, String> {
+    let app_state = state.read().await;
+    let sync_manager = app_state.sync_manager.read().await;
+    Ok(sync_manager.device())
+}
+
+/// Rename this device, or go back to the host name if `name` is empty
+#[tauri::command]
+async fn set_device_name(
+    name: String,
+    state: State<'_, Arc<RwLock<AppState>>>,
+) -> Result<String, String> {
+    let app_state = state.read().await;
+    let mut config = app_state.config.write().await;
+    let name = name.trim();
+    config.device_name = (!name.is_empty()).then(|| name.to_string());
+    config.save().map_err(|e| e.to_string())?;
+
+    let name = config.device_name();
+    app_state
+        .sync_manager
+        .write()
+        .await
+        .set_device_name(name.clone());
+    Ok(name)
+}
+
 #[tauri::command]
 async fn get_config(state: State<'_, Arc<RwLock<AppState>>>) -> Result<Config, String> {
     let app_state = state.read().await;
@@ -118,12 +195,74 @@ async fn set_start_minimized(
     Ok(())
 }
 
-fn create_tray_menu(app: &AppHandle) -> Result<Menu<tauri::Wry>, tauri::Error> {
+fn create_tray_menu(
+    app: &AppHandle,
+    paused: bool,
+    devices: &[DeviceInfo],
+) -> Result<Menu<tauri::Wry>, tauri::Error> {
+    // The other devices in the room, shown as disabled entries
+    let device_header = if devices.is_empty() {
+        "No other devices online"
+    } else {
+        "Devices online:"
+    };
+    let header_item = MenuItem::new(app, device_header, false, None::<&str>)?;
+    let device_items = devices
+        .iter()
+        .map(|device| {
+            let label = match &device.platform {
+                Some(platform) => format!("{} ({})", device.label(), platform),
+                None => device.label().to_string(),
+            };
+            MenuItem::new(app, label, false, None::<&str>)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let separator = PredefinedMenuItem::separator(app)?;
+
+    let pause_label = if paused { "Resume Sync" } else { "Pause Sync" };
+    let pause_item = MenuItem::with_id(app, "pause", pause_label, true, None::<&str>)?;
     let show_item = MenuItem::with_id(app, "show", "Show Window", true, None::<&str>)?;
     let hide_item = MenuItem::with_id(app, "hide", "Hide Window", true, None::<&str>)?;
     let quit_item = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
 
-    Menu::with_items(app, &[&show_item, &hide_item, &quit_item])
+    let mut items: Vec<&dyn IsMenuItem<tauri::Wry>> = vec![&header_item];
+    items.extend(
+        device_items
+            .iter()
+            .map(|item| item as &dyn IsMenuItem<tauri::Wry>),
+    );
+    items.extend([
+        &separator as &dyn IsMenuItem<tauri::Wry>,
+        &pause_item,
+        &show_item,
+        &hide_item,
+        &quit_item,
+    ]);
+    Menu::with_items(app, &items)
+}
+
+fn tray_tooltip(paused: bool) -> &'static str {
+    if paused {
+        "ClipSync (paused)"
+    } else {
+        "ClipSync"
+    }
+}
+
+/// Refresh the tray menu and tooltip after the pause state or the devices
+/// online changed
+fn update_tray(app: &AppHandle, paused: bool, devices: &[DeviceInfo]) {
+    let Some(tray) = app.tray_by_id("main") else {
+        return;
+    };
+
+    match create_tray_menu(app, paused, devices) {
+        Ok(menu) => {
+            let _ = tray.set_menu(Some(menu));
+        }
+        Err(e) => tracing::error!("Failed to rebuild tray menu: {}", e),
+    }
+    let _ = tray.set_tooltip(Some(tray_tooltip(paused)));
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -139,12 +278,18 @@ pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
         .setup(|app| {
-            // Load config
-            let config = Config::load().unwrap_or_default();
+            // Load config, giving this installation a lasting device ID
+            let mut config = Config::load().unwrap_or_default();
+            if config.ensure_device_id() {
+                if let Err(e) = config.save() {
+                    tracing::error!("Failed to save device ID: {}", e);
+                }
+            }
             let start_minimized = config.start_minimized;
+            let paused = config.paused;
 
-            // Create sync manager
-            let sync_manager = SyncManager::new();
+            // Create sync manager, restoring the pause state from last time
+            let sync_manager = SyncManager::new(&config);
 
             // Create app state
             let app_state = Arc::new(RwLock::new(AppState {
@@ -155,14 +300,28 @@ pub fn run() {
             app.manage(app_state);
 
             // Create tray menu
-            let tray_menu = create_tray_menu(app.handle())?;
+            let tray_menu = create_tray_menu(app.handle(), paused, &[])?;
 
             // Build tray icon
             let _tray = TrayIconBuilder::with_id("main")
                 .icon(app.default_window_icon().unwrap().clone())
                 .menu(&tray_menu)
-                .tooltip("ClipSync")
+                .tooltip(tray_tooltip(paused))
                 .on_menu_event(|app, event| match event.id.as_ref() {
+                    "pause" => {
+                        let app = app.clone();
+                        tauri::async_runtime::spawn(async move {
+                            let paused = {
+                                let state = app.state::<Arc<RwLock<AppState>>>();
+                                let app_state = state.read().await;
+                                let sync_manager = app_state.sync_manager.read().await;
+                                sync_manager.is_paused()
+                            };
+                            if let Err(e) = set_sync_paused(&app, !paused).await {
+                                tracing::error!("Failed to toggle pause: {}", e);
+                            }
+                        });
+                    }
                     "show" => {
                         if let Some(window) = app.get_webview_window("main") {
                             let _ = window.show();
@@ -198,6 +357,21 @@ pub fn run() {
                 })
                 .build(app)?;
 
+            // Keep the tray's list of devices online current
+            let handle = app.handle().clone();
+            app.listen_any("devices-changed", move |_| {
+                let app = handle.clone();
+                tauri::async_runtime::spawn(async move {
+                    let (paused, devices) = {
+                        let state = app.state::<Arc<RwLock<AppState>>>();
+                        let app_state = state.read().await;
+                        let sync_manager = app_state.sync_manager.read().await;
+                        (sync_manager.is_paused(), sync_manager.devices())
+                    };
+                    update_tray(&app, paused, &devices);
+                });
+            });
+
             // Handle window close event - minimize to tray instead
             if let Some(window) = app.get_webview_window("main") {
                 let window_clone = window.clone();
@@ -221,7 +395,10 @@ pub fn run() {
             connect_to_server,
             disconnect_from_server,
             toggle_sync,
+            set_inbound_while_paused,
             get_config,
+            get_device,
+            set_device_name,
             show_window,
             hide_window,
             set_autostart,
@@ -1,30 +1,100 @@
-use clipsync_common::{hash_secret, ClientMessage, ClipboardItem, ServerMessage};
+use clipsync_common::{
+    blob_hash, decrypt_item, encrypt_item, from_msgpack, hash_secret, new_clip_id, split_chunks,
+    to_msgpack, ChunkAssembler, ClientMessage, ClipboardEntry, ClipboardItem, Codec, DeviceInfo,
+    EncryptionKey, ErrorCode, Frame, ServerMessage, INLINE_ITEM_LIMIT, MIN_PROTOCOL_VERSION,
+};
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
+use rustls::ClientConfig;
+use std::collections::VecDeque;
+use std::ops::ControlFlow;
 use std::sync::Arc;
+use std::time::Duration;
 use tauri::Emitter;
 use tokio::sync::mpsc;
-use tokio::sync::RwLock;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio::sync::{watch, RwLock};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
 
 use crate::clipboard::ClipboardManager;
+use crate::config::{new_device_id, ClipboardWatchMode, Config, InboundPausePolicy};
+use crate::echo::EchoGuard;
+use crate::files::{FileTransfer, TooLarge};
+use crate::heartbeat::Heartbeat;
+use crate::tls::TlsOptions;
+use crate::transport::{self, WsStream};
+use crate::watcher;
+
+type WsSink = SplitSink<WsStream, Message>;
+
+/// Delay before the first reconnect attempt
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for the reconnect delay
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// Maximum number of local clips queued while offline
+const MAX_PENDING_CLIPS: usize = 50;
+/// How long to hold back clips after the server reports rate limiting
+const RATE_LIMIT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Pause settings shared with the monitor and connection tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PauseState {
+    pub paused: bool,
+    pub inbound: InboundPausePolicy,
+}
+
+/// How the clipboard monitor detects local changes
+#[derive(Debug, Clone, Copy)]
+struct MonitorSettings {
+    mode: ClipboardWatchMode,
+    poll_interval: Duration,
+}
 
 pub struct SyncManager {
-    is_connected: bool,
-    is_paused: bool,
-    device_id: String,
-    clipboard_manager: Option<ClipboardManager>,
+    pause_tx: watch::Sender<PauseState>,
+    monitor_settings: MonitorSettings,
+    file_transfer: FileTransfer,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    websocket_deflate: bool,
+    /// How this device presents itself, shared with the connection so a
+    /// rename applies from the next reconnect
+    device_tx: watch::Sender<DeviceInfo>,
+    /// Other devices connected to the room
+    devices: Arc<std::sync::Mutex<Vec<DeviceInfo>>>,
     disconnect_tx: Option<mpsc::UnboundedSender<()>>,
 }
 
 impl SyncManager {
-    pub fn new() -> Self {
-        let device_id = format!("device-{}", uuid::Uuid::new_v4());
+    pub fn new(config: &Config) -> Self {
+        let device = DeviceInfo {
+            device_id: config.device_id.clone().unwrap_or_else(new_device_id),
+            name: Some(config.device_name()),
+            platform: Some(format!(
+                "{} {}",
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            )),
+        };
+        let (device_tx, _) = watch::channel(device);
+        let (pause_tx, _) = watch::channel(PauseState {
+            paused: config.paused,
+            inbound: config.inbound_while_paused,
+        });
 
         Self {
-            is_connected: false,
-            is_paused: false,
-            device_id,
-            clipboard_manager: None,
+            pause_tx,
+            monitor_settings: MonitorSettings {
+                mode: config.clipboard_watch_mode,
+                poll_interval: Duration::from_millis(config.clipboard_poll_interval_ms.max(50)),
+            },
+            file_transfer: FileTransfer::from_config(config),
+            heartbeat_interval: Duration::from_secs(config.heartbeat_interval_secs),
+            heartbeat_timeout: Duration::from_secs(config.heartbeat_timeout_secs.max(1)),
+            websocket_deflate: config.websocket_deflate,
+            device_tx,
+            devices: Arc::default(),
             disconnect_tx: None,
         }
     }
@@ -33,18 +103,24 @@ impl SyncManager {
         &mut self,
         server_url: String,
         shared_secret: String,
+        tls: TlsOptions,
         app: tauri::AppHandle,
     ) -> anyhow::Result<()> {
-        if self.is_connected {
+        if self.is_connected() {
             return Err(anyhow::anyhow!("Already connected"));
         }
 
         // Initialize clipboard manager
-        let clipboard_manager = ClipboardManager::new()?;
-        self.clipboard_manager = Some(clipboard_manager);
+        let clipboard_manager = Arc::new(RwLock::new(ClipboardManager::new(
+            self.file_transfer.clone(),
+        )?));
 
-        // Hash the secret
+        // Hashes of clips applied from other devices, shared with the monitor
+        let echo_guard = Arc::new(RwLock::new(EchoGuard::new()));
+
+        // Hash the secret for the room ID and derive a separate key for the payloads
         let secret_hash = hash_secret(&shared_secret);
+        let key = EncryptionKey::derive(&shared_secret);
 
         // Connect to WebSocket
         let url = if server_url.ends_with("/ws") {
@@ -53,181 +129,1196 @@ impl SyncManager {
             format!("{}/ws", server_url)
         };
 
+        // The first attempt is made here so a bad URL is reported to the caller;
+        // once connected, the supervisor takes care of reconnecting.
         tracing::info!("Connecting to {}", url);
-        let (ws_stream, _) = connect_async(&url).await?;
-        let (mut write, mut read) = ws_stream.split();
+        let join = ClientMessage::join(secret_hash.clone(), self.device());
+        let tls = tls.client_config()?;
+        let session = open_session(&url, &join, tls.clone(), self.websocket_deflate).await?;
 
-        // Send join message
-        let join_msg = ClientMessage::join(secret_hash, self.device_id.clone());
-        write.send(Message::Text(join_msg.to_json()?)).await?;
+        // Create channels
+        let (disconnect_tx, disconnect_rx) = mpsc::unbounded_channel();
+        let (clipboard_tx, clipboard_rx) = mpsc::unbounded_channel::<ClipboardItem>();
 
-        self.is_connected = true;
+        self.disconnect_tx = Some(disconnect_tx);
 
-        // Emit connection status
-        let _ = app.emit(
-            "connection-status",
-            serde_json::json!({
-                "connected": true
-            }),
-        );
+        // Spawn connection supervisor
+        let connection = Connection {
+            url,
+            secret_hash,
+            device_rx: self.device_tx.subscribe(),
+            tls,
+            deflate: self.websocket_deflate,
+            codec: Codec::Json,
+            key,
+            app: app.clone(),
+            clipboard: clipboard_manager.clone(),
+            echo_guard: echo_guard.clone(),
+            pause_rx: self.pause_tx.subscribe(),
+            pending: VecDeque::new(),
+            queued_inbound: None,
+            upload: None,
+            download: None,
+            unacked: VecDeque::new(),
+            retry_at: None,
+            last_seq: None,
+            max_item_size: None,
+            devices: self.devices.clone(),
+            heartbeat: Heartbeat::new(self.heartbeat_interval, self.heartbeat_timeout),
+        };
+        tokio::spawn(connection.supervise(session, clipboard_rx, disconnect_rx));
 
-        // Create channels
-        let (disconnect_tx, mut disconnect_rx) = mpsc::unbounded_channel();
-        let (clipboard_tx, mut clipboard_rx) = mpsc::unbounded_channel::<ClipboardItem>();
+        // Spawn clipboard monitor task
+        tokio::spawn(monitor_clipboard(
+            self.monitor_settings,
+            app,
+            clipboard_manager,
+            echo_guard,
+            self.pause_tx.subscribe(),
+            clipboard_tx,
+        ));
 
-        self.disconnect_tx = Some(disconnect_tx);
+        Ok(())
+    }
 
-        // Spawn WebSocket reader task
-        let app_handle = app.clone();
-        let clipboard_manager_arc = Arc::new(RwLock::new(self.clipboard_manager.take().unwrap()));
-        let clipboard_manager_clone = clipboard_manager_arc.clone();
+    pub async fn disconnect(&mut self) -> anyhow::Result<()> {
+        if !self.is_connected() {
+            return Err(anyhow::anyhow!("Not connected"));
+        }
 
-        tokio::spawn(async move {
-            while let Some(Ok(msg)) = read.next().await {
-                if let Message::Text(text) = msg {
-                    if let Ok(server_msg) = ServerMessage::from_json(&text) {
-                        match server_msg {
-                            ServerMessage::Joined { history } => {
-                                tracing::info!(
-                                    "Joined room, received {} history items",
-                                    history.len()
-                                );
-
-                                // Emit history to frontend
-                                let _ = app_handle.emit(
-                                    "history-loaded",
-                                    serde_json::json!({
-                                        "history": history
-                                    }),
-                                );
-                            }
-                            ServerMessage::ClipReceived { entry } => {
-                                tracing::debug!("Received clipboard from another device");
-
-                                // Write to local clipboard
-                                let mut clipboard = clipboard_manager_clone.write().await;
-                                if let Err(e) = clipboard.write(&entry.item) {
-                                    tracing::error!("Failed to write to clipboard: {}", e);
-                                }
-
-                                // Emit to frontend
-                                let _ = app_handle.emit(
-                                    "clipboard-received",
-                                    serde_json::json!({
-                                        "item": entry.item,
-                                        "timestamp": entry.timestamp
-                                    }),
-                                );
-                            }
-                            ServerMessage::Error { message } => {
-                                tracing::error!("Server error: {}", message);
-                                let _ = app_handle.emit(
-                                    "connection-status",
-                                    serde_json::json!({
-                                        "connected": false,
-                                        "error": message
-                                    }),
-                                );
-                            }
-                            ServerMessage::Ack { timestamp } => {
-                                tracing::trace!("Clip acknowledged: {}", timestamp);
-                            }
-                            _ => {}
-                        }
-                    }
+        if let Some(tx) = self.disconnect_tx.take() {
+            let _ = tx.send(());
+        }
+
+        Ok(())
+    }
+
+    /// Pause or resume outbound capture; inbound clips follow the configured policy
+    pub fn set_paused(&mut self, paused: bool) {
+        self.pause_tx.send_modify(|state| state.paused = paused);
+        tracing::info!("Sync {}", if paused { "paused" } else { "resumed" });
+    }
+
+    /// Choose what happens to received clips while paused
+    pub fn set_inbound_policy(&mut self, inbound: InboundPausePolicy) {
+        self.pause_tx.send_modify(|state| state.inbound = inbound);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause_tx.borrow().paused
+    }
+
+    /// How this device presents itself to the room
+    pub fn device(&self) -> DeviceInfo {
+        self.device_tx.borrow().clone()
+    }
+
+    /// Rename this device; other devices see the new name once the app next
+    /// connects or reconnects
+    pub fn set_device_name(&mut self, name: String) {
+        self.device_tx
+            .send_modify(|device| device.name = Some(name));
+    }
+
+    /// Other devices connected to the room, as of the last presence update
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// Whether a connection is running, including one that is reconnecting;
+    /// false once the user disconnects or the server refuses the session
+    pub fn is_connected(&self) -> bool {
+        self.disconnect_tx
+            .as_ref()
+            .is_some_and(|tx| !tx.is_closed())
+    }
+}
+
+/// How a single WebSocket session ended
+enum SessionEnd {
+    /// The connection dropped and should be re-established
+    Lost,
+    /// The server is shutting down and asked to reconnect after this long
+    ServerShuttingDown(Duration),
+    /// The user asked to disconnect
+    Disconnected,
+    /// The server refused the session; reconnecting will not help
+    Rejected,
+}
+
+/// State shared by every connection attempt of one sync session
+struct Connection {
+    url: String,
+    secret_hash: String,
+    /// This device's details as of the latest rename
+    device_rx: watch::Receiver<DeviceInfo>,
+    /// TLS settings for `wss://` servers
+    tls: Arc<ClientConfig>,
+    /// Whether to offer permessage-deflate
+    deflate: bool,
+    /// Codec negotiated for the current session
+    codec: Codec,
+    key: EncryptionKey,
+    app: tauri::AppHandle,
+    clipboard: Arc<RwLock<ClipboardManager>>,
+    echo_guard: Arc<RwLock<EchoGuard>>,
+    pause_rx: watch::Receiver<PauseState>,
+    /// Local clips captured while offline, sent once the connection is back
+    pending: VecDeque<OutgoingClip>,
+    /// Newest clip received while paused with the `Queue` policy
+    queued_inbound: Option<ClipboardItem>,
+    /// Large local clip being uploaded in chunks
+    upload: Option<Upload>,
+    /// Large remote clip being downloaded in chunks
+    download: Option<Download>,
+    /// Clips sent inline that the server has not acknowledged yet, oldest
+    /// first; sent again after a reconnect or when asked to retry
+    unacked: VecDeque<OutgoingClip>,
+    /// Hold back clips until then after being rate limited
+    retry_at: Option<Instant>,
+    /// Sequence number of the newest history entry received, so a reconnect
+    /// only fetches what was missed
+    last_seq: Option<u64>,
+    /// Largest item the server stores, in bytes
+    max_item_size: Option<u64>,
+    /// Other devices connected to the room, shared with the tray
+    devices: Arc<std::sync::Mutex<Vec<DeviceInfo>>>,
+    /// Pings that detect a dead connection and measure latency
+    heartbeat: Heartbeat,
+}
+
+/// A local clip and the ID it keeps until the server acknowledges it
+#[derive(Clone)]
+struct OutgoingClip {
+    id: String,
+    item: ClipboardItem,
+}
+
+impl OutgoingClip {
+    fn new(item: ClipboardItem) -> Self {
+        Self {
+            id: new_clip_id(),
+            item,
+        }
+    }
+}
+
+/// A large local clip on its way to the server, kept across reconnects
+struct Upload {
+    hash: String,
+    chunks: Vec<Vec<u8>>,
+    /// Chunks still to send in this session
+    to_send: VecDeque<u32>,
+    /// `NewClip` referencing the blob, sent after the last chunk
+    clip: ClientMessage,
+    clip_sent: bool,
+    id: String,
+}
+
+impl Upload {
+    fn has_work(&self) -> bool {
+        !self.to_send.is_empty() || !self.clip_sent
+    }
+
+    /// The next message to send: a chunk, then the clip itself
+    fn next_message(&mut self) -> Option<ClientMessage> {
+        if let Some(index) = self.to_send.pop_front() {
+            return Some(ClientMessage::UploadChunk {
+                hash: self.hash.clone(),
+                index,
+                total: self.chunks.len() as u32,
+                data: self.chunks[index as usize].clone(),
+            });
+        }
+        if !self.clip_sent {
+            self.clip_sent = true;
+            return Some(self.clip.clone());
+        }
+        None
+    }
+}
+
+/// A large remote clip being fetched, kept across reconnects
+struct Download {
+    /// The received entry, whose item is the reference
+    entry: ClipboardEntry,
+    /// Created once the first chunk tells us the chunk count
+    chunks: Option<ChunkAssembler>,
+}
+
+impl Download {
+    fn hash(&self) -> &str {
+        self.entry.item.reference_hash().unwrap_or_default()
+    }
+
+    /// Where to continue fetching from after a reconnect
+    fn resume_index(&self) -> u32 {
+        self.chunks
+            .as_ref()
+            .and_then(|chunks| chunks.missing().first().copied())
+            .unwrap_or(0)
+    }
+}
+
+impl Connection {
+    /// Run sessions until the user disconnects, reconnecting with backoff in between
+    async fn supervise(
+        mut self,
+        session: (WsStream, ServerMessage),
+        mut clipboard_rx: mpsc::UnboundedReceiver<ClipboardItem>,
+        mut disconnect_rx: mpsc::UnboundedReceiver<()>,
+    ) {
+        let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
+        let mut session = Some(session);
+        let mut come_back_after = Duration::ZERO;
+
+        loop {
+            if let Some((stream, joined)) = session.take() {
+                backoff.reset();
+                let end = self
+                    .run_session(stream, joined, &mut clipboard_rx, &mut disconnect_rx)
+                    .await;
+                // Presence is only known while connected
+                self.update_devices(Vec::clear);
+                match end {
+                    SessionEnd::Disconnected | SessionEnd::Rejected => break,
+                    SessionEnd::ServerShuttingDown(after) => come_back_after = after,
+                    SessionEnd::Lost => {}
                 }
             }
 
-            // Connection closed
-            let _ = app_handle.emit(
-                "connection-status",
-                serde_json::json!({
-                    "connected": false
-                }),
-            );
-        });
+            // After a shutdown, wait at least as long as the server asked,
+            // spread over as long again so devices do not all return at once
+            let restart_delay = come_back_after + come_back_after.mul_f64(rand::thread_rng().gen());
+            come_back_after = Duration::ZERO;
+            let attempt = backoff.attempt() + 1;
+            let delay = backoff.next_delay().max(restart_delay);
+            tracing::info!("Reconnecting in {:?} (attempt {})", delay, attempt);
+            self.emit_status(serde_json::json!({
+                "connected": false,
+                "reconnecting": true,
+                "attempt": attempt,
+                "status": format!("reconnecting (attempt {})", attempt)
+            }));
 
-        // Spawn WebSocket writer task
-        tokio::spawn(async move {
+            // Wait out the backoff, still collecting local clips
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
             loop {
                 tokio::select! {
-                    Some(item) = clipboard_rx.recv() => {
-                        let timestamp = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_millis() as u64;
-
-                        let msg = ClientMessage::new_clip(item, timestamp);
-                        if let Ok(json) = msg.to_json() {
-                            if let Err(e) = write.send(Message::Text(json)).await {
+                    _ = &mut sleep => break,
+                    Some(item) = clipboard_rx.recv() => self.queue(OutgoingClip::new(item)),
+                    _ = disconnect_rx.recv() => {
+                        tracing::info!("Disconnected while reconnecting");
+                        self.emit_status(serde_json::json!({ "connected": false }));
+                        return;
+                    }
+                }
+            }
+
+            // Built afresh so a rename since the last session is announced
+            let mut join =
+                ClientMessage::join(self.secret_hash.clone(), self.device_rx.borrow().clone());
+            if let ClientMessage::Join { since, .. } = &mut join {
+                *since = self.last_seq;
+            }
+            match open_session(&self.url, &join, self.tls.clone(), self.deflate).await {
+                Ok(reconnected) => {
+                    tracing::info!("Reconnected after {} attempt(s)", attempt);
+                    session = Some(reconnected);
+                }
+                Err(e) => {
+                    if let Some(refused) = fatal_refusal(&e) {
+                        tracing::error!(
+                            "Server refused to rejoin ({:?}): {}",
+                            refused.code,
+                            refused.message
+                        );
+                        self.emit_status(serde_json::json!({
+                            "connected": false,
+                            "error": refused.message
+                        }));
+                        break;
+                    }
+                    tracing::warn!("Reconnect attempt {} failed: {}", attempt, e);
+                }
+            }
+        }
+    }
+
+    /// Relay messages in both directions until the connection drops or the user disconnects
+    async fn run_session(
+        &mut self,
+        ws_stream: WsStream,
+        joined: ServerMessage,
+        clipboard_rx: &mut mpsc::UnboundedReceiver<ClipboardItem>,
+        disconnect_rx: &mut mpsc::UnboundedReceiver<()>,
+    ) -> SessionEnd {
+        let (mut write, mut read) = ws_stream.split();
+        let mut pause_rx = self.pause_rx.clone();
+        self.heartbeat.reset(Instant::now());
+
+        // Emit connection status
+        self.emit_status(serde_json::json!({ "connected": true }));
+        match self.handle_message(&mut write, joined).await {
+            Ok(ControlFlow::Continue(())) => {}
+            Ok(ControlFlow::Break(end)) => return end,
+            Err(e) => {
+                tracing::error!("Failed to handle join: {}", e);
+                return SessionEnd::Lost;
+            }
+        }
+        self.apply_queued().await;
+
+        if let Err(e) = self.resume_transfers(&mut write).await {
+            tracing::error!("Failed to resume transfers: {}", e);
+            return SessionEnd::Lost;
+        }
+
+        // Send clips that were never acknowledged, then those captured while
+        // offline; the server ignores any it already stored
+        self.requeue_unacked();
+        if self.retry_at.is_none() {
+            if let Err(e) = self.flush_pending(&mut write).await {
+                tracing::error!("Failed to send queued clip: {}", e);
+                return SessionEnd::Lost;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                msg = read.next() => {
+                    if let Some(Ok(_)) = msg {
+                        self.heartbeat.heard(Instant::now());
+                    }
+                    let server_msg = match msg {
+                        Some(Ok(Message::Text(text))) => ServerMessage::from_json(&text).ok(),
+                        Some(Ok(Message::Binary(bytes))) => ServerMessage::from_msgpack(&bytes).ok(),
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => None,
+                    };
+                    if let Some(server_msg) = server_msg {
+                        match self.handle_message(&mut write, server_msg).await {
+                            Ok(ControlFlow::Continue(())) => {}
+                            Ok(ControlFlow::Break(end)) => return end,
+                            Err(e) => {
                                 tracing::error!("Failed to send message: {}", e);
                                 break;
                             }
                         }
                     }
-                    Some(_) = disconnect_rx.recv() => {
-                        tracing::info!("Disconnecting...");
-                        let _ = write.close().await;
+                }
+                // Upload chunks one at a time so incoming messages are never held up
+                _ = std::future::ready(()), if self.upload.as_ref().is_some_and(Upload::has_work) => {
+                    if let Err(e) = self.send_next_chunk(&mut write).await {
+                        tracing::error!("Failed to send upload chunk: {}", e);
+                        break;
+                    }
+                }
+                Some(item) = clipboard_rx.recv() => {
+                    let clip = OutgoingClip::new(item);
+                    if self.retry_at.is_some() {
+                        self.queue(clip);
+                    } else if let Err(e) = self.send_clip(&mut write, &clip).await {
+                        tracing::error!("Failed to send message: {}", e);
+                        self.queue(clip);
                         break;
                     }
                 }
+                _ = tokio::time::sleep_until(self.retry_at.unwrap_or_else(Instant::now)), if self.retry_at.is_some() => {
+                    self.retry_at = None;
+                    if let Err(e) = self.flush_pending(&mut write).await {
+                        tracing::error!("Failed to send queued clip: {}", e);
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep_until(self.heartbeat.next_ping().unwrap_or_else(Instant::now)), if self.heartbeat.next_ping().is_some() => {
+                    if let Err(e) = send_message(&mut write, &ClientMessage::Ping, self.codec).await {
+                        tracing::error!("Failed to send ping: {}", e);
+                        break;
+                    }
+                    self.heartbeat.ping_sent(Instant::now());
+                }
+                _ = tokio::time::sleep_until(self.heartbeat.deadline().unwrap_or_else(Instant::now)), if self.heartbeat.deadline().is_some() => {
+                    tracing::warn!("No answer from the server, reconnecting");
+                    break;
+                }
+                Ok(()) = pause_rx.changed() => self.apply_queued().await,
+                _ = disconnect_rx.recv() => {
+                    tracing::info!("Disconnecting...");
+                    let _ = write.close().await;
+                    self.emit_status(serde_json::json!({ "connected": false }));
+                    return SessionEnd::Disconnected;
+                }
             }
-        });
+        }
 
-        // Spawn clipboard monitor task
-        let is_paused_arc = Arc::new(RwLock::new(false));
-        let is_paused_clone = is_paused_arc.clone();
+        tracing::warn!("Connection to server lost");
+        SessionEnd::Lost
+    }
 
-        tokio::spawn(async move {
-            let mut last_content: Option<ClipboardItem> = None;
+    async fn handle_message(
+        &mut self,
+        write: &mut WsSink,
+        server_msg: ServerMessage,
+    ) -> anyhow::Result<ControlFlow<SessionEnd>> {
+        match server_msg {
+            ServerMessage::Joined {
+                history,
+                codec,
+                capabilities,
+                devices,
+            } => {
+                tracing::info!(
+                    "Joined room (protocol version {}) using {:?}, received {} history items",
+                    capabilities.protocol_version,
+                    codec,
+                    history.len()
+                );
+                self.codec = codec;
+                self.max_item_size = capabilities.max_item_size;
+                self.update_devices(|known| *known = devices);
+                if let Some(latest) = history.last() {
+                    self.last_seq = Some(latest.seq);
+                }
+                let history: Vec<ClipboardEntry> = history
+                    .into_iter()
+                    .filter_map(|entry| open_entry(&self.key, entry))
+                    .collect();
 
-            loop {
-                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                // Emit history to frontend
+                let _ = self.app.emit(
+                    "history-loaded",
+                    serde_json::json!({
+                        "history": history
+                    }),
+                );
+            }
+            ServerMessage::ClipReceived { entry } => {
+                tracing::debug!("Received clipboard from another device");
+                self.last_seq = Some(entry.seq);
+                if let Some(hash) = entry.item.reference_hash() {
+                    // Too large to travel inline; fetch it in chunks. A newer
+                    // clip supersedes any download still in progress.
+                    tracing::debug!("Fetching large clip {}", hash);
+                    let fetch = ClientMessage::FetchBlob {
+                        hash: hash.to_string(),
+                        from_index: 0,
+                    };
+                    self.download = Some(Download {
+                        entry,
+                        chunks: None,
+                    });
+                    send_message(write, &fetch, self.codec).await?;
+                } else {
+                    self.receive_clip(entry).await;
+                }
+            }
+            ServerMessage::BlobChunk {
+                hash,
+                index,
+                total,
+                data,
+            } => self.receive_chunk(&hash, index, total, &data).await,
+            ServerMessage::UploadProgress {
+                hash,
+                received,
+                total,
+            } => self.emit_progress("upload", &hash, received, total),
+            ServerMessage::UploadStatus { hash, missing } => {
+                if let Some(upload) = self.upload.as_mut().filter(|u| u.hash == hash) {
+                    tracing::debug!("Resuming upload with {} missing chunk(s)", missing.len());
+                    upload.to_send = missing.into();
+                    upload.clip_sent = false;
+                }
+            }
+            ServerMessage::Error {
+                code,
+                message,
+                fatal,
+            } => {
+                tracing::error!("Server error ({:?}): {}", code, message);
+                if fatal {
+                    self.emit_status(serde_json::json!({
+                        "connected": false,
+                        "error": message
+                    }));
+                    return Ok(ControlFlow::Break(SessionEnd::Rejected));
+                }
 
-                // Check if paused
-                if *is_paused_clone.read().await {
-                    continue;
+                match code {
+                    // The server lost track of us or ended the session;
+                    // joining again fixes that
+                    ErrorCode::NotJoined | ErrorCode::Disconnected => {
+                        return Ok(ControlFlow::Break(SessionEnd::Lost))
+                    }
+                    ErrorCode::RateLimited => {
+                        self.requeue_unacked();
+                        self.retry_at = Some(Instant::now() + RATE_LIMIT_RETRY_DELAY);
+                    }
+                    ErrorCode::TransferFailed => self.upload = None,
+                    ErrorCode::NotFound => self.download = None,
+                    // Sending the same clip again would fail the same way. The
+                    // server answers in order, so it refused the oldest one.
+                    ErrorCode::PayloadTooLarge | ErrorCode::InvalidMessage => {
+                        self.unacked.pop_front();
+                    }
+                    _ => {}
                 }
+                self.emit_status(serde_json::json!({
+                    "connected": true,
+                    "error": message
+                }));
+            }
+            ServerMessage::ServerShuttingDown { reconnect_after_ms } => {
+                let after = Duration::from_millis(reconnect_after_ms);
+                tracing::info!(
+                    "Server is shutting down, asked to reconnect after {:?}",
+                    after
+                );
+                return Ok(ControlFlow::Break(SessionEnd::ServerShuttingDown(after)));
+            }
+            ServerMessage::DeviceJoined { device } => {
+                tracing::info!("Device {} connected", device.label());
+                self.update_devices(|known| {
+                    known.retain(|d| d.device_id != device.device_id);
+                    known.push(device);
+                });
+            }
+            ServerMessage::DeviceLeft { device_id } => {
+                tracing::info!("Device {} disconnected", device_id);
+                self.update_devices(|known| known.retain(|d| d.device_id != device_id));
+            }
+            ServerMessage::Pong => {
+                if let Some(latency) = self.heartbeat.pong(Instant::now()) {
+                    tracing::trace!("Server answered ping in {:?}", latency);
+                    let _ = self.app.emit(
+                        "connection-latency",
+                        serde_json::json!({ "latency_ms": latency.as_millis() as u64 }),
+                    );
+                }
+            }
+            ServerMessage::Ack { id } => {
+                tracing::trace!("Clip acknowledged: {}", id);
+                if self.upload.as_ref().is_some_and(|u| u.id == id) {
+                    self.upload = None;
+                }
+                self.unacked.retain(|clip| clip.id != id);
+            }
+            _ => {}
+        }
+        Ok(ControlFlow::Continue(()))
+    }
 
-                // Read clipboard
-                let mut clipboard = clipboard_manager_arc.write().await;
-                if let Ok(Some(content)) = clipboard.read() {
-                    // Check if content changed
-                    if last_content.as_ref() != Some(&content) {
-                        tracing::debug!("Clipboard changed locally");
-                        last_content = Some(content.clone());
+    /// Decrypt a received clip and apply it according to the pause policy
+    async fn receive_clip(&mut self, entry: ClipboardEntry) {
+        let Some(entry) = open_entry(&self.key, entry) else {
+            return;
+        };
 
-                        // Send to WebSocket
-                        let _ = clipboard_tx.send(content);
-                    }
+        let pause = *self.pause_rx.borrow();
+        match (pause.paused, pause.inbound) {
+            (true, InboundPausePolicy::Drop) => {
+                tracing::debug!("Sync paused, dropping received clip");
+                return;
+            }
+            (true, InboundPausePolicy::Queue) => {
+                tracing::debug!("Sync paused, queueing received clip");
+                self.queued_inbound = Some(entry.item.clone());
+            }
+            _ => self.apply_remote(&entry.item).await,
+        }
+
+        // Emit to frontend
+        let _ = self.app.emit(
+            "clipboard-received",
+            serde_json::json!({
+                "item": entry.item,
+                "timestamp": entry.timestamp,
+                "device_id": entry.device_id,
+                "device_name": entry.device_name,
+                "device_platform": entry.device_platform
+            }),
+        );
+    }
+
+    /// Add a chunk to the current download, handling the clip once it is complete
+    async fn receive_chunk(&mut self, hash: &str, index: u32, total: u32, data: &[u8]) {
+        let Some(download) = self.download.as_mut().filter(|d| d.hash() == hash) else {
+            return;
+        };
+
+        let chunks = match download.chunks.as_mut() {
+            Some(chunks) => chunks,
+            None => match ChunkAssembler::new(hash, total) {
+                Ok(chunks) => download.chunks.insert(chunks),
+                Err(e) => {
+                    tracing::warn!("Invalid download {}: {}", hash, e);
+                    self.download = None;
+                    return;
                 }
+            },
+        };
+        if let Err(e) = chunks.insert(index, total, data) {
+            tracing::warn!("Dropping download {}: {}", hash, e);
+            self.download = None;
+            return;
+        }
+
+        let received = chunks.received();
+        self.emit_progress("download", hash, received, total);
+        if received < total {
+            return;
+        }
+
+        let Some(Download {
+            mut entry,
+            chunks: Some(chunks),
+        }) = self.download.take()
+        else {
+            return;
+        };
+        let sealed = chunks
+            .finish()
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(from_msgpack::<ClipboardItem>(&bytes)?));
+        match sealed {
+            Ok(sealed) => {
+                entry.item = sealed;
+                self.receive_clip(entry).await;
             }
-        });
+            Err(e) => tracing::warn!("Dropping download {}: {}", hash, e),
+        }
+    }
 
+    /// Pick up transfers interrupted by a dropped connection
+    async fn resume_transfers(&mut self, write: &mut WsSink) -> anyhow::Result<()> {
+        if let Some(upload) = self.upload.as_mut() {
+            // Hold off until the server says which chunks it still needs
+            upload.to_send.clear();
+            upload.clip_sent = true;
+            let query = ClientMessage::QueryUpload {
+                hash: upload.hash.clone(),
+                total: upload.chunks.len() as u32,
+            };
+            send_message(write, &query, self.codec).await?;
+        }
+
+        if let Some(download) = &self.download {
+            let fetch = ClientMessage::FetchBlob {
+                hash: download.hash().to_string(),
+                from_index: download.resume_index(),
+            };
+            send_message(write, &fetch, self.codec).await?;
+        }
         Ok(())
     }
 
-    pub async fn disconnect(&mut self) -> anyhow::Result<()> {
-        if !self.is_connected {
-            return Err(anyhow::anyhow!("Not connected"));
+    /// Send the next chunk (or the final clip) of the current upload
+    async fn send_next_chunk(&mut self, write: &mut WsSink) -> anyhow::Result<()> {
+        if let Some(msg) = self.upload.as_mut().and_then(Upload::next_message) {
+            send_message(write, &msg, self.codec).await?;
         }
+        Ok(())
+    }
 
-        if let Some(tx) = self.disconnect_tx.take() {
-            let _ = tx.send(());
+    fn emit_progress(&self, direction: &str, hash: &str, done: u32, total: u32) {
+        let _ = self.app.emit(
+            "transfer-progress",
+            serde_json::json!({
+                "direction": direction,
+                "hash": hash,
+                "done": done,
+                "total": total
+            }),
+        );
+    }
+
+    /// Write a received clip to the local clipboard
+    async fn apply_remote(&self, item: &ClipboardItem) {
+        // Remember what we wrote so the monitor does not send it straight back
+        let mut clipboard = self.clipboard.write().await;
+        let mut echo_guard = self.echo_guard.write().await;
+        echo_guard.record_remote(item.content_hash());
+        if let Err(e) = clipboard.write(item) {
+            tracing::error!("Failed to write to clipboard: {}", e);
+        }
+
+        // The platform may hand back slightly different bytes (e.g. a
+        // re-encoded image), so remember what actually landed as well
+        if let Ok(Some(applied)) = clipboard.read() {
+            echo_guard.record_remote(applied.content_hash());
+        }
+    }
+
+    /// Apply the clip queued during a pause, once sync has resumed
+    async fn apply_queued(&mut self) {
+        if self.pause_rx.borrow().paused {
+            return;
+        }
+        if let Some(item) = self.queued_inbound.take() {
+            tracing::debug!("Sync resumed, applying queued clip");
+            self.apply_remote(&item).await;
         }
+    }
+
+    /// Encrypt and send a local clip; large clips are uploaded in chunks
+    async fn send_clip(&mut self, write: &mut WsSink, clip: &OutgoingClip) -> anyhow::Result<()> {
+        let item = &clip.item;
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let sealed = match encrypt_item(&self.key, item) {
+            Ok(sealed) => sealed,
+            Err(e) => {
+                tracing::error!("Failed to encrypt clipboard item: {}", e);
+                return Ok(());
+            }
+        };
 
-        self.is_connected = false;
-        self.clipboard_manager = None;
+        let content_hash = Some(self.key.content_hash(item));
+        let format = item.format();
+        // Small enough to travel inline next to the item for devices that need it
+        let fallback = item
+            .fallback()
+            .and_then(|fallback| encrypt_item(&self.key, &fallback).ok())
+            .filter(|sealed| sealed.size() <= INLINE_ITEM_LIMIT);
+        let new_clip = |item| ClientMessage::NewClip {
+            id: clip.id.clone(),
+            item,
+            content_hash: content_hash.clone(),
+            format,
+            fallback: fallback.clone(),
+            timestamp,
+        };
+
+        let payload = to_msgpack(&sealed)?;
+        let size = payload.len() + fallback.as_ref().map_or(0, ClipboardItem::size);
+        if let Some(max) = self.max_item_size.filter(|&max| size as u64 > max) {
+            tracing::warn!(
+                "Not sending clip of {} bytes, server limit is {}",
+                size,
+                max
+            );
+            self.emit_status(serde_json::json!({
+                "connected": true,
+                "error": format!(
+                    "Clip not synced: it is {} MB, the server accepts up to {} MB",
+                    size.div_ceil(1024 * 1024),
+                    max / (1024 * 1024)
+                )
+            }));
+            return Ok(());
+        }
+        if payload.len() <= INLINE_ITEM_LIMIT {
+            send_message(write, &new_clip(sealed), self.codec).await?;
+            if self.unacked.len() >= MAX_PENDING_CLIPS {
+                self.unacked.pop_front();
+            }
+            self.unacked.push_back(clip.clone());
+            return Ok(());
+        }
 
+        // The newest copy wins over an upload still in progress
+        let hash = blob_hash(&payload);
+        let chunks = split_chunks(&payload);
+        tracing::debug!("Uploading large clip {} in {} chunks", hash, chunks.len());
+        let reference = ClipboardItem::Reference {
+            hash: hash.clone(),
+            size: payload.len() as u64,
+        };
+        self.upload = Some(Upload {
+            to_send: (0..chunks.len() as u32).collect(),
+            clip: new_clip(reference),
+            clip_sent: false,
+            hash,
+            chunks,
+            id: clip.id.clone(),
+        });
         Ok(())
     }
 
-    pub fn set_paused(&mut self, paused: bool) {
-        self.is_paused = paused;
+    /// Queue a clip for sending once the connection is back, dropping the oldest if full
+    fn queue(&mut self, clip: OutgoingClip) {
+        if self.pending.len() >= MAX_PENDING_CLIPS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(clip);
     }
 
-    #[allow(dead_code)]
-    pub fn is_connected(&self) -> bool {
-        self.is_connected
+    /// Put clips the server has not acknowledged back in front of the queue
+    fn requeue_unacked(&mut self) {
+        while let Some(clip) = self.unacked.pop_back() {
+            self.pending.push_front(clip);
+        }
+    }
+
+    /// Send clips queued while offline or rate limited, oldest first
+    async fn flush_pending(&mut self, write: &mut WsSink) -> anyhow::Result<()> {
+        while let Some(clip) = self.pending.pop_front() {
+            if let Err(e) = self.send_clip(write, &clip).await {
+                self.pending.push_front(clip);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_status(&self, status: serde_json::Value) {
+        let _ = self.app.emit("connection-status", status);
+    }
+
+    /// Change the list of other devices in the room and tell the UI and tray
+    fn update_devices(&self, change: impl FnOnce(&mut Vec<DeviceInfo>)) {
+        let devices = {
+            let mut devices = self.devices.lock().unwrap();
+            change(&mut devices);
+            devices.sort_by(|a, b| a.label().cmp(b.label()));
+            devices.clone()
+        };
+        let _ = self
+            .app
+            .emit("devices-changed", serde_json::json!({ "devices": devices }));
+    }
+}
+
+/// Watch the local clipboard and forward local changes to the connection
+async fn monitor_clipboard(
+    settings: MonitorSettings,
+    app: tauri::AppHandle,
+    clipboard: Arc<RwLock<ClipboardManager>>,
+    echo_guard: Arc<RwLock<EchoGuard>>,
+    mut pause_rx: watch::Receiver<PauseState>,
+    clipboard_tx: mpsc::UnboundedSender<ClipboardItem>,
+) {
+    let mut changes = match settings.mode {
+        ClipboardWatchMode::Events => watcher::spawn_change_watcher(),
+        ClipboardWatchMode::Poll => None,
+    };
+    if changes.is_none() {
+        tracing::info!("Polling clipboard every {:?}", settings.poll_interval);
+    }
+
+    let mut poll = tokio::time::interval(settings.poll_interval);
+    poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let event = tokio::select! {
+            // Stop once the supervisor has shut down
+            _ = clipboard_tx.closed() => break,
+            Ok(()) = pause_rx.changed() => None,
+            alive = wait_for_change(&mut changes, &mut poll) => Some(alive),
+        };
+
+        match event {
+            None => {
+                // Whatever was copied during the pause stays local, so take a
+                // fresh baseline as soon as sync resumes
+                if !pause_rx.borrow_and_update().paused {
+                    let _ = clipboard.write().await.read_if_changed();
+                }
+                continue;
+            }
+            Some(false) => {
+                tracing::warn!("Clipboard watcher stopped, falling back to polling");
+                changes = None;
+                continue;
+            }
+            Some(true) => {}
+        }
+
+        // Coalesce bursts of notifications into a single read
+        if let Some(rx) = changes.as_mut() {
+            while rx.try_recv().is_ok() {}
+        }
+
+        // Check if paused
+        if pause_rx.borrow().paused {
+            continue;
+        }
+
+        // Read clipboard
+        let content = match clipboard.write().await.read_if_changed() {
+            Ok(Some(content)) => content,
+            Ok(None) => continue,
+            Err(e) => {
+                if let Some(TooLarge { total, max }) = e.downcast_ref::<TooLarge>() {
+                    tracing::warn!("{}; not syncing", e);
+                    let _ = app.emit(
+                        "clip-not-synced",
+                        serde_json::json!({
+                            "error": format!(
+                                "Files not synced: they total {} MB, the limit is {} MB",
+                                total.div_ceil(1024 * 1024),
+                                max / (1024 * 1024)
+                            )
+                        }),
+                    );
+                } else {
+                    tracing::warn!("Failed to read clipboard: {}", e);
+                }
+                continue;
+            }
+        };
+
+        // Skip changes we made ourselves for a received clip
+        if echo_guard
+            .write()
+            .await
+            .take_remote(&content.content_hash())
+        {
+            tracing::debug!("Ignoring clipboard change applied from remote");
+            continue;
+        }
+
+        tracing::debug!("Clipboard changed locally");
+
+        // Send to WebSocket
+        let _ = clipboard_tx.send(content);
+    }
+}
+
+/// Wait for the next change notification, or the next poll tick without a
+/// watcher. Returns `false` if the watcher went away.
+async fn wait_for_change(
+    changes: &mut Option<mpsc::UnboundedReceiver<()>>,
+    poll: &mut tokio::time::Interval,
+) -> bool {
+    match changes {
+        Some(rx) => rx.recv().await.is_some(),
+        None => {
+            poll.tick().await;
+            true
+        }
+    }
+}
+
+async fn send_message(write: &mut WsSink, msg: &ClientMessage, codec: Codec) -> anyhow::Result<()> {
+    let frame = match msg.encode(codec)? {
+        Frame::Text(text) => Message::Text(text),
+        Frame::Binary(bytes) => Message::Binary(bytes),
+    };
+    write.send(frame).await?;
+    Ok(())
+}
+
+/// The server answered `Join` with an error
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+struct JoinRefused {
+    code: ErrorCode,
+    message: String,
+    /// Whether joining again would be refused the same way
+    fatal: bool,
+}
+
+/// The server's refusal, if `error` is one that reconnecting will not fix
+fn fatal_refusal(error: &anyhow::Error) -> Option<&JoinRefused> {
+    error
+        .downcast_ref::<JoinRefused>()
+        .filter(|refused| refused.fatal)
+}
+
+/// Open a WebSocket to the server, join the room and wait for the server's
+/// `Joined` reply, failing if the server refuses this client
+async fn open_session(
+    url: &str,
+    join: &ClientMessage,
+    tls: Arc<ClientConfig>,
+    deflate: bool,
+) -> anyhow::Result<(WsStream, ServerMessage)> {
+    let mut ws_stream = transport::connect(url, tls, deflate).await?;
+    ws_stream.send(Message::Text(join.to_json()?)).await?;
+
+    while let Some(msg) = ws_stream.next().await {
+        let reply = match msg? {
+            Message::Text(text) => ServerMessage::from_json(&text)?,
+            Message::Binary(bytes) => ServerMessage::from_msgpack(&bytes)?,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        match reply {
+            ServerMessage::Joined {
+                ref capabilities, ..
+            } if capabilities.protocol_version < MIN_PROTOCOL_VERSION => {
+                anyhow::bail!(
+                    "Server speaks protocol version {}, this app needs at least {}",
+                    capabilities.protocol_version,
+                    MIN_PROTOCOL_VERSION
+                );
+            }
+            ServerMessage::Joined { .. } => return Ok((ws_stream, reply)),
+            ServerMessage::Error {
+                code,
+                message,
+                fatal,
+            } => {
+                return Err(JoinRefused {
+                    code,
+                    message,
+                    fatal,
+                }
+                .into())
+            }
+            _ => {}
+        }
+    }
+    anyhow::bail!("Connection closed before joining the room")
+}
+
+/// Exponential reconnect backoff with jitter
+struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Number of delays handed out since the last reset
+    fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Delay before the next attempt, randomized within the upper half of the
+    /// exponential window so devices that lost the same server spread out
+    fn next_delay(&mut self) -> Duration {
+        let window = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = window / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Decrypt a history entry received from the server, dropping it if it cannot be opened.
+///
+/// References to large items are passed through for the UI to show.
+fn open_entry(key: &EncryptionKey, mut entry: ClipboardEntry) -> Option<ClipboardEntry> {
+    if entry.item.reference_hash().is_some() {
+        return Some(entry);
+    }
+
+    match decrypt_item(key, &entry.item) {
+        Ok(item) => {
+            entry.item = item;
+            Some(entry)
+        }
+        Err(e) => {
+            tracing::warn!("Dropping clipboard item that could not be decrypted: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Serve one WebSocket connection that answers `Join` with `reply`
+    async fn answering_server(reply: ServerMessage) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws.next().await;
+            ws.send(Message::Text(reply.to_json().unwrap()))
+                .await
+                .unwrap();
+        });
+        url
+    }
+
+    async fn rejoin(reply: ServerMessage) -> anyhow::Error {
+        let url = answering_server(reply).await;
+        let join = ClientMessage::join(
+            hash_secret("secret"),
+            DeviceInfo {
+                device_id: new_device_id(),
+                name: None,
+                platform: None,
+            },
+        );
+        let tls = TlsOptions::default().client_config().unwrap();
+        match open_session(&url, &join, tls, false).await {
+            Ok(_) => panic!("the server refused the join"),
+            Err(e) => e,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fatal_join_refusal_stops_reconnecting() {
+        let error = rejoin(ServerMessage::error(
+            ErrorCode::Unauthorized,
+            "This room is not allowed",
+        ))
+        .await;
+        let refused = fatal_refusal(&error).unwrap();
+        assert_eq!(refused.code, ErrorCode::Unauthorized);
+        assert_eq!(error.to_string(), "This room is not allowed");
+
+        let error = rejoin(ServerMessage::error(ErrorCode::RoomFull, "Room is full")).await;
+        assert!(fatal_refusal(&error).is_some());
+
+        let error = rejoin(ServerMessage::error(ErrorCode::RateLimited, "Slow down")).await;
+        assert!(fatal_refusal(&error).is_none());
+    }
+
+    #[test]
+    fn test_backoff_grows_up_to_the_cap() {
+        let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
+        for attempt in 0..20 {
+            assert_eq!(backoff.attempt(), attempt);
+            let window = (RECONNECT_BASE_DELAY * 2u32.pow(attempt)).min(RECONNECT_MAX_DELAY);
+            let delay = backoff.next_delay();
+            assert!(delay >= window / 2 && delay <= window, "{:?}", delay);
+        }
+
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= RECONNECT_MAX_DELAY);
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_jittered() {
+        let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
+        let delays: std::collections::HashSet<_> = (0..20)
+            .map(|_| {
+                backoff.reset();
+                backoff.next_delay()
+            })
+            .collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn test_backoff_starts_over_after_reset() {
+        let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        let delay = backoff.next_delay();
+        assert!(delay >= RECONNECT_BASE_DELAY / 2 && delay <= RECONNECT_BASE_DELAY);
     }
 }
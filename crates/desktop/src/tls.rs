@@ -0,0 +1,237 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config::Config;
+
+/// TLS settings for `wss://` connections to self-hosted servers
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// PEM bundle of CA certificates to trust instead of the built-in roots
+    pub ca_bundle: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate, in hex
+    pub pinned_sha256: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            ca_bundle: config.tls_ca_bundle.clone(),
+            pinned_sha256: config.tls_pinned_sha256.clone(),
+        }
+    }
+
+    /// Build the client configuration for these settings.
+    ///
+    /// When nothing is customised, the bundled web PKI roots are trusted.
+    pub fn client_config(&self) -> anyhow::Result<Arc<ClientConfig>> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        if self.ca_bundle.is_none() && self.pinned_sha256.is_none() {
+            let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            return Ok(Arc::new(
+                builder.with_root_certificates(roots).with_no_client_auth(),
+            ));
+        }
+
+        // Chain validation against the custom bundle, if one was given
+        let chain_verifier = match &self.ca_bundle {
+            Some(path) => Some(
+                WebPkiServerVerifier::builder_with_provider(
+                    Arc::new(load_ca_bundle(path)?),
+                    provider.clone(),
+                )
+                .build()?,
+            ),
+            None => None,
+        };
+
+        let verifier: Arc<dyn ServerCertVerifier> = match &self.pinned_sha256 {
+            Some(pin) => Arc::new(PinnedCertVerifier {
+                pin: parse_fingerprint(pin)?,
+                chain_verifier,
+                provider: provider.clone(),
+            }),
+            None => chain_verifier.expect("a CA bundle is configured when there is no pin"),
+        };
+
+        let config = builder
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+
+        Ok(Arc::new(config))
+    }
+}
+
+/// Read every certificate in a PEM bundle into a root store
+fn load_ca_bundle(path: &Path) -> anyhow::Result<RootCertStore> {
+    let pem = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read CA bundle {}: {}", path.display(), e))?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+        roots.add(cert?)?;
+    }
+
+    if roots.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No certificates found in CA bundle {}",
+            path.display()
+        ));
+    }
+    Ok(roots)
+}
+
+/// Parse a SHA-256 fingerprint given as hex, with or without `:` separators
+fn parse_fingerprint(pin: &str) -> anyhow::Result<[u8; 32]> {
+    let hex: String = pin
+        .trim()
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect();
+
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(anyhow::anyhow!(
+            "Pinned certificate fingerprint must be 32 hex-encoded bytes"
+        ));
+    }
+
+    let mut fingerprint = [0u8; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow::anyhow!("Invalid hex in pinned certificate fingerprint"))?;
+    }
+    Ok(fingerprint)
+}
+
+/// Accepts the server only if its leaf certificate matches the pinned fingerprint.
+///
+/// Without a CA bundle the pin replaces chain and hostname validation, which is
+/// what makes self-signed certificates usable. With a bundle, both must pass.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    pin: [u8; 32],
+    chain_verifier: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain_verifier) = &self.chain_verifier {
+            chain_verifier.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        if Sha256::digest(end_entity.as_ref()).as_slice() != self.pin {
+            tracing::warn!("Server certificate does not match the pinned fingerprint");
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinned(cert: &[u8]) -> PinnedCertVerifier {
+        PinnedCertVerifier {
+            pin: Sha256::digest(cert).into(),
+            chain_verifier: None,
+            provider: Arc::new(rustls::crypto::aws_lc_rs::default_provider()),
+        }
+    }
+
+    #[test]
+    fn test_parse_fingerprint_formats() {
+        let plain = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let colons = "00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:\
+                      00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF";
+
+        let expected = parse_fingerprint(plain).unwrap();
+        assert_eq!(expected[0], 0x00);
+        assert_eq!(expected[15], 0xff);
+        assert_eq!(parse_fingerprint(colons).unwrap(), expected);
+
+        assert!(parse_fingerprint("abcd").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_pinned_verifier_checks_leaf_fingerprint() {
+        let verifier = pinned(b"trusted certificate");
+        let name = ServerName::try_from("clipsync.local").unwrap();
+
+        let trusted = CertificateDer::from(b"trusted certificate".to_vec());
+        assert!(verifier
+            .verify_server_cert(&trusted, &[], &name, &[], UnixTime::now())
+            .is_ok());
+
+        let other = CertificateDer::from(b"some other certificate".to_vec());
+        assert!(verifier
+            .verify_server_cert(&other, &[], &name, &[], UnixTime::now())
+            .is_err());
+    }
+
+    #[test]
+    fn test_no_options_trusts_web_pki_roots() {
+        assert!(TlsOptions::default().client_config().is_ok());
+    }
+}
@@ -0,0 +1,57 @@
+use clipsync_common::{DeflateStream, DEFLATE_EXTENSION};
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_EXTENSIONS;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::{client_async, MaybeTlsStream, WebSocketStream};
+
+/// A WebSocket to the server, compressed if permessage-deflate was negotiated
+pub type WsStream = WebSocketStream<DeflateStream<MaybeTlsStream<TcpStream>>>;
+
+/// Open a WebSocket to `url`, over TLS with `tls` for `wss://` URLs.
+///
+/// tokio-tungstenite does not implement permessage-deflate, so with
+/// `deflate` set the extension is offered here and handled by a
+/// [`DeflateStream`] between TLS and the WebSocket.
+pub async fn connect(url: &str, tls: Arc<ClientConfig>, deflate: bool) -> anyhow::Result<WsStream> {
+    let mut request = url.into_client_request()?;
+    if deflate {
+        request.headers_mut().insert(
+            SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_static(DEFLATE_EXTENSION),
+        );
+    }
+
+    let uri = request.uri();
+    let secure = match uri.scheme_str() {
+        Some("wss") => true,
+        Some("ws") => false,
+        _ => anyhow::bail!("Server URL must start with ws:// or wss://"),
+    };
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("Server URL has no host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+    let tcp = TcpStream::connect((host.as_str(), port)).await?;
+    let stream = if secure {
+        let server_name = ServerName::try_from(host)?;
+        let tls_stream = TlsConnector::from(tls).connect(server_name, tcp).await?;
+        MaybeTlsStream::Rustls(tls_stream)
+    } else {
+        MaybeTlsStream::Plain(tcp)
+    };
+
+    let (ws_stream, _) = client_async(request, DeflateStream::client(stream)).await?;
+    if ws_stream.get_ref().is_deflating() {
+        tracing::debug!("Server accepted permessage-deflate");
+    }
+    Ok(ws_stream)
+}
@@ -0,0 +1,60 @@
+use clipboard_master::{CallbackResult, ClipboardHandler, Master};
+use std::io;
+use tokio::sync::mpsc;
+
+/// Forwards clipboard change notifications into a channel
+struct ChangeForwarder {
+    tx: mpsc::UnboundedSender<()>,
+}
+
+impl ClipboardHandler for ChangeForwarder {
+    fn on_clipboard_change(&mut self) -> CallbackResult {
+        // Stop watching once the monitor has gone away
+        match self.tx.send(()) {
+            Ok(()) => CallbackResult::Next,
+            Err(_) => CallbackResult::Stop,
+        }
+    }
+
+    fn on_clipboard_error(&mut self, error: io::Error) -> CallbackResult {
+        tracing::warn!("Clipboard watcher error: {}", error);
+        CallbackResult::Next
+    }
+}
+
+/// Start a platform clipboard watcher (XFixes selection events on X11, the
+/// native change notifications elsewhere) on a background thread.
+///
+/// The returned channel yields one message per change and closes if the
+/// watcher stops, in which case the caller should fall back to polling.
+pub fn spawn_change_watcher() -> Option<mpsc::UnboundedReceiver<()>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+
+    let spawned = std::thread::Builder::new()
+        .name("clipboard-watcher".into())
+        .spawn(move || {
+            let mut master = match Master::new(ChangeForwarder { tx }) {
+                Ok(master) => {
+                    let _ = ready_tx.send(true);
+                    master
+                }
+                Err(e) => {
+                    tracing::warn!("Clipboard change notifications unavailable: {}", e);
+                    let _ = ready_tx.send(false);
+                    return;
+                }
+            };
+
+            if let Err(e) = master.run() {
+                tracing::warn!("Clipboard watcher stopped: {}", e);
+            }
+        });
+
+    if let Err(e) = spawned {
+        tracing::warn!("Failed to start clipboard watcher thread: {}", e);
+        return None;
+    }
+
+    ready_rx.recv().unwrap_or(false).then_some(rx)
+}
@@ -0,0 +1,202 @@
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router,
+};
+use std::sync::Arc;
+
+use crate::config::LimitSettings;
+use crate::room::{DeviceSummary, LookupError, RoomSummary};
+use crate::state::AppState;
+
+/// Routes of the admin API, to be nested under `/admin`. Every request must
+/// carry `Authorization: Bearer <token>`.
+pub fn router(token: String) -> Router<AppState> {
+    Router::new()
+        .route("/rooms", get(list_rooms))
+        .route("/rooms/:room/devices", get(list_devices))
+        .route("/rooms/:room/devices/:device", delete(disconnect_device))
+        .route("/rooms/:room/history", delete(purge_history))
+        .route("/limits", get(get_limits).patch(update_limits))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            require_token,
+        ))
+}
+
+/// Errors returned by the admin API, as JSON with a matching status code
+#[derive(Debug)]
+enum AdminError {
+    Unauthorized,
+    NotFound(String),
+    Conflict(String),
+    BadRequest(String),
+}
+
+impl From<LookupError> for AdminError {
+    fn from(e: LookupError) -> Self {
+        match e {
+            LookupError::NotFound(_) => Self::NotFound(e.to_string()),
+            LookupError::Ambiguous(_) => Self::Conflict(e.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            Self::Unauthorized => (StatusCode::UNAUTHORIZED, "invalid admin token".to_string()),
+            Self::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            Self::Conflict(message) => (StatusCode::CONFLICT, message),
+            Self::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+/// Compare tokens without returning early on the first differing byte, so
+/// response times do not reveal how much of a guess was right
+fn token_matches(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn require_token(
+    State(token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AdminError> {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !token_matches(token.as_bytes(), given.as_bytes()) {
+        tracing::warn!("Rejected admin request to {}", request.uri().path());
+        return Err(AdminError::Unauthorized);
+    }
+    Ok(next.run(request).await)
+}
+
+async fn list_rooms(State(state): State<AppState>) -> Json<Vec<RoomSummary>> {
+    Json(state.rooms.list_rooms().await)
+}
+
+async fn list_devices(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+) -> Result<Json<Vec<DeviceSummary>>, AdminError> {
+    let room = state.rooms.find_room(&room).await?;
+    let devices = room.read().await.devices();
+    Ok(Json(devices))
+}
+
+async fn disconnect_device(
+    State(state): State<AppState>,
+    Path((room, device)): Path<(String, String)>,
+) -> Result<StatusCode, AdminError> {
+    let room = state.rooms.find_room(&room).await?;
+    if !room.read().await.disconnect_client(&device) {
+        return Err(AdminError::NotFound(format!(
+            "no device {} in room",
+            device
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn purge_history(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+) -> Result<Json<serde_json::Value>, AdminError> {
+    let room = state.rooms.find_room(&room).await?;
+    let mut room = room.write().await;
+    let purged = room.purge_history();
+    tracing::info!(
+        "Purged {} history entries from room {}",
+        purged,
+        room.short_hash()
+    );
+    Ok(Json(serde_json::json!({ "purged": purged })))
+}
+
+async fn get_limits(State(state): State<AppState>) -> Json<LimitSettings> {
+    Json(LimitSettings::current(
+        state.rooms.limits(),
+        state.limiter.limits(),
+    ))
+}
+
+/// Change limits at runtime. They are not saved, so a restart goes back to
+/// the configured ones.
+async fn update_limits(
+    State(state): State<AppState>,
+    Json(settings): Json<LimitSettings>,
+) -> Result<Json<LimitSettings>, AdminError> {
+    let mut limits = state.rooms.limits();
+    let mut rate = state.limiter.limits();
+    settings
+        .apply(&mut limits, &mut rate)
+        .map_err(AdminError::BadRequest)?;
+
+    state.rooms.set_limits(limits);
+    state.limiter.set_limits(rate);
+    tracing::info!("Limits changed: {:?}, {:?}", limits, rate);
+    Ok(Json(LimitSettings::current(limits, rate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::RateLimits;
+    use crate::room::Limits;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_must_match_exactly() {
+        assert!(token_matches(b"s3cret-token", b"s3cret-token"));
+        assert!(!token_matches(b"s3cret-token", b"s3cret-tokeN"));
+        assert!(!token_matches(b"s3cret-token", b"s3cret"));
+        assert!(!token_matches(b"s3cret-token", b""));
+    }
+
+    #[test]
+    fn test_limit_update_keeps_omitted_fields() {
+        let mut limits = Limits::default();
+        let mut rate = RateLimits::default();
+        let update: LimitSettings = serde_json::from_str(
+            r#"{"max_item_bytes": 1024, "join_timeout_secs": 3, "idle_timeout_secs": 30}"#,
+        )
+        .unwrap();
+        update.apply(&mut limits, &mut rate).unwrap();
+
+        assert_eq!(limits.max_item_size, 1024);
+        assert_eq!(limits.max_room_bytes, Limits::default().max_room_bytes);
+        assert_eq!(rate.join_timeout, Duration::from_secs(3));
+        assert_eq!(rate.idle_timeout, Duration::from_secs(30));
+        assert_eq!(
+            rate.messages_per_sec,
+            RateLimits::default().messages_per_sec
+        );
+    }
+
+    #[test]
+    fn test_invalid_limit_updates_are_refused() {
+        let mut limits = Limits::default();
+        let mut rate = RateLimits::default();
+        let update = LimitSettings {
+            rate_messages: Some(0),
+            ..LimitSettings::default()
+        };
+        assert!(update.apply(&mut limits, &mut rate).is_err());
+        assert!(serde_json::from_str::<LimitSettings>(r#"{"max_items": 1}"#).is_err());
+    }
+}
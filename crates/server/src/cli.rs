@@ -0,0 +1,21 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+use crate::config::Settings;
+
+/// Command line arguments. Settings can also be given through their
+/// environment variable or in the config file, in that order of precedence.
+#[derive(Debug, Parser)]
+#[command(name = "clipsync-server", version, about = "ClipSync WebSocket server")]
+pub struct Args {
+    /// TOML config file
+    #[arg(long, short, env = "CLIPSYNC_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Check the configuration, print the effective settings and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[command(flatten)]
+    pub settings: Settings,
+}
@@ -0,0 +1,503 @@
+use axum::http::HeaderValue;
+use clap::{Args, ValueEnum};
+use clipsync_common::is_secret_hash;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::ratelimit::RateLimits;
+use crate::room::Limits;
+use crate::store::HistoryBackend;
+
+/// Fill the fields of `$target` that are not set from `$fallback`
+macro_rules! or_fields {
+    ($target:expr, $fallback:expr; $($field:ident),+ $(,)?) => {
+        $(
+            if $target.$field.is_none() {
+                $target.$field = $fallback.$field;
+            }
+        )+
+    };
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+/// Server settings as given on the command line, in the environment or in
+/// the config file. Everything is optional here; [`Config::resolve`] fills in
+/// the defaults.
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Address to listen on [default: 0.0.0.0]; `::` also accepts IPv4
+    /// connections unless --ipv6-only is set
+    #[arg(long, env = "CLIPSYNC_BIND")]
+    pub bind: Option<IpAddr>,
+
+    /// Accept only IPv6 connections on an IPv6 bind address
+    #[arg(long, env = "CLIPSYNC_IPV6_ONLY", num_args = 0..=1, default_missing_value = "true")]
+    pub ipv6_only: Option<bool>,
+
+    /// Port to listen on [default: 8080]
+    #[arg(long, env = "CLIPSYNC_PORT")]
+    pub port: Option<u16>,
+
+    /// Serve a plain-HTTP /health endpoint on this port as well
+    #[arg(long, env = "CLIPSYNC_HEALTH_PORT")]
+    pub health_port: Option<u16>,
+
+    /// Format of log lines [default: text]
+    #[arg(long, env = "CLIPSYNC_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Only allow rooms with these hashes (hex SHA-256 of the shared secret),
+    /// comma separated
+    #[arg(
+        long = "allowed-room",
+        env = "CLIPSYNC_ALLOWED_ROOMS",
+        value_delimiter = ','
+    )]
+    pub allowed_rooms: Option<Vec<String>>,
+
+    /// Enable the admin API, requiring this bearer token
+    #[arg(long, env = "CLIPSYNC_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// Compress WebSocket messages with permessage-deflate for clients that
+    /// offer it [default: true]
+    #[arg(long, env = "CLIPSYNC_WS_DEFLATE", num_args = 0..=1, default_missing_value = "true")]
+    pub ws_deflate: Option<bool>,
+
+    #[command(flatten)]
+    pub history: HistorySettings,
+
+    #[command(flatten)]
+    pub limits: LimitSettings,
+
+    #[command(flatten)]
+    pub tls: TlsSettings,
+
+    #[command(flatten)]
+    pub cors: CorsSettings,
+
+    #[command(flatten)]
+    pub shutdown: ShutdownSettings,
+}
+
+/// Where history is kept and for how long
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistorySettings {
+    /// Where history is kept, `memory` or `sqlite` [default: memory]
+    #[arg(long = "history-backend", env = "CLIPSYNC_HISTORY_BACKEND")]
+    pub backend: Option<String>,
+
+    /// SQLite database file for the `sqlite` backend [default: clipsync.db]
+    #[arg(long, env = "CLIPSYNC_DB_PATH")]
+    pub db_path: Option<PathBuf>,
+
+    /// Entries kept per room [default: 50]
+    #[arg(long = "max-history", env = "CLIPSYNC_MAX_HISTORY")]
+    pub max_entries: Option<usize>,
+
+    /// Delete history older than this many seconds, 0 to keep it
+    /// [default: 604800]
+    #[arg(
+        long = "history-retention-secs",
+        env = "CLIPSYNC_HISTORY_RETENTION_SECS"
+    )]
+    pub retention_secs: Option<u64>,
+}
+
+/// The server's size and rate limits, as the admin API shows and accepts
+/// them. Names follow the environment variables; in an update, omitted
+/// fields keep their value.
+#[derive(Debug, Default, PartialEq, Args, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitSettings {
+    /// Largest clipboard item in bytes
+    #[arg(long, env = "CLIPSYNC_MAX_ITEM_BYTES")]
+    pub max_item_bytes: Option<usize>,
+    /// Bytes of history kept per room
+    #[arg(long, env = "CLIPSYNC_MAX_ROOM_BYTES")]
+    pub max_room_bytes: Option<usize>,
+    /// Bytes of history kept across all rooms
+    #[arg(long, env = "CLIPSYNC_MAX_TOTAL_BYTES")]
+    pub max_total_bytes: Option<usize>,
+    /// Messages per second from one connection
+    #[arg(long, env = "CLIPSYNC_RATE_MESSAGES")]
+    pub rate_messages: Option<u32>,
+    /// Bytes per second from one connection
+    #[arg(long, env = "CLIPSYNC_RATE_BYTES")]
+    pub rate_bytes: Option<u64>,
+    /// Messages per second from all connections of one IP address
+    #[arg(long, env = "CLIPSYNC_IP_RATE_MESSAGES")]
+    pub ip_rate_messages: Option<u32>,
+    /// Bytes per second from all connections of one IP address
+    #[arg(long, env = "CLIPSYNC_IP_RATE_BYTES")]
+    pub ip_rate_bytes: Option<u64>,
+    /// Open connections allowed from one IP address
+    #[arg(long, env = "CLIPSYNC_MAX_CONNECTIONS_PER_IP")]
+    pub max_connections_per_ip: Option<usize>,
+    /// Devices allowed in one room
+    #[arg(long, env = "CLIPSYNC_MAX_CLIENTS_PER_ROOM")]
+    pub max_clients_per_room: Option<usize>,
+    /// Seconds a new connection may take to join a room
+    #[arg(long, env = "CLIPSYNC_JOIN_TIMEOUT_SECS")]
+    pub join_timeout_secs: Option<u64>,
+    /// Seconds a joined connection may stay silent
+    #[arg(long, env = "CLIPSYNC_IDLE_TIMEOUT_SECS")]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl LimitSettings {
+    pub fn current(limits: Limits, rate: RateLimits) -> Self {
+        Self {
+            max_item_bytes: Some(limits.max_item_size),
+            max_room_bytes: Some(limits.max_room_bytes),
+            max_total_bytes: Some(limits.max_total_bytes),
+            rate_messages: Some(rate.messages_per_sec),
+            rate_bytes: Some(rate.bytes_per_sec),
+            ip_rate_messages: Some(rate.ip_messages_per_sec),
+            ip_rate_bytes: Some(rate.ip_bytes_per_sec),
+            max_connections_per_ip: Some(rate.max_connections_per_ip),
+            max_clients_per_room: Some(rate.max_clients_per_room),
+            join_timeout_secs: Some(rate.join_timeout.as_secs()),
+            idle_timeout_secs: Some(rate.idle_timeout.as_secs()),
+        }
+    }
+
+    /// Apply the fields that are set, refusing zeros, which would stop the
+    /// server from accepting anything
+    pub fn apply(&self, limits: &mut Limits, rate: &mut RateLimits) -> Result<(), String> {
+        macro_rules! set {
+            ($target:expr, $field:ident) => {
+                match self.$field {
+                    Some(0) => return Err(format!("{} must not be 0", stringify!($field))),
+                    Some(value) => $target = value,
+                    None => {}
+                }
+            };
+        }
+
+        set!(limits.max_item_size, max_item_bytes);
+        set!(limits.max_room_bytes, max_room_bytes);
+        set!(limits.max_total_bytes, max_total_bytes);
+        set!(rate.messages_per_sec, rate_messages);
+        set!(rate.bytes_per_sec, rate_bytes);
+        set!(rate.ip_messages_per_sec, ip_rate_messages);
+        set!(rate.ip_bytes_per_sec, ip_rate_bytes);
+        set!(rate.max_connections_per_ip, max_connections_per_ip);
+        set!(rate.max_clients_per_room, max_clients_per_room);
+        let mut join_timeout = rate.join_timeout.as_secs();
+        set!(join_timeout, join_timeout_secs);
+        rate.join_timeout = Duration::from_secs(join_timeout);
+        let mut idle_timeout = rate.idle_timeout.as_secs();
+        set!(idle_timeout, idle_timeout_secs);
+        rate.idle_timeout = Duration::from_secs(idle_timeout);
+        Ok(())
+    }
+}
+
+/// Built-in TLS, enabled by giving both files
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    /// PEM certificate chain; enables built-in TLS together with --tls-key
+    #[arg(long = "tls-cert", env = "CLIPSYNC_TLS_CERT")]
+    pub cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long = "tls-key", env = "CLIPSYNC_TLS_KEY")]
+    pub key: Option<PathBuf>,
+}
+
+/// Cross-origin access to the HTTP endpoints, e.g. for a browser dashboard
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSettings {
+    /// Origins allowed to call /health, /metrics and the admin API, comma
+    /// separated, or `*` for any
+    #[arg(
+        long = "cors-origin",
+        env = "CLIPSYNC_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    pub allowed_origins: Option<Vec<String>>,
+}
+
+/// How the server stops on SIGTERM or SIGINT
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSettings {
+    /// Seconds a graceful shutdown may take before remaining connections are
+    /// dropped [default: 5]
+    #[arg(long = "shutdown-timeout-secs", env = "CLIPSYNC_SHUTDOWN_TIMEOUT_SECS")]
+    pub timeout_secs: Option<u64>,
+
+    /// Seconds clients are asked to wait before reconnecting after a shutdown
+    /// [default: 5]
+    #[arg(
+        long = "shutdown-reconnect-secs",
+        env = "CLIPSYNC_SHUTDOWN_RECONNECT_SECS"
+    )]
+    pub reconnect_secs: Option<u64>,
+}
+
+impl Settings {
+    /// Read settings from a TOML file
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    /// Keep the settings that are given, taking the others from `fallback`
+    pub fn or(mut self, fallback: Self) -> Self {
+        or_fields!(self, fallback; bind, ipv6_only, port, health_port, log_format, allowed_rooms, admin_token, ws_deflate);
+        or_fields!(self.history, fallback.history; backend, db_path, max_entries, retention_secs);
+        or_fields!(
+            self.limits,
+            fallback.limits;
+            max_item_bytes,
+            max_room_bytes,
+            max_total_bytes,
+            rate_messages,
+            rate_bytes,
+            ip_rate_messages,
+            ip_rate_bytes,
+            max_connections_per_ip,
+            max_clients_per_room,
+            join_timeout_secs,
+            idle_timeout_secs,
+        );
+        or_fields!(self.tls, fallback.tls; cert, key);
+        or_fields!(self.cors, fallback.cors; allowed_origins);
+        or_fields!(self.shutdown, fallback.shutdown; timeout_secs, reconnect_secs);
+        self
+    }
+}
+
+/// The server's configuration, with defaults filled in and checked
+pub struct Config {
+    pub listen: SocketAddr,
+    pub ipv6_only: bool,
+    pub health_port: Option<u16>,
+    pub log_format: LogFormat,
+    /// Rooms that may be joined, or `None` for any
+    pub allowed_rooms: Option<HashSet<String>>,
+    pub admin_token: Option<String>,
+    pub ws_deflate: bool,
+    pub history_backend: HistoryBackend,
+    pub max_history: usize,
+    /// How long history is kept, or `None` until it falls out of the ring buffer
+    pub history_retention: Option<Duration>,
+    pub limits: Limits,
+    pub rate_limits: RateLimits,
+    /// Certificate and key for built-in TLS
+    pub tls: Option<(PathBuf, PathBuf)>,
+    /// Origins allowed cross-origin access; empty to send no CORS headers
+    pub cors_origins: Vec<HeaderValue>,
+    pub shutdown_timeout: Duration,
+    pub reconnect_after: Duration,
+}
+
+impl Config {
+    pub fn resolve(settings: Settings) -> anyhow::Result<Self> {
+        let bind = settings.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let ipv6_only = settings.ipv6_only.unwrap_or(false);
+        if ipv6_only && bind.is_ipv4() {
+            anyhow::bail!("ipv6_only needs an IPv6 bind address, not {}", bind);
+        }
+
+        let allowed_rooms = match settings.allowed_rooms {
+            Some(rooms) => Some(parse_room_hashes(rooms)?),
+            None => None,
+        };
+
+        let history_backend = HistoryBackend::parse(
+            settings.history.backend.as_deref().unwrap_or("memory"),
+            settings
+                .history
+                .db_path
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "clipsync.db".into()),
+        )?;
+        let max_history = settings.history.max_entries.unwrap_or(50);
+        if max_history == 0 {
+            anyhow::bail!("max_entries must not be 0");
+        }
+
+        let mut limits = Limits::default();
+        let mut rate_limits = RateLimits::default();
+        settings
+            .limits
+            .apply(&mut limits, &mut rate_limits)
+            .map_err(anyhow::Error::msg)?;
+
+        let tls = match (settings.tls.cert, settings.tls.key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => anyhow::bail!("TLS needs both a certificate and a key"),
+        };
+
+        let cors_origins = parse_origins(settings.cors.allowed_origins.unwrap_or_default())?;
+
+        Ok(Self {
+            listen: SocketAddr::new(bind, settings.port.unwrap_or(8080)),
+            ipv6_only,
+            health_port: settings.health_port,
+            log_format: settings.log_format.unwrap_or_default(),
+            allowed_rooms,
+            admin_token: settings.admin_token.filter(|token| !token.is_empty()),
+            ws_deflate: settings.ws_deflate.unwrap_or(true),
+            history_backend,
+            max_history,
+            history_retention: Some(settings.history.retention_secs.unwrap_or(7 * 24 * 60 * 60))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            limits,
+            rate_limits,
+            tls,
+            cors_origins,
+            shutdown_timeout: Duration::from_secs(settings.shutdown.timeout_secs.unwrap_or(5)),
+            reconnect_after: Duration::from_secs(settings.shutdown.reconnect_secs.unwrap_or(5)),
+        })
+    }
+
+    /// The effective settings, one per line, without the admin token
+    pub fn summary(&self) -> String {
+        let mut lines = vec![
+            format!("listen: {}", self.listen),
+            format!("ipv6_only: {}", self.ipv6_only),
+            format!("health_port: {:?}", self.health_port),
+            format!("log_format: {:?}", self.log_format),
+            format!(
+                "allowed_rooms: {}",
+                self.allowed_rooms
+                    .as_ref()
+                    .map_or("any".to_string(), |rooms| rooms.len().to_string())
+            ),
+            format!("admin_api: {}", self.admin_token.is_some()),
+            format!("ws_deflate: {}", self.ws_deflate),
+            format!("history_backend: {:?}", self.history_backend),
+            format!("max_history: {}", self.max_history),
+            format!("history_retention: {:?}", self.history_retention),
+            format!("limits: {:?}", self.limits),
+            format!("rate_limits: {:?}", self.rate_limits),
+            format!("tls: {}", self.tls.is_some()),
+            format!("cors_origins: {:?}", self.cors_origins),
+            format!("shutdown_timeout: {:?}", self.shutdown_timeout),
+            format!("reconnect_after: {:?}", self.reconnect_after),
+        ];
+        if let Some((cert, key)) = &self.tls {
+            lines.push(format!("tls_cert: {}", cert.display()));
+            lines.push(format!("tls_key: {}", key.display()));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Room hashes as `hash_secret` produces them: 64 lowercase hex digits
+fn parse_room_hashes(rooms: Vec<String>) -> anyhow::Result<HashSet<String>> {
+    let rooms: HashSet<String> = rooms
+        .into_iter()
+        .map(|room| room.trim().to_ascii_lowercase())
+        .filter(|room| !room.is_empty())
+        .collect();
+    if rooms.is_empty() {
+        anyhow::bail!("allowed_rooms is empty, so no room could be joined");
+    }
+    if let Some(room) = rooms.iter().find(|room| !is_secret_hash(room)) {
+        anyhow::bail!("Not a room hash (64 hex digits): {}", room);
+    }
+    Ok(rooms)
+}
+
+fn parse_origins(origins: Vec<String>) -> anyhow::Result<Vec<HeaderValue>> {
+    if origins.len() > 1 && origins.iter().any(|origin| origin == "*") {
+        anyhow::bail!("The CORS origin `*` cannot be combined with other origins");
+    }
+    origins
+        .into_iter()
+        .map(|origin| {
+            HeaderValue::from_str(origin.trim_end_matches('/'))
+                .map_err(|_| anyhow::anyhow!("Invalid CORS origin: {}", origin))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line_and_environment_override_the_file() {
+        let file: Settings = toml::from_str(
+            r#"
+            port = 9000
+            bind = "::"
+
+            [history]
+            max_entries = 20
+
+            [limits]
+            rate_messages = 5
+            "#,
+        )
+        .unwrap();
+        let cli = Settings {
+            port: Some(9100),
+            limits: LimitSettings {
+                rate_bytes: Some(1024),
+                ..LimitSettings::default()
+            },
+            ..Settings::default()
+        };
+
+        let config = Config::resolve(cli.or(file)).unwrap();
+        assert_eq!(config.listen, "[::]:9100".parse().unwrap());
+        assert_eq!(config.max_history, 20);
+        assert_eq!(config.rate_limits.messages_per_sec, 5);
+        assert_eq!(config.rate_limits.bytes_per_sec, 1024);
+        assert_eq!(config.history_retention, Some(Duration::from_secs(604800)));
+        assert!(config.allowed_rooms.is_none());
+    }
+
+    #[test]
+    fn test_invalid_settings_are_reported() {
+        let resolve = |toml: &str| Config::resolve(toml::from_str(toml).unwrap());
+        assert!(resolve("ipv6_only = true").is_err());
+        assert!(resolve(r#"allowed_rooms = ["not-a-hash"]"#).is_err());
+        assert!(resolve("allowed_rooms = []").is_err());
+        assert!(resolve("[tls]\ncert = \"cert.pem\"").is_err());
+        assert!(resolve("[limits]\nmax_item_bytes = 0").is_err());
+        assert!(resolve("[history]\nbackend = \"postgres\"").is_err());
+        assert!(resolve(
+            r#"[cors]
+allowed_origins = ["*", "https://example.com"]"#
+        )
+        .is_err());
+        assert!(toml::from_str::<Settings>("prot = 8080").is_err());
+    }
+
+    #[test]
+    fn test_example_config_is_valid() {
+        let settings: Settings =
+            toml::from_str(include_str!("../../../clipsync-server.example.toml")).unwrap();
+        let config = Config::resolve(settings).unwrap();
+        let room = "0".repeat(64);
+        assert!(config.allowed_rooms.unwrap().contains(&room));
+    }
+}
@@ -1,141 +1,714 @@
 use axum::{
-    extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
-    },
-    response::Response,
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use clipsync_common::{
+    is_secret_hash, offers_deflate, Capabilities, ClientMessage, ClipboardEntry, ClipboardItem,
+    Codec, CodecError, DeflateStream, DeviceInfo, ErrorCode, Frame, ServerMessage, TransferError,
+    DEFLATE_EXTENSION, MAX_DEVICE_LABEL_LEN, TRANSFER_CHUNK_SIZE,
 };
-use clipsync_common::{ClientMessage, ClipboardEntry, ServerMessage};
 use futures_util::{sink::SinkExt, stream::StreamExt};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::ratelimit::Verdict;
+use crate::room::{short_hash, HistoryQuery};
+use crate::state::AppState;
+
+/// How long replies still queued when a session ends get to go out
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
 
-use crate::room::RoomManager;
+/// A client connection, with permessage-deflate if it was negotiated
+type WebSocket = WebSocketStream<DeflateStream<TokioIo<Upgraded>>>;
+
+/// Whether a comma separated header lists `token`, ignoring case
+fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
 
+/// Upgrade to a WebSocket. The handshake is done here rather than by axum,
+/// which cannot negotiate permessage-deflate.
 pub async fn websocket_handler(
-    ws: WebSocketUpgrade,
-    State(room_manager): State<Arc<RoomManager>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    mut request: Request,
 ) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, room_manager))
+    let headers = request.headers();
+    if !has_token(headers, header::CONNECTION, "upgrade")
+        || !has_token(headers, header::UPGRADE, "websocket")
+    {
+        return (StatusCode::UPGRADE_REQUIRED, "Expected a WebSocket upgrade").into_response();
+    }
+    if !has_token(headers, header::SEC_WEBSOCKET_VERSION, "13") {
+        return (StatusCode::BAD_REQUEST, "Unsupported WebSocket version").into_response();
+    }
+    let Some(key) = headers.get(header::SEC_WEBSOCKET_KEY) else {
+        return (StatusCode::BAD_REQUEST, "Missing Sec-WebSocket-Key").into_response();
+    };
+    let accept = derive_accept_key(key.as_bytes());
+    let deflate = state.ws_deflate
+        && offers_deflate(
+            headers
+                .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+                .iter()
+                .filter_map(|value| value.to_str().ok()),
+        );
+    let Some(on_upgrade) = request.extensions_mut().remove::<OnUpgrade>() else {
+        return (
+            StatusCode::UPGRADE_REQUIRED,
+            "Connection cannot be upgraded",
+        )
+            .into_response();
+    };
+
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let stream = DeflateStream::server(TokioIo::new(upgraded), deflate);
+                let socket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+                handle_socket(socket, addr, state).await;
+            }
+            Err(e) => tracing::warn!("WebSocket upgrade from {} failed: {}", addr, e),
+        }
+    });
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept);
+    if deflate {
+        response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, DEFLATE_EXTENSION);
+    }
+    response.body(Body::empty()).unwrap()
+}
+
+/// Tell a client why it is turned away, then close the connection
+async fn refuse(mut socket: WebSocket, msg: ServerMessage) {
+    if let Ok(json) = msg.to_json() {
+        let _ = socket.send(Message::Text(json)).await;
+    }
+    let _ = socket.close(None).await;
+}
+
+/// A device name or platform as it is passed on to other devices: without
+/// control characters and no longer than `MAX_DEVICE_LABEL_LEN`
+fn device_label(label: Option<String>) -> Option<String> {
+    let label: String = label?
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_DEVICE_LABEL_LEN)
+        .collect();
+    let label = label.trim();
+    (!label.is_empty()).then(|| label.to_string())
 }
 
-async fn handle_socket(socket: WebSocket, room_manager: Arc<RoomManager>) {
+/// A blob streamed to the client one chunk at a time, each chunk encoded only
+/// once the previous one has been written to the socket
+#[derive(Clone)]
+struct Download {
+    hash: String,
+    blob: Arc<Vec<u8>>,
+    /// Index of the next chunk to send
+    next: u32,
+}
+
+impl Download {
+    /// The next chunk, or `None` once the whole blob has been sent
+    fn next_chunk(&mut self) -> Option<ServerMessage> {
+        let total = self.blob.len().div_ceil(TRANSFER_CHUNK_SIZE) as u32;
+        let data = self
+            .blob
+            .chunks(TRANSFER_CHUNK_SIZE)
+            .nth(self.next as usize)?
+            .to_vec();
+        let index = self.next;
+        self.next += 1;
+        Some(ServerMessage::BlobChunk {
+            hash: self.hash.clone(),
+            index,
+            total,
+            data,
+        })
+    }
+}
+
+/// Tell a client the server is going away and when to come back
+fn shutting_down(state: &AppState) -> ServerMessage {
+    ServerMessage::ServerShuttingDown {
+        reconnect_after_ms: state.shutdown.reconnect_after().as_millis() as u64,
+    }
+}
+
+async fn handle_socket(socket: WebSocket, addr: SocketAddr, state: AppState) {
+    // Counted until the last reply is flushed, so shutdown waits for it
+    let _open = state.shutdown.track();
+    if state.shutdown.is_started() {
+        refuse(socket, shutting_down(&state)).await;
+        return;
+    }
+    let Some(mut limit) = state.limiter.connect(addr.ip()) else {
+        let msg = ServerMessage::error(
+            ErrorCode::RateLimited,
+            "Too many connections from this address",
+        );
+        refuse(socket, msg).await;
+        return;
+    };
+    let room_manager = state.rooms.clone();
+    let metrics = state.metrics.clone();
+    let send_metrics = state.metrics.clone();
+    let send_limiter = state.limiter.clone();
+    let join_deadline = tokio::time::Instant::now() + state.limiter.limits().join_timeout;
+
     let (mut sender, mut receiver) = socket.split();
 
     // Channel for sending messages to this client
     let (tx, mut rx) = mpsc::unbounded_channel::<ServerMessage>();
+    // The blob being downloaded; a new request replaces one still in flight
+    let (download_tx, mut downloads) = watch::channel(None::<Download>);
 
     // State for this connection
     let mut room: Option<Arc<tokio::sync::RwLock<crate::room::Room>>> = None;
-    let mut device_id: Option<String> = None;
+    let mut device: Option<DeviceInfo> = None;
+    let mut capabilities = Capabilities::default();
+    // Notified to end the session, e.g. by an operator through the admin API
+    let kick = Arc::new(Notify::new());
 
     // Spawn task to forward messages from channel to websocket
     let mut send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if let Ok(json) = msg.to_json() {
-                if sender.send(Message::Text(json)).await.is_err() {
-                    break;
+        // JSON until the client joins and negotiates something else
+        let mut codec = Codec::Json;
+        let mut download: Option<Download> = None;
+        // WebSocket pings get an answer even from clients that send no
+        // heartbeats of their own, so only dead connections go silent
+        let mut keepalive_period = send_limiter.limits().keepalive_interval();
+        let mut keepalive = tokio::time::interval_at(
+            tokio::time::Instant::now() + keepalive_period,
+            keepalive_period,
+        );
+        loop {
+            let msg = tokio::select! {
+                // Replies and broadcasts go out between the chunks of a download
+                biased;
+                msg = rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                Ok(()) = downloads.changed() => {
+                    download = downloads.borrow_and_update().clone();
+                    continue;
+                }
+                _ = keepalive.tick() => {
+                    if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                    // Follow changes to the idle timeout made at runtime
+                    let period = send_limiter.limits().keepalive_interval();
+                    if period != keepalive_period {
+                        keepalive_period = period;
+                        keepalive = tokio::time::interval_at(
+                            tokio::time::Instant::now() + period,
+                            period,
+                        );
+                    }
+                    continue;
+                }
+                _ = std::future::ready(()), if download.is_some() => {
+                    let Some(chunk) = download.as_mut().and_then(Download::next_chunk) else {
+                        download = None;
+                        continue;
+                    };
+                    chunk
+                }
+            };
+            if let ServerMessage::Joined {
+                codec: negotiated, ..
+            } = &msg
+            {
+                codec = *negotiated;
+            }
+            if let ServerMessage::Error { code, .. } = &msg {
+                send_metrics.rejected(*code);
+            }
+
+            let frame = match msg.encode(codec) {
+                Ok(Frame::Text(text)) => {
+                    send_metrics.sent(msg.kind(), text.len());
+                    Message::Text(text)
+                }
+                Ok(Frame::Binary(bytes)) => {
+                    send_metrics.sent(msg.kind(), bytes.len());
+                    Message::Binary(bytes)
                 }
+                Err(e) => {
+                    tracing::warn!("Failed to encode message: {}", e);
+                    continue;
+                }
+            };
+            if sender.send(frame).await.is_err() {
+                break;
             }
         }
+        let _ = sender.close().await;
     });
 
     // Handle incoming messages
     let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                Message::Text(text) => {
-                    match ClientMessage::from_json(&text) {
-                        Ok(client_msg) => {
-                            match client_msg {
-                                ClientMessage::Join {
-                                    secret_hash: hash,
-                                    device_id: dev_id,
-                                } => {
-                                    tracing::info!("Device {} joining room {}", dev_id, &hash[..8]);
-
-                                    // Get or create room
-                                    let r = room_manager.get_or_create_room(hash.clone()).await;
-
-                                    // Get history before adding client
-                                    let history = {
-                                        let room_guard = r.read().await;
-                                        room_guard.get_history()
+        loop {
+            // A connection that never joins only holds resources
+            let next = if room.is_none() {
+                tokio::select! {
+                    next = tokio::time::timeout_at(join_deadline, receiver.next()) => match next {
+                        Ok(next) => next,
+                        Err(_) => {
+                            tracing::warn!("Closing connection from {} that did not join", addr);
+                            let _ = tx.send(ServerMessage::error(
+                                ErrorCode::NotJoined,
+                                "Timed out waiting for Join",
+                            ));
+                            break;
+                        }
+                    },
+                    _ = state.shutdown.started() => {
+                        let _ = tx.send(shutting_down(&state));
+                        break;
+                    }
+                }
+            } else {
+                // Heartbeats and answers to keepalive pings arrive well within
+                // the idle timeout; a silent connection is most likely half-open
+                let idle_timeout = state.limiter.limits().idle_timeout;
+                tokio::select! {
+                    next = tokio::time::timeout(idle_timeout, receiver.next()) => match next {
+                        Ok(next) => next,
+                        Err(_) => {
+                            tracing::warn!(
+                                "Closing connection from {} after {:?} without a message",
+                                addr,
+                                idle_timeout
+                            );
+                            metrics.timed_out();
+                            let _ = tx.send(ServerMessage::error(
+                                ErrorCode::Disconnected,
+                                "Timed out waiting for a message",
+                            ));
+                            break;
+                        }
+                    },
+                    _ = kick.notified() => {
+                        let _ = tx.send(ServerMessage::error(
+                            ErrorCode::Disconnected,
+                            "Disconnected by the server operator",
+                        ));
+                        break;
+                    }
+                    _ = state.shutdown.started() => {
+                        let _ = tx.send(shutting_down(&state));
+                        break;
+                    }
+                }
+            };
+            let Some(Ok(msg)) = next else {
+                break;
+            };
+
+            let (parsed, len) = match msg {
+                Message::Text(text) => (
+                    ClientMessage::from_json(&text).map_err(CodecError::from),
+                    text.len(),
+                ),
+                Message::Binary(bytes) => (ClientMessage::from_msgpack(&bytes), bytes.len()),
+                Message::Close(_) => {
+                    tracing::info!("Client closed connection");
+                    break;
+                }
+                Message::Ping(data) => {
+                    // tungstenite answers pings itself
+                    tracing::trace!("Received ping: {:?}", data);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            metrics.received(parsed.as_ref().map_or("invalid", ClientMessage::kind), len);
+            let counts_as_message = !matches!(parsed, Ok(ClientMessage::UploadChunk { .. }));
+            match limit.check(len, counts_as_message) {
+                Verdict::Allow(wait) => tokio::time::sleep(wait).await,
+                Verdict::Reject => {
+                    let _ = tx.send(ServerMessage::error(
+                        ErrorCode::RateLimited,
+                        "Too many messages; slow down",
+                    ));
+                    continue;
+                }
+                Verdict::Close => {
+                    let _ = tx.send(ServerMessage::error(
+                        ErrorCode::RateLimited,
+                        "Too many messages; closing the connection",
+                    ));
+                    break;
+                }
+            }
+
+            match parsed {
+                Ok(client_msg) => {
+                    match client_msg {
+                        ClientMessage::Join {
+                            secret_hash: hash,
+                            device_id: dev_id,
+                            capabilities: client_capabilities,
+                            since,
+                            name,
+                            platform,
+                        } => {
+                            let started = std::time::Instant::now();
+                            // Cleanup only knows the room joined last
+                            if room.is_some() {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::InvalidMessage,
+                                    "Already joined; reconnect to join another room",
+                                ));
+                                continue;
+                            }
+                            if !is_secret_hash(&hash) {
+                                tracing::warn!("Refusing device {}: malformed room hash", dev_id);
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::InvalidMessage,
+                                    "secret_hash must be 64 lowercase hex digits",
+                                ));
+                                continue;
+                            }
+                            if let Some(reason) = client_capabilities.incompatibility() {
+                                tracing::warn!("Refusing device {}: {}", dev_id, reason);
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::VersionMismatch,
+                                    format!("Incompatible client: {}", reason),
+                                ));
+                                continue;
+                            }
+
+                            if !room_manager.is_allowed(&hash) {
+                                tracing::warn!(
+                                    "Refusing device {}: room {} is not allowed",
+                                    dev_id,
+                                    short_hash(&hash)
+                                );
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::Unauthorized,
+                                    "This server does not host that room",
+                                ));
+                                continue;
+                            }
+
+                            tracing::info!(
+                                "Device {} joining room {} with protocol version {}",
+                                dev_id,
+                                short_hash(&hash),
+                                client_capabilities.protocol_version
+                            );
+
+                            // Get or create room
+                            let r = room_manager.get_or_create_room(hash.clone()).await;
+
+                            let codec = Codec::negotiate(&client_capabilities.codecs);
+                            let server_capabilities = Capabilities {
+                                max_item_size: Some(room_manager.limits().max_item_size as u64),
+                                ..Capabilities::current()
+                            };
+                            let info = DeviceInfo {
+                                device_id: dev_id.clone(),
+                                name: device_label(name),
+                                platform: device_label(platform),
+                            };
+
+                            // Confirm the join with history and presence before
+                            // adding the client, so `Joined` is the first thing
+                            // it hears about the room
+                            let joined = {
+                                let mut room_guard = r.write().await;
+                                if !room_guard.has_client(&dev_id)
+                                    && room_guard.client_count()
+                                        >= state.limiter.limits().max_clients_per_room
+                                {
+                                    false
+                                } else {
+                                    let query = HistoryQuery {
+                                        since,
+                                        ..Default::default()
                                     };
+                                    let (history, _) =
+                                        room_guard.history_for(&client_capabilities, query);
+                                    let _ = tx.send(ServerMessage::joined(
+                                        history,
+                                        codec,
+                                        server_capabilities,
+                                        room_guard.presence(&dev_id),
+                                    ));
+                                    room_guard.add_client(
+                                        info.clone(),
+                                        tx.clone(),
+                                        client_capabilities.clone(),
+                                        kick.clone(),
+                                    );
+                                    true
+                                }
+                            };
+                            if !joined {
+                                state.limiter.refuse();
+                                tracing::warn!(
+                                    "Room {} is full, refusing device {}",
+                                    short_hash(&hash),
+                                    dev_id
+                                );
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::RoomFull,
+                                    "Room has no space for another device",
+                                ));
+                                break;
+                            }
+                            metrics.joined(started.elapsed());
+
+                            // Update state
+                            room = Some(r);
+                            device = Some(info);
+                            capabilities = client_capabilities;
+                        }
+                        ClientMessage::NewClip {
+                            id,
+                            item,
+                            content_hash,
+                            format,
+                            fallback,
+                            timestamp,
+                        } => {
+                            let sealed = item.is_encrypted() || item.reference_hash().is_some();
+                            if !sealed || fallback.as_ref().is_some_and(|f| !f.is_encrypted()) {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::InvalidMessage,
+                                    "Clipboard items must be end-to-end encrypted",
+                                ));
+                                continue;
+                            }
 
-                                    // Add client to room
-                                    {
-                                        let mut room_guard = r.write().await;
-                                        room_guard.add_client(dev_id.clone(), tx.clone());
+                            if let (Some(ref r), Some(ref info)) = (&room, &device) {
+                                let dev_id = &info.device_id;
+                                let mut room_guard = r.write().await;
+                                if room_guard.contains(&id) {
+                                    // Retransmitted after a lost ack; it is stored already
+                                    tracing::debug!(
+                                        "Clip {} from device {} already stored",
+                                        id,
+                                        dev_id
+                                    );
+                                    let _ = tx.send(ServerMessage::Ack { id });
+                                    continue;
+                                }
+
+                                let entry = ClipboardEntry {
+                                    id: id.clone(),
+                                    seq: 0,
+                                    item,
+                                    timestamp,
+                                    device_id: Some(dev_id.clone()),
+                                    device_name: info.name.clone(),
+                                    device_platform: info.platform.clone(),
+                                    content_hash,
+                                    format,
+                                    fallback,
+                                };
+
+                                if let ClipboardItem::Reference { hash, size } = &entry.item {
+                                    // The size counts against quotas, so it must be the real one
+                                    if room_guard.blob_size(hash) != Some(*size as usize) {
+                                        let _ = tx.send(ServerMessage::error(
+                                            ErrorCode::TransferFailed,
+                                            "Referenced item has not been uploaded",
+                                        ));
+                                        continue;
                                     }
+                                }
 
-                                    // Send join confirmation with history
-                                    let _ = tx.send(ServerMessage::joined(history));
+                                if room_guard.is_latest(&entry) {
+                                    // Same content bounced back by another device
+                                    tracing::debug!(
+                                        "Dropping repeated clip from device {}",
+                                        dev_id
+                                    );
+                                    let _ = tx.send(ServerMessage::Ack { id });
+                                    continue;
+                                }
 
-                                    // Update state
-                                    room = Some(r);
-                                    device_id = Some(dev_id);
+                                if let Err(e) = room_guard.make_space(&entry) {
+                                    tracing::warn!("Rejected clip from device {}: {}", dev_id, e);
+                                    let _ = tx.send(ServerMessage::error(
+                                        ErrorCode::PayloadTooLarge,
+                                        format!("Clip not stored: {}", e),
+                                    ));
+                                    continue;
                                 }
-                                ClientMessage::NewClip { item, timestamp } => {
-                                    if let (Some(ref r), Some(ref dev_id)) = (&room, &device_id) {
-                                        let entry = ClipboardEntry {
-                                            item,
-                                            timestamp,
-                                            device_id: Some(dev_id.clone()),
-                                        };
-
-                                        tracing::debug!("New clip from device {}", dev_id);
-
-                                        let mut room_guard = r.write().await;
-                                        room_guard.add_to_history(entry.clone());
-                                        room_guard.broadcast(entry, dev_id);
-
-                                        // Send acknowledgment
-                                        let _ = tx.send(ServerMessage::Ack { timestamp });
-                                    } else {
-                                        let _ =
-                                            tx.send(ServerMessage::error("Not joined to a room"));
-                                    }
+
+                                tracing::debug!("New clip from device {}", dev_id);
+
+                                let entry = room_guard.add_to_history(entry);
+                                metrics.broadcast_failed(room_guard.broadcast(entry, dev_id));
+
+                                // Send acknowledgment
+                                let _ = tx.send(ServerMessage::Ack { id });
+                            } else {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::NotJoined,
+                                    "Not joined to a room",
+                                ));
+                            }
+                        }
+                        ClientMessage::UploadChunk {
+                            hash,
+                            index,
+                            total,
+                            data,
+                        } => {
+                            let Some(ref r) = room else {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::NotJoined,
+                                    "Not joined to a room",
+                                ));
+                                continue;
+                            };
+
+                            if total > room_manager.limits().max_chunks() {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::PayloadTooLarge,
+                                    "Upload is larger than the server accepts",
+                                ));
+                                continue;
+                            }
+
+                            let result = r.write().await.receive_chunk(&hash, index, total, &data);
+                            match result {
+                                Ok(received) => {
+                                    let _ = tx.send(ServerMessage::UploadProgress {
+                                        hash,
+                                        received,
+                                        total,
+                                    });
                                 }
-                                ClientMessage::RequestHistory => {
-                                    if let Some(ref r) = room {
-                                        let room_guard = r.read().await;
-                                        let history = room_guard.get_history();
-                                        let _ =
-                                            tx.send(ServerMessage::History { entries: history });
-                                    } else {
-                                        let _ =
-                                            tx.send(ServerMessage::error("Not joined to a room"));
-                                    }
+                                Err(TransferError::TooLarge) => {
+                                    let _ = tx.send(ServerMessage::error(
+                                        ErrorCode::PayloadTooLarge,
+                                        "Upload does not fit within the server's limits",
+                                    ));
                                 }
-                                ClientMessage::Ping => {
-                                    let _ = tx.send(ServerMessage::Pong);
+                                Err(e) => {
+                                    tracing::warn!("Rejected upload chunk: {}", e);
+                                    let _ = tx.send(ServerMessage::error(
+                                        ErrorCode::TransferFailed,
+                                        format!("Upload failed: {}", e),
+                                    ));
                                 }
                             }
                         }
-                        Err(e) => {
-                            tracing::warn!("Failed to parse client message: {}", e);
-                            let _ =
-                                tx.send(ServerMessage::error(format!("Invalid message: {}", e)));
+                        ClientMessage::QueryUpload { hash, total } => {
+                            let Some(ref r) = room else {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::NotJoined,
+                                    "Not joined to a room",
+                                ));
+                                continue;
+                            };
+
+                            // The reply lists every chunk of an unknown upload
+                            if total == 0 || total > room_manager.limits().max_chunks() {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::PayloadTooLarge,
+                                    "Upload is empty or larger than the server accepts",
+                                ));
+                                continue;
+                            }
+
+                            let missing = r.read().await.missing_chunks(&hash, total);
+                            let _ = tx.send(ServerMessage::UploadStatus { hash, missing });
+                        }
+                        ClientMessage::FetchBlob { hash, from_index } => {
+                            let Some(ref r) = room else {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::NotJoined,
+                                    "Not joined to a room",
+                                ));
+                                continue;
+                            };
+
+                            let Some(blob) = r.write().await.blob(&hash) else {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::NotFound,
+                                    "Unknown item",
+                                ));
+                                continue;
+                            };
+
+                            // Streamed by the send task, so a client that does
+                            // not read holds up its own download, not server memory
+                            let _ = download_tx.send(Some(Download {
+                                hash,
+                                blob,
+                                next: from_index,
+                            }));
+                        }
+                        ClientMessage::RequestHistory {
+                            since,
+                            before,
+                            limit,
+                        } => {
+                            if let Some(ref r) = room {
+                                let query = HistoryQuery {
+                                    since,
+                                    before,
+                                    limit: limit.map(|limit| limit as usize),
+                                };
+                                let (entries, more) =
+                                    r.write().await.history_for(&capabilities, query);
+                                let _ = tx.send(ServerMessage::History { entries, more });
+                            } else {
+                                let _ = tx.send(ServerMessage::error(
+                                    ErrorCode::NotJoined,
+                                    "Not joined to a room",
+                                ));
+                            }
+                        }
+                        ClientMessage::Ping => {
+                            let _ = tx.send(ServerMessage::Pong);
                         }
                     }
                 }
-                Message::Close(_) => {
-                    tracing::info!("Client closed connection");
-                    break;
-                }
-                Message::Ping(data) => {
-                    // Axum handles pong automatically
-                    tracing::trace!("Received ping: {:?}", data);
+                Err(e) => {
+                    tracing::warn!("Failed to parse client message: {}", e);
+                    let _ = tx.send(ServerMessage::error(
+                        ErrorCode::InvalidMessage,
+                        format!("Invalid message: {}", e),
+                    ));
                 }
-                _ => {}
             }
         }
 
         // Cleanup when connection closes
-        if let (Some(r), Some(dev_id)) = (room, device_id) {
+        if let (Some(r), Some(info)) = (room, device) {
             let mut room_guard = r.write().await;
-            room_guard.remove_client(&dev_id);
+            room_guard.remove_client(&info.device_id, &tx);
         }
 
         // Cleanup empty rooms periodically
@@ -148,7 +721,184 @@ async fn handle_socket(socket: WebSocket, room_manager: Arc<RoomManager>) {
             recv_task.abort();
         }
         _ = &mut recv_task => {
-            send_task.abort();
+            // Let queued replies, such as the error that ended the session, go out
+            if tokio::time::timeout(FLUSH_TIMEOUT, &mut send_task).await.is_err() {
+                send_task.abort();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::ratelimit::{Limiter, RateLimits};
+    use crate::room::{Limits, RoomManager};
+    use crate::shutdown::Shutdown;
+    use crate::store::MemoryStore;
+    use axum::{routing::get, Router};
+    use clipsync_common::hash_secret;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::MaybeTlsStream;
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Serve the WebSocket endpoint on a local port
+    async fn serve() -> (AppState, SocketAddr) {
+        let state = AppState {
+            rooms: Arc::new(RoomManager::new(
+                10,
+                Limits::default(),
+                Arc::new(MemoryStore),
+                None,
+            )),
+            limiter: Arc::new(Limiter::new(RateLimits::default())),
+            metrics: Arc::new(Metrics::default()),
+            shutdown: Arc::new(Shutdown::new(Duration::from_secs(1))),
+            ws_deflate: true,
+        };
+        let app = Router::new()
+            .route("/ws", get(websocket_handler))
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        (state, addr)
+    }
+
+    async fn send(client: &mut Client, msg: ClientMessage) {
+        client
+            .send(Message::Text(msg.to_json().unwrap()))
+            .await
+            .unwrap();
+    }
+
+    /// The next message from the server in either codec, skipping pings
+    async fn recv(client: &mut Client) -> ServerMessage {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            match msg {
+                Message::Text(text) => return ServerMessage::from_json(&text).unwrap(),
+                Message::Binary(bytes) => return ServerMessage::from_msgpack(&bytes).unwrap(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Connect and join the room for `secret` as `device_id`
+    async fn join(addr: SocketAddr, secret: &str, device_id: &str) -> Client {
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        send(&mut client, join_message(secret, device_id)).await;
+        assert!(matches!(
+            recv(&mut client).await,
+            ServerMessage::Joined { .. }
+        ));
+        client
+    }
+
+    fn join_message(secret: &str, device_id: &str) -> ClientMessage {
+        ClientMessage::join(
+            hash_secret(secret),
+            DeviceInfo {
+                device_id: device_id.into(),
+                name: None,
+                platform: None,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_second_join_on_a_socket_is_refused() {
+        let (state, addr) = serve().await;
+        let mut client = join(addr, "first", "dev-a").await;
+
+        send(&mut client, join_message("second", "dev-a")).await;
+        assert!(matches!(
+            recv(&mut client).await,
+            ServerMessage::Error {
+                code: ErrorCode::InvalidMessage,
+                ..
+            }
+        ));
+        assert_eq!(state.rooms.get_stats().await.rooms, 1);
+
+        // Leaving the first room leaves nothing behind
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.rooms.get_stats().await.clients > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upload_queries_are_bounded() {
+        let (_, addr) = serve().await;
+        let mut client = join(addr, "secret", "dev-a").await;
+
+        for total in [u32::MAX, 0] {
+            send(
+                &mut client,
+                ClientMessage::QueryUpload {
+                    hash: "abc".into(),
+                    total,
+                },
+            )
+            .await;
+            assert!(matches!(
+                recv(&mut client).await,
+                ServerMessage::Error {
+                    code: ErrorCode::PayloadTooLarge,
+                    ..
+                }
+            ));
+        }
+
+        send(
+            &mut client,
+            ClientMessage::QueryUpload {
+                hash: "abc".into(),
+                total: 3,
+            },
+        )
+        .await;
+        let ServerMessage::UploadStatus { missing, .. } = recv(&mut client).await else {
+            panic!("expected the upload's status");
+        };
+        assert_eq!(missing, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_download_resumes_from_index_and_ends() {
+        let blob = Arc::new(vec![7u8; TRANSFER_CHUNK_SIZE * 2 + 1]);
+        let mut download = Download {
+            hash: "abc".into(),
+            blob,
+            next: 1,
+        };
+
+        let mut sent = Vec::new();
+        while let Some(ServerMessage::BlobChunk {
+            index, total, data, ..
+        }) = download.next_chunk()
+        {
+            sent.push((index, total, data.len()));
         }
+        assert_eq!(sent, vec![(1, 3, TRANSFER_CHUNK_SIZE), (2, 3, 1)]);
     }
 }
@@ -1,55 +1,250 @@
+mod admin;
+mod cli;
+mod config;
 mod handler;
+mod metrics;
+mod ratelimit;
 mod room;
+mod shutdown;
+mod state;
+mod store;
+mod tls;
 
-use axum::{routing::get, Router};
+use axum::{
+    extract::State,
+    http::{header, Method},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use clap::Parser;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+use crate::cli::Args;
+use crate::config::{Config, LogFormat, Settings};
+use crate::metrics::Metrics;
+use crate::ratelimit::Limiter;
 use crate::room::RoomManager;
+use crate::shutdown::Shutdown;
+use crate::state::AppState;
+
+/// How often expired history is purged
+const EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Command line flags and environment variables take precedence over the file
+    let file = match &args.config {
+        Some(path) => Settings::from_file(path)?,
+        None => Settings::default(),
+    };
+    let config = Config::resolve(args.settings.or(file))?;
+
+    if args.check_config {
+        if let Some((cert, key)) = &config.tls {
+            tls::check(cert, key).await?;
+        }
+        println!("{}", config.summary());
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
     // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "clipsync_server=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    // Get configuration from environment
-    let port = std::env::var("CLIPSYNC_PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8080);
-
-    let max_history = std::env::var("CLIPSYNC_MAX_HISTORY")
-        .ok()
-        .and_then(|h| h.parse().ok())
-        .unwrap_or(50);
-
-    tracing::info!("Starting ClipSync server on port {}", port);
-    tracing::info!("Max history per room: {}", max_history);
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "clipsync_server=debug,tower_http=debug".into());
+    let registry = tracing_subscriber::registry().with(filter);
+    match config.log_format {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
+    }
+
+    tracing::info!("Starting ClipSync server on {}", config.listen);
+    tracing::info!("Max history per room: {}", config.max_history);
+    tracing::info!(
+        "Size limits: {} bytes per item, {} per room, {} in total",
+        config.limits.max_item_size,
+        config.limits.max_room_bytes,
+        config.limits.max_total_bytes
+    );
+    tracing::info!("History backend: {:?}", config.history_backend);
+    tracing::info!("Rate limits: {:?}", config.rate_limits);
+    if let Some(rooms) = &config.allowed_rooms {
+        tracing::info!("Only {} allowed room(s) may be joined", rooms.len());
+    }
 
     // Create room manager
-    let room_manager = Arc::new(RoomManager::new(max_history));
+    let store = config.history_backend.open()?;
+    let room_manager = Arc::new(RoomManager::new(
+        config.max_history,
+        config.limits,
+        store,
+        config.allowed_rooms,
+    ));
+
+    // Periodically expire old history
+    if let Some(retention) = config.history_retention {
+        let room_manager = room_manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64;
+                let cutoff = now.saturating_sub(retention.as_millis() as u64);
+                room_manager.expire_history(cutoff).await;
+            }
+        });
+    }
 
     // Build router
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/ws", get(handler::websocket_handler))
         .route("/health", get(|| async { "OK" }))
-        .with_state(room_manager)
+        .route("/metrics", get(metrics_handler));
+    if let Some(token) = config.admin_token {
+        tracing::info!("Admin API enabled at /admin");
+        app = app.nest("/admin", admin::router(token));
+    }
+    let shutdown = Arc::new(Shutdown::new(config.reconnect_after));
+    let shutdown_timeout = config.shutdown_timeout;
+    let mut app = app
+        .with_state(AppState {
+            rooms: room_manager.clone(),
+            limiter: Arc::new(Limiter::new(config.rate_limits)),
+            metrics: Arc::new(Metrics::default()),
+            shutdown: shutdown.clone(),
+            ws_deflate: config.ws_deflate,
+        })
         .layer(TraceLayer::new_for_http());
+    if !config.cors_origins.is_empty() {
+        tracing::info!(
+            "Allowing cross-origin requests from {:?}",
+            config.cors_origins
+        );
+        let origins = if config.cors_origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(config.cors_origins)
+        };
+        app = app.layer(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::GET, Method::PATCH, Method::DELETE])
+                .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]),
+        );
+    }
+
+    // Optional plain-HTTP health endpoint, e.g. for load balancers that cannot speak TLS
+    if let Some(health_port) = config.health_port {
+        let health_addr = SocketAddr::new(config.listen.ip(), health_port);
+        let health_app = Router::new().route("/health", get(|| async { "OK" }));
+        let health_listener =
+            tokio::net::TcpListener::from_std(bind(health_addr, config.ipv6_only)?)?;
+        tracing::info!("Health endpoint listening on {}", health_addr);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(health_listener, health_app).await {
+                tracing::error!("Health endpoint failed: {}", e);
+            }
+        });
+    }
 
     // Start server
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    tracing::info!("Server listening on {}", addr);
+    let addr = config.listen;
+    let listener = bind(addr, config.ipv6_only)?;
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    // Both servers stop accepting connections once the shutdown begins
+    let mut server = match config.tls {
+        Some((cert, key)) => {
+            let tls_config = tls::load(cert, key).await?;
+            tracing::info!("Server listening on {} (TLS)", addr);
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown.started().await;
+                    handle.graceful_shutdown(Some(shutdown_timeout));
+                }
+            });
+            tokio::spawn(
+                axum_server::from_tcp_rustls(listener, tls_config)
+                    .handle(handle)
+                    .serve(app),
+            )
+        }
+        None => {
+            tracing::info!("Server listening on {}", addr);
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(async move { shutdown.started().await })
+                    .await
+            })
+        }
+    };
 
+    tokio::select! {
+        result = &mut server => return Ok(result??),
+        _ = shutdown::signal() => {}
+    }
+
+    // Tell every client to come back later, let in-flight requests finish and
+    // wait for the connections to close, but no longer than the deadline
+    tracing::info!(
+        "Shutting down, closing {} connection(s) within {:?}",
+        shutdown.open_connections(),
+        shutdown_timeout
+    );
+    shutdown.begin();
+    let drained = tokio::time::timeout(shutdown_timeout, async {
+        let _ = server.await;
+        shutdown.drained().await;
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!(
+            "Shutdown deadline passed, dropping {} connection(s)",
+            shutdown.open_connections()
+        );
+    }
+
+    room_manager.flush_history();
+    tracing::info!("Shutdown complete");
     Ok(())
 }
+
+/// Open a listening socket on `addr`. An IPv6 address also accepts IPv4
+/// connections, as far as the OS allows, unless `ipv6_only` is set.
+fn bind(addr: SocketAddr, ipv6_only: bool) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let stats = state.rooms.get_stats().await;
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&stats, &state.limiter),
+    )
+}
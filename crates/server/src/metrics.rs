@@ -0,0 +1,257 @@
+use clipsync_common::ErrorCode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::ratelimit::Limiter;
+use crate::room::Stats;
+
+/// Upper bounds of the join latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+/// Counters keyed by a label with a small, fixed set of values
+#[derive(Default)]
+struct CounterVec(Mutex<BTreeMap<&'static str, u64>>);
+
+impl CounterVec {
+    fn add(&self, label: &'static str, n: u64) {
+        *self.0.lock().unwrap().entry(label).or_default() += n;
+    }
+
+    fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Server-wide counters, exposed in the Prometheus text format.
+///
+/// Nothing is labelled by room: a room's hash is derived from its secret and
+/// must not leave the server.
+#[derive(Default)]
+pub struct Metrics {
+    messages_received: CounterVec,
+    messages_sent: CounterVec,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    errors_sent: CounterVec,
+    broadcast_failures: AtomicU64,
+    idle_timeouts: AtomicU64,
+    join_latency: Histogram,
+}
+
+impl Metrics {
+    /// Count a message from a client; `kind` is "invalid" if it did not parse
+    pub fn received(&self, kind: &'static str, bytes: usize) {
+        self.messages_received.add(kind, 1);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a message to a client
+    pub fn sent(&self, kind: &'static str, bytes: usize) {
+        self.messages_sent.add(kind, 1);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a request refused with an error
+    pub fn rejected(&self, code: ErrorCode) {
+        self.errors_sent.add(code.as_str(), 1);
+    }
+
+    /// Count devices a clip could not be relayed to
+    pub fn broadcast_failed(&self, devices: usize) {
+        self.broadcast_failures
+            .fetch_add(devices as u64, Ordering::Relaxed);
+    }
+
+    /// Count a connection closed after staying silent too long
+    pub fn timed_out(&self) {
+        self.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long a join took, from `Join` to `Joined`
+    pub fn joined(&self, latency: Duration) {
+        self.join_latency.observe(latency);
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render(&self, stats: &Stats, limiter: &Limiter) -> String {
+        let mut out = String::new();
+        let gauge = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+            );
+        };
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+            );
+        };
+        let labelled = |out: &mut String, name: &str, help: &str, label: &str, vec: &CounterVec| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            for (value, count) in vec.snapshot() {
+                let _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {count}");
+            }
+        };
+
+        gauge(
+            &mut out,
+            "clipsync_rooms",
+            "Rooms in memory.",
+            stats.rooms as u64,
+        );
+        gauge(
+            &mut out,
+            "clipsync_clients",
+            "Connected devices that have joined a room.",
+            stats.clients as u64,
+        );
+        gauge(
+            &mut out,
+            "clipsync_history_entries",
+            "History entries held across all rooms.",
+            stats.history_entries as u64,
+        );
+        gauge(
+            &mut out,
+            "clipsync_history_bytes",
+            "Bytes of history held across all rooms.",
+            stats.history_bytes as u64,
+        );
+        labelled(
+            &mut out,
+            "clipsync_messages_received_total",
+            "Messages received from clients.",
+            "type",
+            &self.messages_received,
+        );
+        labelled(
+            &mut out,
+            "clipsync_messages_sent_total",
+            "Messages sent to clients.",
+            "type",
+            &self.messages_sent,
+        );
+        counter(
+            &mut out,
+            "clipsync_bytes_received_total",
+            "Bytes of WebSocket messages received from clients.",
+            self.bytes_received.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "clipsync_bytes_sent_total",
+            "Bytes of WebSocket messages sent to clients.",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+        labelled(
+            &mut out,
+            "clipsync_rejected_messages_total",
+            "Requests answered with an error.",
+            "code",
+            &self.errors_sent,
+        );
+        counter(
+            &mut out,
+            "clipsync_rate_limit_violations_total",
+            "Messages dropped for exceeding a rate limit.",
+            limiter.violations(),
+        );
+        counter(
+            &mut out,
+            "clipsync_refused_connections_total",
+            "Connections refused for exceeding a connection or room limit.",
+            limiter.refused(),
+        );
+        counter(
+            &mut out,
+            "clipsync_broadcast_failures_total",
+            "Clips that could not be relayed to a device.",
+            self.broadcast_failures.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "clipsync_idle_timeouts_total",
+            "Connections closed after going silent.",
+            self.idle_timeouts.load(Ordering::Relaxed),
+        );
+
+        let name = "clipsync_join_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Time from Join to Joined.\n# TYPE {name} histogram"
+        );
+        let histogram = &self.join_latency;
+        for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}\n{name}_count {count}");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::RateLimits;
+
+    #[test]
+    fn test_metrics_render_as_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.received("NewClip", 100);
+        metrics.received("NewClip", 50);
+        metrics.sent("Ack", 20);
+        metrics.rejected(ErrorCode::RateLimited);
+        metrics.joined(Duration::from_millis(3));
+        metrics.timed_out();
+
+        let stats = Stats {
+            rooms: 2,
+            clients: 3,
+            history_entries: 4,
+            history_bytes: 5,
+        };
+        let text = metrics.render(&stats, &Limiter::new(RateLimits::default()));
+
+        assert!(text.contains("clipsync_clients 3\n"));
+        assert!(text.contains("clipsync_messages_received_total{type=\"NewClip\"} 2\n"));
+        assert!(text.contains("clipsync_bytes_received_total 150\n"));
+        assert!(text.contains("clipsync_rejected_messages_total{code=\"rate_limited\"} 1\n"));
+        assert!(text.contains("clipsync_idle_timeouts_total 1\n"));
+        assert!(text.contains("clipsync_join_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("clipsync_join_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("clipsync_join_duration_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Violations after which a connection is closed
+pub const MAX_VIOLATIONS: u32 = 10;
+
+/// Limits protecting the WebSocket endpoint from misbehaving clients
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    /// Messages per second from one connection, excluding upload chunks
+    pub messages_per_sec: u32,
+    /// Bytes per second from one connection
+    pub bytes_per_sec: u64,
+    /// Messages per second from all connections of one IP address
+    pub ip_messages_per_sec: u32,
+    /// Bytes per second from all connections of one IP address
+    pub ip_bytes_per_sec: u64,
+    /// Open connections from one IP address
+    pub max_connections_per_ip: usize,
+    /// Devices in one room
+    pub max_clients_per_room: usize,
+    /// How long a new connection may take to send `Join`
+    pub join_timeout: Duration,
+    /// How long a joined connection may stay silent before it is presumed dead
+    pub idle_timeout: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: 20,
+            bytes_per_sec: 16 * 1024 * 1024,
+            ip_messages_per_sec: 60,
+            ip_bytes_per_sec: 64 * 1024 * 1024,
+            max_connections_per_ip: 16,
+            max_clients_per_room: 32,
+            join_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+impl RateLimits {
+    /// How often the server pings a quiet connection, so clients that do
+    /// not send heartbeats of their own still answer before `idle_timeout`
+    pub fn keepalive_interval(&self) -> Duration {
+        (self.idle_timeout / 3).max(Duration::from_secs(1))
+    }
+}
+
+/// A token bucket holding up to one second's worth of tokens
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Change the rate, e.g. after the limits were changed at runtime
+    fn set_rate(&mut self, rate: f64) {
+        self.rate = rate;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// Take `n` tokens if there are enough
+    fn try_take(&mut self, n: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < n {
+            return false;
+        }
+        self.tokens -= n;
+        true
+    }
+
+    /// Take `n` tokens, borrowing if there are not enough, and return how
+    /// long until the debt is paid off
+    fn take(&mut self, n: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= n;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Connections and shared buckets of one IP address
+struct Peer {
+    connections: usize,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+/// Tracks connections and traffic per IP address
+pub struct Limiter {
+    limits: RwLock<RateLimits>,
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+    violations: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl Limiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            peers: Mutex::new(HashMap::new()),
+            violations: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        *self.limits.read().unwrap()
+    }
+
+    /// Replace the limits; open connections adopt the new rates on their
+    /// next message
+    pub fn set_limits(&self, limits: RateLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Rate limit violations since the server started
+    pub fn violations(&self) -> u64 {
+        self.violations.load(Ordering::Relaxed)
+    }
+
+    /// Connections refused since the server started
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    /// Register a new connection from `ip`, or `None` if it has too many
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Option<Connection> {
+        let limits = self.limits();
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(ip).or_insert_with(|| Peer {
+            connections: 0,
+            messages: TokenBucket::new(limits.ip_messages_per_sec as f64),
+            bytes: TokenBucket::new(limits.ip_bytes_per_sec as f64),
+        });
+        if peer.connections >= limits.max_connections_per_ip {
+            let refused = self.refused.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!(
+                "Refusing connection from {}: {} already open ({} refused so far)",
+                ip,
+                peer.connections,
+                refused
+            );
+            return None;
+        }
+        peer.connections += 1;
+
+        Some(Connection {
+            limiter: self.clone(),
+            ip,
+            messages: TokenBucket::new(limits.messages_per_sec as f64),
+            bytes: TokenBucket::new(limits.bytes_per_sec as f64),
+            violations: 0,
+        })
+    }
+
+    /// Count a connection that joined a full room
+    pub fn refuse(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// What to do with a message
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Handle it, after waiting this long to keep to the byte rate
+    Allow(Duration),
+    /// Drop it and tell the client
+    Reject,
+    /// Tell the client and close the connection
+    Close,
+}
+
+/// One open connection, with its own buckets; released when dropped
+pub struct Connection {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+}
+
+impl Connection {
+    /// Account for a received message of `len` bytes. Upload chunks are paced
+    /// by the byte rate alone, so they do not count as messages.
+    pub fn check(&mut self, len: usize, counts_as_message: bool) -> Verdict {
+        let now = Instant::now();
+        let limits = self.limiter.limits();
+        let mut peers = self.limiter.peers.lock().unwrap();
+        let peer = peers
+            .get_mut(&self.ip)
+            .expect("peer is registered while connected");
+        self.messages.set_rate(limits.messages_per_sec as f64);
+        self.bytes.set_rate(limits.bytes_per_sec as f64);
+        peer.messages.set_rate(limits.ip_messages_per_sec as f64);
+        peer.bytes.set_rate(limits.ip_bytes_per_sec as f64);
+
+        let within_rate = !counts_as_message
+            || (self.messages.try_take(1.0, now) && peer.messages.try_take(1.0, now));
+        if !within_rate {
+            drop(peers);
+            self.violations += 1;
+            let total = self.limiter.violations.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!(
+                "Rate limit exceeded by {} ({} on this connection, {} in total)",
+                self.ip,
+                self.violations,
+                total
+            );
+            return if self.violations >= MAX_VIOLATIONS {
+                Verdict::Close
+            } else {
+                Verdict::Reject
+            };
+        }
+
+        // Bytes are throttled rather than refused, so large uploads slow down
+        // instead of failing
+        let len = len as f64;
+        let wait = self.bytes.take(len, now).max(peer.bytes.take(len, now));
+        Verdict::Allow(wait)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut peers = self.limiter.peers.lock().unwrap();
+        if let Some(peer) = peers.get_mut(&self.ip) {
+            peer.connections -= 1;
+            if peer.connections == 0 {
+                peers.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: RateLimits) -> Arc<Limiter> {
+        Arc::new(Limiter::new(limits))
+    }
+
+    #[test]
+    fn test_bucket_refills_at_its_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0);
+        assert!(bucket.try_take(2.0, start));
+        assert!(!bucket.try_take(1.0, start));
+        assert!(bucket.try_take(1.0, start + Duration::from_millis(500)));
+
+        // Borrowing reports how long the debt takes to pay off
+        assert_eq!(
+            bucket.take(4.0, start + Duration::from_millis(500)),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn test_connections_per_ip_are_capped() {
+        let limiter = limiter(RateLimits {
+            max_connections_per_ip: 2,
+            ..RateLimits::default()
+        });
+        let ip: IpAddr = [10, 0, 0, 1].into();
+
+        let first = limiter.connect(ip).unwrap();
+        let _second = limiter.connect(ip).unwrap();
+        assert!(limiter.connect(ip).is_none());
+        assert!(limiter.connect([10, 0, 0, 2].into()).is_some());
+        assert_eq!(limiter.refused(), 1);
+
+        drop(first);
+        assert!(limiter.connect(ip).is_some());
+    }
+
+    #[test]
+    fn test_flooding_connection_is_rejected_then_closed() {
+        let limiter = limiter(RateLimits {
+            messages_per_sec: 5,
+            ..RateLimits::default()
+        });
+        let mut conn = limiter.connect([10, 0, 0, 1].into()).unwrap();
+
+        for _ in 0..5 {
+            assert_eq!(conn.check(100, true), Verdict::Allow(Duration::ZERO));
+        }
+        // Upload chunks are only paced by bytes
+        assert_eq!(conn.check(100, false), Verdict::Allow(Duration::ZERO));
+
+        for _ in 1..MAX_VIOLATIONS {
+            assert_eq!(conn.check(100, true), Verdict::Reject);
+        }
+        assert_eq!(conn.check(100, true), Verdict::Close);
+        assert_eq!(limiter.violations(), MAX_VIOLATIONS as u64);
+    }
+
+    #[test]
+    fn test_connections_of_one_ip_share_its_rate() {
+        let limiter = limiter(RateLimits {
+            ip_messages_per_sec: 3,
+            ..RateLimits::default()
+        });
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let mut a = limiter.connect(ip).unwrap();
+        let mut b = limiter.connect(ip).unwrap();
+
+        assert_eq!(a.check(1, true), Verdict::Allow(Duration::ZERO));
+        assert_eq!(b.check(1, true), Verdict::Allow(Duration::ZERO));
+        assert_eq!(a.check(1, true), Verdict::Allow(Duration::ZERO));
+        assert_eq!(b.check(1, true), Verdict::Reject);
+    }
+
+    #[test]
+    fn test_changed_limits_apply_to_open_connections() {
+        let limiter = limiter(RateLimits {
+            messages_per_sec: 1,
+            ..RateLimits::default()
+        });
+        let mut conn = limiter.connect([10, 0, 0, 1].into()).unwrap();
+        assert_eq!(conn.check(1, true), Verdict::Allow(Duration::ZERO));
+        assert_eq!(conn.check(1, true), Verdict::Reject);
+
+        limiter.set_limits(RateLimits {
+            max_connections_per_ip: 1,
+            ..RateLimits::default()
+        });
+        assert!(limiter.connect([10, 0, 0, 1].into()).is_none());
+        // The bucket refills at the new rate, so a moment later there is room
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(conn.check(1, true), Verdict::Allow(Duration::ZERO));
+    }
+}
@@ -1,65 +1,786 @@
-use clipsync_common::{ClipboardEntry, ServerMessage};
-use std::collections::{HashMap, VecDeque};
-use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use clipsync_common::{
+    from_msgpack, Capabilities, ChunkAssembler, ClipboardEntry, ClipboardItem, Compression,
+    DeviceInfo, ItemFormat, ServerMessage, TransferError, TRANSFER_CHUNK_SIZE,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock as StdRwLock};
+use std::time::Instant;
+use thiserror::Error;
+use tokio::sync::{mpsc, Notify, RwLock};
+
+use crate::store::HistoryStore;
 
 pub type ClientSender = mpsc::UnboundedSender<ServerMessage>;
 
-/// Represents a room where clients with the same secret can sync clipboards
+/// A connected device and what it can handle
+struct Client {
+    info: DeviceInfo,
+    sender: ClientSender,
+    capabilities: Capabilities,
+    /// Woken to end the device's session
+    kick: Arc<Notify>,
+    joined: Instant,
+}
+
+/// Uploads kept per room while waiting for their chunks or their `NewClip`
+const MAX_PENDING_UPLOADS: usize = 4;
+
+/// Byte limits on the history the server keeps in memory
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Largest single item, inline or uploaded
+    pub max_item_size: usize,
+    /// Total size of one room's history
+    pub max_room_bytes: usize,
+    /// Total size of the history of all rooms
+    pub max_total_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_item_size: 64 * 1024 * 1024,
+            max_room_bytes: 256 * 1024 * 1024,
+            max_total_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Most chunks an upload within the item size limit can have
+    pub fn max_chunks(&self) -> u32 {
+        self.max_item_size.div_ceil(TRANSFER_CHUNK_SIZE) as u32
+    }
+}
+
+/// Limits shared by all rooms, with the bytes they currently hold
+#[derive(Debug, Clone, Default)]
+pub struct Quota {
+    limits: Arc<StdRwLock<Limits>>,
+    used: Arc<AtomicUsize>,
+    uploads: Arc<AtomicUsize>,
+}
+
+impl Quota {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits: Arc::new(StdRwLock::new(limits)),
+            used: Arc::default(),
+            uploads: Arc::default(),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        *self.limits.read().unwrap()
+    }
+
+    /// Replace the limits; rooms over the new ones shrink as clips arrive
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Bytes of history held across all rooms
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of history and of uploads not yet in history, across all rooms
+    fn held(&self) -> usize {
+        self.used() + self.uploads.load(Ordering::Relaxed)
+    }
+}
+
+/// Why a clip cannot be stored
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum QuotaError {
+    #[error("item is {size} bytes, more than the limit of {max} bytes")]
+    ItemTooLarge { size: usize, max: usize },
+    #[error("server storage is full")]
+    ServerFull,
+}
+
+/// Bytes an entry takes up, counting the blob a reference stands for
+fn entry_size(entry: &ClipboardEntry) -> usize {
+    entry.item.size() + entry.fallback.as_ref().map_or(0, ClipboardItem::size)
+}
+
+/// Why a room could not be found by a prefix of its hash
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LookupError {
+    #[error("no room matches {0}")]
+    NotFound(String),
+    #[error("more than one room matches {0}")]
+    Ambiguous(String),
+}
+
+/// A room as shown to operators. Rooms are named by the first characters of
+/// their hash, since the full hash is enough to join them.
+#[derive(Debug, Serialize)]
+pub struct RoomSummary {
+    pub room: String,
+    pub clients: usize,
+    pub history_entries: usize,
+    pub history_bytes: usize,
+}
+
+/// A connected device as shown to operators
+#[derive(Debug, Serialize)]
+pub struct DeviceSummary {
+    pub device_id: String,
+    pub name: Option<String>,
+    pub platform: Option<String>,
+    pub protocol_version: u32,
+    pub connected_secs: u64,
+}
+
+/// Which part of a room's history to send, by sequence number
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HistoryQuery {
+    /// Only entries after this one, e.g. the last a reconnecting device saw
+    pub since: Option<u64>,
+    /// Only entries before this one, to page back through history
+    pub before: Option<u64>,
+    /// At most this many of the newest matching entries
+    pub limit: Option<usize>,
+}
+
+/// The first characters of a room hash, enough to tell rooms apart in logs
+/// and the admin API without revealing the hash
+pub fn short_hash(hash: &str) -> &str {
+    hash.get(..8).unwrap_or(hash)
+}
+
+/// Represents a room where clients with the same secret can sync clipboards.
+///
+/// Entries are end-to-end encrypted by the clients; the room only ever stores
+/// and relays ciphertext.
 pub struct Room {
+    /// Secret hash identifying this room
+    hash: String,
     /// Connected clients in this room
-    clients: HashMap<String, ClientSender>,
+    clients: HashMap<String, Client>,
     /// Recent clipboard history (ring buffer)
     history: VecDeque<ClipboardEntry>,
     /// Maximum history size
     max_history: usize,
+    /// Byte limits, shared with the other rooms
+    quota: Quota,
+    /// Bytes held by `history`
+    bytes: usize,
+    /// Bytes held by `uploads` and `uploaded`, counted against the same limits
+    upload_bytes: usize,
+    /// Persistence backend the history is written through to
+    store: Arc<dyn HistoryStore>,
+    /// Chunked uploads still in progress
+    uploads: VecDeque<ChunkAssembler>,
+    /// Completed uploads not yet referenced by a clip
+    uploaded: VecDeque<(String, Arc<Vec<u8>>)>,
+    /// Blobs referenced by history entries
+    blobs: HashMap<String, Arc<Vec<u8>>>,
+    /// Sequence number for the next history entry
+    next_seq: u64,
 }
 
 impl Room {
-    pub fn new(max_history: usize) -> Self {
-        Self {
+    /// Create a room, restoring any history persisted for it
+    pub fn new(
+        hash: String,
+        max_history: usize,
+        quota: Quota,
+        store: Arc<dyn HistoryStore>,
+    ) -> Self {
+        let mut history = Vec::with_capacity(max_history);
+        match store.load(&hash) {
+            Ok(entries) => {
+                let skip = entries.len().saturating_sub(max_history);
+                history.extend(entries.into_iter().skip(skip));
+            }
+            Err(e) => tracing::error!(
+                "Failed to load history for room {}: {}",
+                short_hash(&hash),
+                e
+            ),
+        }
+
+        // Entries stored before sequence numbers existed are numbered in order
+        let mut next_seq = 1;
+        for entry in history.iter_mut() {
+            if entry.seq == 0 {
+                entry.seq = next_seq;
+            }
+            next_seq = next_seq.max(entry.seq + 1);
+        }
+
+        let mut room = Self {
+            hash,
             clients: HashMap::new(),
             history: VecDeque::with_capacity(max_history),
             max_history,
+            quota,
+            bytes: 0,
+            upload_bytes: 0,
+            store,
+            uploads: VecDeque::new(),
+            uploaded: VecDeque::new(),
+            blobs: HashMap::new(),
+            next_seq,
+        };
+        // The limits may have been lowered since the history was stored
+        for entry in history {
+            let size = entry_size(&entry);
+            while !room.history.is_empty() && room.bytes + size > room.quota.limits().max_room_bytes
+            {
+                room.pop_oldest();
+            }
+            room.push(entry);
         }
+        room
+    }
+
+    /// Add a client to the room and tell the others; `kick` is notified to
+    /// end its session
+    pub fn add_client(
+        &mut self,
+        device: DeviceInfo,
+        sender: ClientSender,
+        capabilities: Capabilities,
+        kick: Arc<Notify>,
+    ) {
+        tracing::info!("Device {} joined room", device.device_id);
+        self.notify_others(
+            &device.device_id,
+            ServerMessage::DeviceJoined {
+                device: device.clone(),
+            },
+        );
+        self.clients.insert(
+            device.device_id.clone(),
+            Client {
+                info: device,
+                sender,
+                capabilities,
+                kick,
+                joined: Instant::now(),
+            },
+        );
     }
 
-    /// Add a client to the room
-    pub fn add_client(&mut self, device_id: String, sender: ClientSender) {
-        tracing::info!("Device {} joined room", device_id);
-        self.clients.insert(device_id, sender);
+    pub fn has_client(&self, device_id: &str) -> bool {
+        self.clients.contains_key(device_id)
     }
 
-    /// Remove a client from the room
-    pub fn remove_client(&mut self, device_id: &str) {
+    /// Remove a client from the room and tell the others, unless the device
+    /// has since rejoined on another connection than `sender`'s
+    pub fn remove_client(&mut self, device_id: &str, sender: &ClientSender) {
+        if !self
+            .clients
+            .get(device_id)
+            .is_some_and(|client| client.sender.same_channel(sender))
+        {
+            return;
+        }
         tracing::info!("Device {} left room", device_id);
         self.clients.remove(device_id);
+        self.notify_others(
+            device_id,
+            ServerMessage::DeviceLeft {
+                device_id: device_id.to_string(),
+            },
+        );
     }
 
-    /// Get the current history
-    pub fn get_history(&self) -> Vec<ClipboardEntry> {
-        self.history.iter().cloned().collect()
+    /// The devices in the room other than `device_id`
+    pub fn presence(&self, device_id: &str) -> Vec<DeviceInfo> {
+        let mut devices: Vec<_> = self
+            .clients
+            .iter()
+            .filter(|(id, _)| id.as_str() != device_id)
+            .map(|(_, client)| client.info.clone())
+            .collect();
+        devices.sort_by(|a, b| a.label().cmp(b.label()));
+        devices
     }
 
-    /// Add a clipboard entry to history
-    pub fn add_to_history(&mut self, entry: ClipboardEntry) {
-        if self.history.len() >= self.max_history {
-            self.history.pop_front();
+    fn notify_others(&self, device_id: &str, msg: ServerMessage) {
+        for (id, client) in &self.clients {
+            if id != device_id {
+                let _ = client.sender.send(msg.clone());
+            }
         }
+    }
+
+    /// End a device's session, returning whether it was connected. Its
+    /// connection removes it from the room as it closes.
+    pub fn disconnect_client(&self, device_id: &str) -> bool {
+        let Some(client) = self.clients.get(device_id) else {
+            return false;
+        };
+        tracing::info!(
+            "Disconnecting device {} from room {}",
+            device_id,
+            self.short_hash()
+        );
+        client.kick.notify_one();
+        true
+    }
+
+    /// This room's [`short_hash`]
+    pub fn short_hash(&self) -> &str {
+        short_hash(&self.hash)
+    }
+
+    pub fn summary(&self) -> RoomSummary {
+        RoomSummary {
+            room: self.short_hash().to_string(),
+            clients: self.clients.len(),
+            history_entries: self.history.len(),
+            history_bytes: self.bytes,
+        }
+    }
+
+    /// Connected devices, longest connected first
+    pub fn devices(&self) -> Vec<DeviceSummary> {
+        let mut devices: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(device_id, client)| DeviceSummary {
+                device_id: device_id.clone(),
+                name: client.info.name.clone(),
+                platform: client.info.platform.clone(),
+                protocol_version: client.capabilities.protocol_version,
+                connected_secs: client.joined.elapsed().as_secs(),
+            })
+            .collect();
+        devices.sort_by_key(|device| std::cmp::Reverse(device.connected_secs));
+        devices
+    }
+
+    /// The part of history selected by `query`, as a device with
+    /// `capabilities` can handle it, and whether older matching entries were
+    /// left out because of the limit
+    pub fn history_for(
+        &mut self,
+        capabilities: &Capabilities,
+        query: HistoryQuery,
+    ) -> (Vec<ClipboardEntry>, bool) {
+        // A cursor from before a server restart can be ahead of the room
+        let latest = self.next_seq - 1;
+        let since = query.since.filter(|&since| since <= latest).unwrap_or(0);
+        let matching: Vec<ClipboardEntry> = self
+            .history
+            .iter()
+            .filter(|entry| {
+                entry.seq > since && query.before.is_none_or(|before| entry.seq < before)
+            })
+            .cloned()
+            .collect();
+        let skip = query
+            .limit
+            .map_or(0, |limit| matching.len().saturating_sub(limit));
+
+        let entries = matching[skip..]
+            .iter()
+            .filter_map(|entry| self.deliverable(entry, capabilities))
+            .collect();
+        (entries, skip > 0)
+    }
+
+    /// Downgrade an entry for a device that lacks some capability.
+    ///
+    /// Items are sealed, so the room cannot change their content. Instead it
+    /// relays the sender's fallback in place of a format the device does not
+    /// handle or cannot decompress, inlines references for devices that
+    /// cannot fetch blobs, and skips anything over the device's size limit.
+    fn deliverable(
+        &mut self,
+        entry: &ClipboardEntry,
+        capabilities: &Capabilities,
+    ) -> Option<ClipboardEntry> {
+        let primary = entry
+            .format
+            .is_none_or(|format| capabilities.supports_format(format))
+            .then(|| self.inline_reference(&entry.item, capabilities))
+            .flatten();
+        let item = [primary, entry.fallback.clone()]
+            .into_iter()
+            .flatten()
+            .find(|item| {
+                capabilities.accepts_size(item.size()) && self.can_decompress(item, capabilities)
+            })?;
+
+        Some(ClipboardEntry {
+            item,
+            fallback: None,
+            ..entry.clone()
+        })
+    }
+
+    /// The item itself, or the sealed item a reference stands for if the
+    /// device cannot fetch blobs
+    fn inline_reference(
+        &mut self,
+        item: &ClipboardItem,
+        capabilities: &Capabilities,
+    ) -> Option<ClipboardItem> {
+        match item.reference_hash() {
+            Some(hash) if !capabilities.supports_format(ItemFormat::Reference) => {
+                let blob = self.blob(hash)?;
+                from_msgpack(&blob).ok()
+            }
+            _ => Some(item.clone()),
+        }
+    }
+
+    /// Whether the device can decompress a sealed item, looking inside the
+    /// blob of a reference only when the device lacks some scheme
+    fn can_decompress(&mut self, item: &ClipboardItem, capabilities: &Capabilities) -> bool {
+        if Compression::SUPPORTED
+            .iter()
+            .all(|scheme| capabilities.compression.contains(scheme))
+        {
+            return true;
+        }
+
+        let compression = match item.reference_hash() {
+            Some(hash) => self
+                .blob(hash)
+                .and_then(|blob| from_msgpack::<ClipboardItem>(&blob).ok())
+                .and_then(|sealed| sealed.compression()),
+            None => item.compression(),
+        };
+        compression.is_none_or(|scheme| capabilities.compression.contains(&scheme))
+    }
+
+    /// Whether a clip with this ID is in history
+    pub fn contains(&self, id: &str) -> bool {
+        self.history.iter().any(|entry| entry.id == id)
+    }
+
+    /// Whether `entry` carries the same content hash as the newest history entry
+    pub fn is_latest(&self, entry: &ClipboardEntry) -> bool {
+        match (&entry.content_hash, self.history.back()) {
+            (Some(hash), Some(latest)) => latest.content_hash.as_ref() == Some(hash),
+            _ => false,
+        }
+    }
+
+    /// Evict the oldest entries until `entry` fits within the room's and the
+    /// server's byte limits
+    pub fn make_space(&mut self, entry: &ClipboardEntry) -> Result<(), QuotaError> {
+        let size = entry_size(entry);
+        let limits = self.quota.limits();
+        let max = limits.max_item_size.min(limits.max_room_bytes);
+        if size > max {
+            return Err(QuotaError::ItemTooLarge { size, max });
+        }
+        // A referenced upload is counted already, and moves into history
+        let reserved = entry
+            .item
+            .reference_hash()
+            .and_then(|hash| self.uploaded.iter().find(|(h, _)| h == hash))
+            .map_or(0, |(_, blob)| blob.len());
+        let size = size.saturating_sub(reserved);
+        if self.evict_for(size) {
+            Ok(())
+        } else {
+            Err(QuotaError::ServerFull)
+        }
+    }
+
+    /// Evict the oldest entries until `size` more bytes fit within the room's
+    /// and the server's byte limits, or report that they never will
+    fn evict_for(&mut self, size: usize) -> bool {
+        let limits = self.quota.limits();
+        // Other rooms' history is theirs to keep
+        if self.quota.held() - self.bytes + size > limits.max_total_bytes {
+            return false;
+        }
+
+        while !self.history.is_empty()
+            && (self.bytes + self.upload_bytes + size > limits.max_room_bytes
+                || self.quota.held() + size > limits.max_total_bytes)
+        {
+            self.pop_oldest();
+        }
+        self.prune_blobs();
+        self.bytes + self.upload_bytes + size <= limits.max_room_bytes
+    }
+
+    fn push(&mut self, entry: ClipboardEntry) {
+        let size = entry_size(&entry);
+        self.bytes += size;
+        self.quota.used.fetch_add(size, Ordering::Relaxed);
         self.history.push_back(entry);
     }
 
-    /// Broadcast a clipboard entry to all clients except the sender
-    pub fn broadcast(&self, entry: ClipboardEntry, sender_device_id: &str) {
-        let message = ServerMessage::clip_received(entry);
+    fn pop_oldest(&mut self) {
+        if let Some(entry) = self.history.pop_front() {
+            self.release(entry_size(&entry));
+        }
+    }
+
+    fn release(&mut self, size: usize) {
+        self.bytes -= size;
+        self.quota.used.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn reserve_upload(&mut self, size: usize) {
+        self.upload_bytes += size;
+        self.quota.uploads.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn release_upload(&mut self, size: usize) {
+        self.upload_bytes -= size;
+        self.quota.uploads.fetch_sub(size, Ordering::Relaxed);
+    }
 
-        for (device_id, client) in &self.clients {
-            if device_id != sender_device_id {
-                if let Err(e) = client.send(message.clone()) {
-                    tracing::warn!("Failed to send to device {}: {}", device_id, e);
+    /// Add a clipboard entry to history, returning it with its sequence number
+    pub fn add_to_history(&mut self, mut entry: ClipboardEntry) -> ClipboardEntry {
+        entry.seq = self.next_seq;
+        self.next_seq += 1;
+
+        // A referenced upload becomes part of history
+        let blob = entry.item.reference_hash().and_then(|hash| {
+            let pos = self.uploaded.iter().position(|(h, _)| h == hash);
+            let blob = match pos {
+                Some(pos) => {
+                    let (_, blob) = self.uploaded.remove(pos)?;
+                    self.release_upload(blob.len());
+                    Some(blob)
+                }
+                None => self.blobs.get(hash).cloned(),
+            }?;
+            self.blobs.insert(hash.to_string(), blob.clone());
+            Some(blob)
+        });
+
+        if let Err(e) = self.store.append(
+            &self.hash,
+            &entry,
+            blob.as_deref().map(Vec::as_slice),
+            self.max_history,
+        ) {
+            tracing::error!(
+                "Failed to persist history for room {}: {}",
+                self.short_hash(),
+                e
+            );
+        }
+
+        if self.history.len() >= self.max_history {
+            self.pop_oldest();
+        }
+        self.push(entry.clone());
+        self.prune_blobs();
+        entry
+    }
+
+    /// Delete the room's history, in memory and in the store, returning how
+    /// many entries were in memory
+    pub fn purge_history(&mut self) -> usize {
+        let purged = self.history.len();
+        while !self.history.is_empty() {
+            self.pop_oldest();
+        }
+        self.prune_blobs();
+        if let Err(e) = self.store.purge(&self.hash) {
+            tracing::error!(
+                "Failed to purge stored history for room {}: {}",
+                self.short_hash(),
+                e
+            );
+        }
+        purged
+    }
+
+    /// Drop entries older than `cutoff` (unix milliseconds)
+    pub fn expire_history(&mut self, cutoff: u64) {
+        let mut expired = 0;
+        self.history.retain(|entry| {
+            let keep = entry.timestamp >= cutoff;
+            if !keep {
+                expired += entry_size(entry);
+            }
+            keep
+        });
+        self.release(expired);
+        self.prune_blobs();
+    }
+
+    /// Store one chunk of an upload, returning how many chunks have arrived.
+    ///
+    /// Once every chunk is in and the content matches its hash, the blob waits
+    /// for the `NewClip` that references it.
+    pub fn receive_chunk(
+        &mut self,
+        hash: &str,
+        index: u32,
+        total: u32,
+        data: &[u8],
+    ) -> Result<u32, TransferError> {
+        if self.has_blob(hash) {
+            return Ok(total);
+        }
+
+        // Every chunk but the last is full, so the last one gives the blob's size
+        if total > 0 && index == total - 1 {
+            let size = (total as usize - 1) * TRANSFER_CHUNK_SIZE + data.len();
+            if size > self.quota.limits().max_item_size {
+                self.drop_upload(hash);
+                return Err(TransferError::TooLarge);
+            }
+        }
+
+        let pos = match self.uploads.iter().position(|u| u.hash() == hash) {
+            Some(pos) => pos,
+            None => {
+                if self.uploads.len() >= MAX_PENDING_UPLOADS {
+                    if let Some(evicted) = self.uploads.pop_front() {
+                        self.release_upload(evicted.bytes());
+                    }
                 }
+                self.uploads.push_back(ChunkAssembler::new(hash, total)?);
+                self.uploads.len() - 1
+            }
+        };
+
+        // Upload bytes count against the same limits as history
+        if self.uploads[pos].check(index, total, data)? {
+            if !self.evict_for(data.len()) {
+                return Err(TransferError::TooLarge);
             }
+            self.reserve_upload(data.len());
         }
+
+        let upload = &mut self.uploads[pos];
+        upload.insert(index, total, data)?;
+        let received = upload.received();
+
+        if upload.is_complete() {
+            let upload = self.uploads.remove(pos).expect("position is in bounds");
+            let size = upload.bytes();
+            let blob = match upload.finish() {
+                Ok(blob) => blob,
+                Err(e) => {
+                    self.release_upload(size);
+                    return Err(e);
+                }
+            };
+            if self.uploaded.len() >= MAX_PENDING_UPLOADS {
+                if let Some((_, evicted)) = self.uploaded.pop_front() {
+                    self.release_upload(evicted.len());
+                }
+            }
+            self.uploaded.push_back((hash.to_string(), Arc::new(blob)));
+        }
+        Ok(received)
+    }
+
+    /// Give up on a pending upload, releasing the bytes it holds
+    fn drop_upload(&mut self, hash: &str) {
+        if let Some(pos) = self.uploads.iter().position(|u| u.hash() == hash) {
+            let upload = self.uploads.remove(pos).expect("position is in bounds");
+            self.release_upload(upload.bytes());
+        }
+    }
+
+    /// Chunks of an upload that have not arrived yet
+    pub fn missing_chunks(&self, hash: &str, total: u32) -> Vec<u32> {
+        if self.has_blob(hash) {
+            return Vec::new();
+        }
+        match self.uploads.iter().find(|u| u.hash() == hash) {
+            Some(upload) => upload.missing(),
+            None => (0..total).collect(),
+        }
+    }
+
+    /// Whether a completed blob with this hash is available
+    pub fn has_blob(&self, hash: &str) -> bool {
+        self.blobs.contains_key(hash) || self.uploaded.iter().any(|(h, _)| h == hash)
+    }
+
+    /// Length of a completed blob, if it is available
+    pub fn blob_size(&self, hash: &str) -> Option<usize> {
+        self.blobs
+            .get(hash)
+            .or_else(|| {
+                self.uploaded
+                    .iter()
+                    .find(|(h, _)| h == hash)
+                    .map(|(_, blob)| blob)
+            })
+            .map(|blob| blob.len())
+    }
+
+    /// Look up a blob referenced by history, loading it from the store if needed
+    pub fn blob(&mut self, hash: &str) -> Option<Arc<Vec<u8>>> {
+        if let Some(blob) = self.blobs.get(hash) {
+            return Some(blob.clone());
+        }
+
+        let referenced = self
+            .history
+            .iter()
+            .any(|entry| entry.item.reference_hash() == Some(hash));
+        if !referenced {
+            return None;
+        }
+
+        match self.store.load_blob(&self.hash, hash) {
+            Ok(Some(blob)) => {
+                let blob = Arc::new(blob);
+                self.blobs.insert(hash.to_string(), blob.clone());
+                Some(blob)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                tracing::error!("Failed to load blob for room {}: {}", self.short_hash(), e);
+                None
+            }
+        }
+    }
+
+    /// Forget blobs that no history entry refers to any more
+    fn prune_blobs(&mut self) {
+        let history = &self.history;
+        self.blobs.retain(|hash, _| {
+            history
+                .iter()
+                .any(|entry| entry.item.reference_hash() == Some(hash.as_str()))
+        });
+    }
+
+    /// Broadcast a clipboard entry to all clients except the sender, in the
+    /// form each of them can handle, returning how many could not be reached
+    pub fn broadcast(&mut self, entry: ClipboardEntry, sender_device_id: &str) -> usize {
+        let recipients: Vec<_> = self
+            .clients
+            .iter()
+            .filter(|(device_id, _)| device_id.as_str() != sender_device_id)
+            .map(|(device_id, client)| {
+                (
+                    device_id.clone(),
+                    client.sender.clone(),
+                    client.capabilities.clone(),
+                )
+            })
+            .collect();
+
+        let mut failed = 0;
+        for (device_id, sender, capabilities) in recipients {
+            let Some(entry) = self.deliverable(&entry, &capabilities) else {
+                tracing::debug!("Device {} cannot handle clip, skipping", device_id);
+                continue;
+            };
+            if let Err(e) = sender.send(ServerMessage::clip_received(entry)) {
+                tracing::warn!("Failed to send to device {}: {}", device_id, e);
+                failed += 1;
+            }
+        }
+        failed
     }
 
     /// Check if room is empty
@@ -68,26 +789,68 @@ impl Room {
     }
 
     /// Get number of connected clients
-    #[allow(dead_code)]
     pub fn client_count(&self) -> usize {
         self.clients.len()
     }
 }
 
+impl Drop for Room {
+    fn drop(&mut self) {
+        self.release(self.bytes);
+        self.release_upload(self.upload_bytes);
+    }
+}
+
+/// Totals across all rooms in memory; rooms busy at the time are skipped
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub rooms: usize,
+    pub clients: usize,
+    pub history_entries: usize,
+    pub history_bytes: usize,
+}
+
 /// Manages all rooms
 pub struct RoomManager {
     rooms: Arc<RwLock<HashMap<String, Arc<RwLock<Room>>>>>,
     max_history: usize,
+    quota: Quota,
+    store: Arc<dyn HistoryStore>,
+    /// Hashes of the rooms that may be joined, or `None` for any
+    allowed_rooms: Option<HashSet<String>>,
 }
 
 impl RoomManager {
-    pub fn new(max_history: usize) -> Self {
+    pub fn new(
+        max_history: usize,
+        limits: Limits,
+        store: Arc<dyn HistoryStore>,
+        allowed_rooms: Option<HashSet<String>>,
+    ) -> Self {
         Self {
             rooms: Arc::new(RwLock::new(HashMap::new())),
             max_history,
+            quota: Quota::new(limits),
+            store,
+            allowed_rooms,
         }
     }
 
+    /// Whether the room with this hash may be joined
+    pub fn is_allowed(&self, secret_hash: &str) -> bool {
+        self.allowed_rooms
+            .as_ref()
+            .is_none_or(|rooms| rooms.contains(secret_hash))
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.quota.limits()
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        self.quota.set_limits(limits);
+    }
+
     /// Get or create a room for a given secret hash
     pub async fn get_or_create_room(&self, secret_hash: String) -> Arc<RwLock<Room>> {
         let mut rooms = self.rooms.write().await;
@@ -95,13 +858,67 @@ impl RoomManager {
         rooms
             .entry(secret_hash.clone())
             .or_insert_with(|| {
-                tracing::info!("Creating new room for hash {}", &secret_hash[..8]);
-                Arc::new(RwLock::new(Room::new(self.max_history)))
+                tracing::info!("Creating new room for hash {}", short_hash(&secret_hash));
+                Arc::new(RwLock::new(Room::new(
+                    secret_hash.clone(),
+                    self.max_history,
+                    self.quota.clone(),
+                    self.store.clone(),
+                )))
             })
             .clone()
     }
 
-    /// Clean up empty rooms
+    /// Summaries of the rooms in memory, busiest first
+    pub async fn list_rooms(&self) -> Vec<RoomSummary> {
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        let mut summaries = Vec::with_capacity(rooms.len());
+        for room in rooms {
+            summaries.push(room.read().await.summary());
+        }
+        summaries.sort_by(|a, b| b.clients.cmp(&a.clients).then(a.room.cmp(&b.room)));
+        summaries
+    }
+
+    /// Find a room in memory by a prefix of its hash
+    pub async fn find_room(&self, prefix: &str) -> Result<Arc<RwLock<Room>>, LookupError> {
+        let rooms = self.rooms.read().await;
+        let mut matches = rooms
+            .iter()
+            .filter(|(hash, _)| hash.starts_with(prefix))
+            .map(|(_, room)| room);
+        match (matches.next(), matches.next()) {
+            (Some(room), None) if !prefix.is_empty() => Ok(room.clone()),
+            (None, _) => Err(LookupError::NotFound(prefix.to_string())),
+            _ => Err(LookupError::Ambiguous(prefix.to_string())),
+        }
+    }
+
+    /// Expire history older than `cutoff` (unix milliseconds) in live rooms and the store
+    pub async fn expire_history(&self, cutoff: u64) {
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        for room in rooms {
+            room.write().await.expire_history(cutoff);
+        }
+
+        match self.store.expire(cutoff) {
+            Ok(0) => {}
+            Ok(removed) => tracing::info!("Expired {} stored history entries", removed),
+            Err(e) => tracing::error!("Failed to expire stored history: {}", e),
+        }
+    }
+
+    /// Write persisted history through to disk, e.g. before the server exits
+    pub fn flush_history(&self) {
+        if let Err(e) = self.store.flush() {
+            tracing::error!("Failed to flush stored history: {}", e);
+        }
+    }
+
+    /// Clean up empty rooms.
+    ///
+    /// Only the in-memory room is dropped; its persisted history stays in the
+    /// store and is restored if the room is joined again.
     pub async fn cleanup_empty_rooms(&self) {
         let mut rooms = self.rooms.write().await;
         rooms.retain(|hash, room| {
@@ -114,7 +931,7 @@ impl RoomManager {
             };
 
             if is_empty {
-                tracing::info!("Removing empty room {}", &hash[..8]);
+                tracing::info!("Removing empty room {}", short_hash(hash));
                 false
             } else {
                 true
@@ -123,18 +940,508 @@ impl RoomManager {
     }
 
     /// Get statistics
-    #[allow(dead_code)]
-    pub async fn get_stats(&self) -> (usize, usize) {
+    pub async fn get_stats(&self) -> Stats {
         let rooms = self.rooms.read().await;
-        let room_count = rooms.len();
+        let mut stats = Stats {
+            rooms: rooms.len(),
+            history_bytes: self.quota.used(),
+            ..Stats::default()
+        };
 
-        let mut total_clients = 0;
         for room in rooms.values() {
             if let Ok(r) = room.try_read() {
-                total_clients += r.client_count();
+                stats.clients += r.client_count();
+                stats.history_entries += r.history.len();
             }
         }
 
-        (room_count, total_clients)
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use clipsync_common::{blob_hash, new_clip_id, split_chunks, ClipboardItem};
+
+    fn entry(hash: Option<&str>) -> ClipboardEntry {
+        ClipboardEntry {
+            id: new_clip_id(),
+            seq: 0,
+            item: ClipboardItem::text("ciphertext"),
+            timestamp: 1,
+            device_id: Some("device-a".to_string()),
+            device_name: None,
+            device_platform: None,
+            content_hash: hash.map(str::to_string),
+            format: None,
+            fallback: None,
+        }
+    }
+
+    fn device(device_id: &str) -> DeviceInfo {
+        DeviceInfo {
+            device_id: device_id.to_string(),
+            name: Some(format!("{} laptop", device_id)),
+            platform: Some("linux".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_repeated_latest_clip_is_detected() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        assert!(!room.is_latest(&entry(Some("hash-1"))));
+
+        room.add_to_history(entry(Some("hash-1")));
+        assert!(room.is_latest(&entry(Some("hash-1"))));
+        assert!(!room.is_latest(&entry(Some("hash-2"))));
+
+        // Only the newest entry counts, so going back to older content is allowed
+        room.add_to_history(entry(Some("hash-2")));
+        assert!(!room.is_latest(&entry(Some("hash-1"))));
+    }
+
+    #[test]
+    fn test_retransmitted_clip_is_recognised_by_id() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        let clip = entry(None);
+        assert!(!room.contains(&clip.id));
+
+        room.add_to_history(clip.clone());
+        assert!(room.contains(&clip.id));
+        assert!(!room.contains(&entry(None).id));
+    }
+
+    #[test]
+    fn test_uploaded_blob_follows_its_history_entry() {
+        let mut room = Room::new("a".repeat(64), 1, Quota::default(), Arc::new(MemoryStore));
+        let data = vec![7u8; 600 * 1024];
+        let hash = blob_hash(&data);
+        let chunks = split_chunks(&data);
+        let total = chunks.len() as u32;
+
+        assert_eq!(room.missing_chunks(&hash, total), vec![0, 1, 2]);
+        room.receive_chunk(&hash, 1, total, &chunks[1]).unwrap();
+        assert_eq!(room.missing_chunks(&hash, total), vec![0, 2]);
+        room.receive_chunk(&hash, 0, total, &chunks[0]).unwrap();
+        room.receive_chunk(&hash, 2, total, &chunks[2]).unwrap();
+        assert!(room.has_blob(&hash));
+
+        // Not downloadable until a clip references it
+        assert!(room.blob(&hash).is_none());
+        room.add_to_history(ClipboardEntry {
+            item: ClipboardItem::Reference {
+                hash: hash.clone(),
+                size: data.len() as u64,
+            },
+            ..entry(None)
+        });
+        assert_eq!(room.blob(&hash).unwrap().as_slice(), data.as_slice());
+
+        // Pushed out of history, the blob goes too
+        room.add_to_history(entry(None));
+        assert!(room.blob(&hash).is_none());
+        assert!(!room.has_blob(&hash));
+    }
+
+    #[test]
+    fn test_corrupt_upload_is_rejected() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        let chunks = split_chunks(b"payload");
+
+        assert_eq!(
+            room.receive_chunk("wrong-hash", 0, 1, &chunks[0]),
+            Err(TransferError::HashMismatch)
+        );
+        assert!(!room.has_blob("wrong-hash"));
+        assert_eq!(room.quota.held(), 0);
+    }
+
+    #[test]
+    fn test_uploads_over_the_item_limit_are_rejected() {
+        let limits = Limits {
+            max_item_size: TRANSFER_CHUNK_SIZE + 100,
+            ..Limits::default()
+        };
+        let mut room = Room::new(
+            "a".repeat(64),
+            10,
+            Quota::new(limits),
+            Arc::new(MemoryStore),
+        );
+        let data = vec![7u8; TRANSFER_CHUNK_SIZE + 101];
+        let hash = blob_hash(&data);
+        let chunks = split_chunks(&data);
+
+        room.receive_chunk(&hash, 0, 2, &chunks[0]).unwrap();
+        assert_eq!(
+            room.receive_chunk(&hash, 1, 2, &chunks[1]),
+            Err(TransferError::TooLarge)
+        );
+        assert!(!room.has_blob(&hash));
+
+        let data = &data[..TRANSFER_CHUNK_SIZE + 100];
+        let hash = blob_hash(data);
+        for (index, chunk) in split_chunks(data).iter().enumerate() {
+            room.receive_chunk(&hash, index as u32, 2, chunk).unwrap();
+        }
+        assert!(room.has_blob(&hash));
+    }
+
+    #[test]
+    fn test_uploads_count_against_byte_limits() {
+        let quota = Quota::new(Limits {
+            max_item_size: 3 * TRANSFER_CHUNK_SIZE,
+            max_room_bytes: 3 * TRANSFER_CHUNK_SIZE,
+            max_total_bytes: 4 * TRANSFER_CHUNK_SIZE,
+        });
+        let mut a = Room::new("a".repeat(64), 10, quota.clone(), Arc::new(MemoryStore));
+        let mut b = Room::new("b".repeat(64), 10, quota.clone(), Arc::new(MemoryStore));
+        let text = ClipboardEntry {
+            item: ClipboardItem::text("x".repeat(TRANSFER_CHUNK_SIZE)),
+            ..entry(None)
+        };
+        a.make_space(&text).unwrap();
+        a.add_to_history(text);
+
+        // The last chunk no longer fits beside the history, which makes way
+        let data = vec![7u8; 2 * TRANSFER_CHUNK_SIZE + 10];
+        let hash = blob_hash(&data);
+        for (index, chunk) in split_chunks(&data).iter().enumerate() {
+            a.receive_chunk(&hash, index as u32, 3, chunk).unwrap();
+        }
+        assert!(a.history.is_empty());
+        assert_eq!(quota.used(), 0);
+        assert_eq!(quota.held(), data.len());
+
+        // Another room cannot take what the upload holds
+        let other = vec![8u8; 2 * TRANSFER_CHUNK_SIZE];
+        let chunks = split_chunks(&other);
+        b.receive_chunk("other", 0, 2, &chunks[0]).unwrap();
+        assert_eq!(
+            b.receive_chunk("other", 1, 2, &chunks[1]),
+            Err(TransferError::TooLarge)
+        );
+
+        // Referencing the upload moves its bytes into history
+        let reference = ClipboardEntry {
+            item: ClipboardItem::Reference {
+                hash: hash.clone(),
+                size: data.len() as u64,
+            },
+            ..entry(None)
+        };
+        a.make_space(&reference).unwrap();
+        a.add_to_history(reference);
+        assert_eq!(quota.used(), data.len());
+        assert_eq!(quota.held(), data.len() + TRANSFER_CHUNK_SIZE);
+
+        drop(b);
+        assert_eq!(quota.held(), data.len());
+        drop(a);
+        assert_eq!(quota.held(), 0);
+    }
+
+    #[test]
+    fn test_entries_are_downgraded_per_device() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        let sealed = |len: usize| ClipboardItem::Encrypted {
+            nonce: vec![0; 24],
+            ciphertext: vec![1; len],
+            compression: None,
+        };
+        let rich = ClipboardEntry {
+            item: sealed(100),
+            format: Some(ItemFormat::Rich),
+            fallback: Some(sealed(10)),
+            ..entry(None)
+        };
+        let text_only = Capabilities {
+            formats: vec![ItemFormat::Text],
+            ..Capabilities::current()
+        };
+
+        let full = room.deliverable(&rich, &Capabilities::current()).unwrap();
+        assert_eq!(full.item, sealed(100));
+        assert_eq!(full.fallback, None);
+        assert_eq!(
+            room.deliverable(&rich, &text_only).unwrap().item,
+            sealed(10)
+        );
+
+        let no_fallback = ClipboardEntry {
+            fallback: None,
+            ..rich.clone()
+        };
+        assert!(room.deliverable(&no_fallback, &text_only).is_none());
+
+        let small = Capabilities {
+            max_item_size: Some(50),
+            ..Capabilities::current()
+        };
+        assert!(room.deliverable(&no_fallback, &small).is_none());
+
+        let compressed = ClipboardEntry {
+            item: ClipboardItem::Encrypted {
+                nonce: vec![0; 24],
+                ciphertext: vec![1; 100],
+                compression: Some(Compression::Zstd),
+            },
+            ..rich.clone()
+        };
+        let uncompressed = Capabilities {
+            compression: Vec::new(),
+            ..Capabilities::current()
+        };
+        assert_eq!(
+            room.deliverable(&compressed, &uncompressed).unwrap().item,
+            sealed(10)
+        );
+    }
+
+    #[test]
+    fn test_references_are_inlined_for_devices_without_blob_support() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        let item = ClipboardItem::Encrypted {
+            nonce: vec![0; 24],
+            ciphertext: vec![1; 1000],
+            compression: None,
+        };
+        let data = clipsync_common::to_msgpack(&item).unwrap();
+        let hash = blob_hash(&data);
+        for (index, chunk) in split_chunks(&data).iter().enumerate() {
+            room.receive_chunk(&hash, index as u32, 1, chunk).unwrap();
+        }
+        room.add_to_history(ClipboardEntry {
+            item: ClipboardItem::Reference {
+                hash: hash.clone(),
+                size: data.len() as u64,
+            },
+            ..entry(None)
+        });
+
+        let legacy = Capabilities {
+            formats: vec![ItemFormat::Text, ItemFormat::Image],
+            ..Capabilities::current()
+        };
+        assert_eq!(
+            room.history_for(&legacy, HistoryQuery::default()).0[0].item,
+            item
+        );
+        assert_eq!(
+            room.history_for(&Capabilities::current(), HistoryQuery::default())
+                .0[0]
+                .item
+                .reference_hash(),
+            Some(hash.as_str())
+        );
+    }
+
+    #[test]
+    fn test_history_is_sent_from_a_cursor_and_in_pages() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        for _ in 0..5 {
+            room.add_to_history(entry(None));
+        }
+        let caps = Capabilities::current();
+        let seqs = |(entries, more): (Vec<ClipboardEntry>, bool)| {
+            (entries.iter().map(|e| e.seq).collect::<Vec<_>>(), more)
+        };
+
+        let query = |since, before, limit| HistoryQuery {
+            since,
+            before,
+            limit,
+        };
+        assert_eq!(
+            seqs(room.history_for(&caps, query(None, None, None))),
+            (vec![1, 2, 3, 4, 5], false)
+        );
+        assert_eq!(
+            seqs(room.history_for(&caps, query(Some(3), None, None))),
+            (vec![4, 5], false)
+        );
+        assert_eq!(
+            seqs(room.history_for(&caps, query(None, None, Some(2)))),
+            (vec![4, 5], true)
+        );
+        assert_eq!(
+            seqs(room.history_for(&caps, query(None, Some(4), Some(2)))),
+            (vec![2, 3], true)
+        );
+
+        // A cursor the room has never reached predates a restart; send everything
+        assert_eq!(
+            seqs(room.history_for(&caps, query(Some(99), None, None)))
+                .0
+                .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_sequence_numbers_continue_after_reload() {
+        let store = Arc::new(crate::store::SqliteStore::open_in_memory().unwrap());
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), store.clone());
+        room.add_to_history(entry(None));
+        room.add_to_history(entry(None));
+
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), store);
+        assert_eq!(room.add_to_history(entry(None)).seq, 3);
+    }
+
+    #[test]
+    fn test_history_is_evicted_by_bytes_within_quotas() {
+        let quota = Quota::new(Limits {
+            max_item_size: 100,
+            max_room_bytes: 250,
+            max_total_bytes: 400,
+        });
+        let sized = |len| ClipboardEntry {
+            item: ClipboardItem::text("x".repeat(len)),
+            ..entry(None)
+        };
+        let mut a = Room::new("a".repeat(64), 10, quota.clone(), Arc::new(MemoryStore));
+        let mut b = Room::new("b".repeat(64), 10, quota.clone(), Arc::new(MemoryStore));
+        let mut c = Room::new("c".repeat(64), 10, quota.clone(), Arc::new(MemoryStore));
+
+        assert_eq!(
+            a.make_space(&sized(101)),
+            Err(QuotaError::ItemTooLarge {
+                size: 101,
+                max: 100
+            })
+        );
+
+        // The third entry pushes the oldest out of the room's 250 bytes
+        for _ in 0..3 {
+            a.make_space(&sized(100)).unwrap();
+            a.add_to_history(sized(100));
+        }
+        assert_eq!(a.history.len(), 2);
+        assert_eq!(quota.used(), 200);
+
+        for _ in 0..2 {
+            b.make_space(&sized(100)).unwrap();
+            b.add_to_history(sized(100));
+        }
+        assert_eq!(quota.used(), 400);
+
+        // Rooms only ever make space from their own history
+        assert_eq!(c.make_space(&sized(100)), Err(QuotaError::ServerFull));
+        drop(a);
+        assert_eq!(quota.used(), 200);
+        assert_eq!(c.make_space(&sized(100)), Ok(()));
+    }
+
+    #[test]
+    fn test_clips_without_hash_are_never_dropped() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        room.add_to_history(entry(None));
+        assert!(!room.is_latest(&entry(None)));
+    }
+
+    #[tokio::test]
+    async fn test_rooms_are_found_by_hash_prefix_and_purged() {
+        let manager = RoomManager::new(10, Limits::default(), Arc::new(MemoryStore), None);
+        let a = manager
+            .get_or_create_room(format!("ab12{}", "0".repeat(60)))
+            .await;
+        manager
+            .get_or_create_room(format!("ab34{}", "0".repeat(60)))
+            .await;
+
+        assert!(Arc::ptr_eq(&manager.find_room("ab12").await.unwrap(), &a));
+        assert!(matches!(
+            manager.find_room("ab").await,
+            Err(LookupError::Ambiguous(_))
+        ));
+        assert!(matches!(
+            manager.find_room("cd").await,
+            Err(LookupError::NotFound(_))
+        ));
+
+        let mut room = a.write().await;
+        assert_eq!(room.short_hash(), "ab120000");
+        assert_eq!(short_hash("ab12"), "ab12");
+        assert_eq!(short_hash("abcdefgé"), "abcdefgé");
+        room.add_to_history(entry(None));
+        assert_eq!(room.summary().history_entries, 1);
+        assert_eq!(room.purge_history(), 1);
+        assert_eq!(room.summary().history_bytes, 0);
+        assert_eq!(manager.quota.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_disconnected_device_is_notified() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        let kick = Arc::new(Notify::new());
+        let (tx, _rx) = mpsc::unbounded_channel();
+        room.add_client(
+            device("device-a"),
+            tx,
+            Capabilities::current(),
+            kick.clone(),
+        );
+
+        assert!(!room.disconnect_client("device-b"));
+        assert!(room.disconnect_client("device-a"));
+        // The permit is kept until the connection waits for it
+        tokio::time::timeout(std::time::Duration::from_secs(1), kick.notified())
+            .await
+            .unwrap();
+        assert_eq!(room.devices()[0].device_id, "device-a");
+    }
+
+    #[test]
+    fn test_devices_are_told_who_joins_and_leaves() {
+        let mut room = Room::new("a".repeat(64), 10, Quota::default(), Arc::new(MemoryStore));
+        let kick = Arc::new(Notify::new());
+        let (a_tx, mut a_rx) = mpsc::unbounded_channel();
+        let (b_tx, mut b_rx) = mpsc::unbounded_channel();
+        room.add_client(device("a"), a_tx, Capabilities::current(), kick.clone());
+        room.add_client(
+            device("b"),
+            b_tx.clone(),
+            Capabilities::current(),
+            kick.clone(),
+        );
+
+        assert!(matches!(
+            a_rx.try_recv(),
+            Ok(ServerMessage::DeviceJoined { device }) if device.device_id == "b"
+        ));
+        assert!(b_rx.try_recv().is_err());
+        assert_eq!(room.presence("b"), vec![device("a")]);
+
+        // A stale connection of a device that has rejoined does not remove it
+        let (old_tx, _old_rx) = mpsc::unbounded_channel();
+        room.remove_client("b", &old_tx);
+        assert!(room.has_client("b"));
+        assert!(a_rx.try_recv().is_err());
+
+        room.remove_client("b", &b_tx);
+        assert!(matches!(
+            a_rx.try_recv(),
+            Ok(ServerMessage::DeviceLeft { device_id }) if device_id == "b"
+        ));
+        assert!(room.presence("a").is_empty());
+    }
+
+    #[test]
+    fn test_only_allowed_rooms_can_be_joined() {
+        let allowed = "a".repeat(64);
+        let manager = RoomManager::new(
+            10,
+            Limits::default(),
+            Arc::new(MemoryStore),
+            Some(HashSet::from([allowed.clone()])),
+        );
+        assert!(manager.is_allowed(&allowed));
+        assert!(!manager.is_allowed(&"b".repeat(64)));
+
+        let open = RoomManager::new(10, Limits::default(), Arc::new(MemoryStore), None);
+        assert!(open.is_allowed(&"b".repeat(64)));
     }
 }
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Coordinates a graceful shutdown between `main` and the open connections.
+///
+/// Once it begins, every connection tells its client to come back later and
+/// closes; `main` waits for them to drain before flushing history and exiting.
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+    open: watch::Sender<usize>,
+    /// How long clients are asked to wait before reconnecting
+    reconnect_after: Duration,
+}
+
+impl Shutdown {
+    pub fn new(reconnect_after: Duration) -> Self {
+        Self {
+            started: watch::Sender::new(false),
+            open: watch::Sender::new(0),
+            reconnect_after,
+        }
+    }
+
+    pub fn reconnect_after(&self) -> Duration {
+        self.reconnect_after
+    }
+
+    pub fn begin(&self) {
+        self.started.send_replace(true);
+    }
+
+    /// Resolve once the shutdown has begun
+    pub async fn started(&self) {
+        let mut started = self.started.subscribe();
+        let _ = started.wait_for(|started| *started).await;
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Count a connection as open until the returned guard is dropped
+    pub fn track(self: &Arc<Self>) -> ConnectionGuard {
+        self.open.send_modify(|open| *open += 1);
+        ConnectionGuard(self.clone())
+    }
+
+    pub fn open_connections(&self) -> usize {
+        *self.open.borrow()
+    }
+
+    /// Resolve once no connections are open
+    pub async fn drained(&self) {
+        let mut open = self.open.subscribe();
+        let _ = open.wait_for(|open| *open == 0).await;
+    }
+}
+
+/// Keeps a connection counted as open; see [`Shutdown::track`]
+pub struct ConnectionGuard(Arc<Shutdown>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.open.send_modify(|open| *open -= 1);
+    }
+}
+
+/// Resolve on SIGINT (Ctrl+C) or, on Unix, SIGTERM as sent by Docker and systemd
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received Ctrl+C"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drains_once_every_connection_closed() {
+        let shutdown = Arc::new(Shutdown::new(Duration::from_secs(5)));
+        let first = shutdown.track();
+        let second = shutdown.track();
+        assert_eq!(shutdown.open_connections(), 2);
+
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown.started().await;
+                shutdown.drained().await;
+            }
+        });
+        shutdown.begin();
+        assert!(shutdown.is_started());
+
+        drop(first);
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        drop(second);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(shutdown.open_connections(), 0);
+    }
+}
@@ -0,0 +1,17 @@
+use std::sync::Arc;
+
+use crate::metrics::Metrics;
+use crate::ratelimit::Limiter;
+use crate::room::RoomManager;
+use crate::shutdown::Shutdown;
+
+/// State shared by all request handlers
+#[derive(Clone)]
+pub struct AppState {
+    pub rooms: Arc<RoomManager>,
+    pub limiter: Arc<Limiter>,
+    pub metrics: Arc<Metrics>,
+    pub shutdown: Arc<Shutdown>,
+    /// Whether clients offering permessage-deflate get it
+    pub ws_deflate: bool,
+}
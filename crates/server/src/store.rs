@@ -0,0 +1,399 @@
+use clipsync_common::ClipboardEntry;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Persistence backend for room history.
+///
+/// Rooms keep their live ring buffer in memory and write every new entry
+/// through to the store, so history can be reloaded when a room is recreated
+/// after it emptied out or after a server restart.
+pub trait HistoryStore: Send + Sync {
+    /// Load the persisted history for a room, oldest first
+    fn load(&self, room_hash: &str) -> anyhow::Result<Vec<ClipboardEntry>>;
+
+    /// Persist an entry, together with the blob it references if any, keeping
+    /// only the newest `max_history` entries (and their blobs) for the room
+    fn append(
+        &self,
+        room_hash: &str,
+        entry: &ClipboardEntry,
+        blob: Option<&[u8]>,
+        max_history: usize,
+    ) -> anyhow::Result<()>;
+
+    /// Load a blob referenced by the room's history
+    fn load_blob(&self, room_hash: &str, hash: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Delete entries older than `cutoff` (unix milliseconds) in every room
+    fn expire(&self, cutoff: u64) -> anyhow::Result<usize>;
+
+    /// Delete all of a room's entries and blobs
+    fn purge(&self, room_hash: &str) -> anyhow::Result<usize>;
+
+    /// Make sure everything written so far is on disk, before the server exits
+    fn flush(&self) -> anyhow::Result<()>;
+}
+
+/// History backend selected by configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryBackend {
+    /// Keep history only in live rooms (lost when a room empties or the server restarts)
+    Memory,
+    /// Persist history to an SQLite database file
+    Sqlite(String),
+}
+
+impl HistoryBackend {
+    /// Parse a backend name as given in `CLIPSYNC_HISTORY_BACKEND`
+    pub fn parse(name: &str, db_path: String) -> anyhow::Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "sqlite" => Ok(Self::Sqlite(db_path)),
+            other => Err(anyhow::anyhow!("Unknown history backend: {}", other)),
+        }
+    }
+
+    /// Open the configured store
+    pub fn open(&self) -> anyhow::Result<Arc<dyn HistoryStore>> {
+        Ok(match self {
+            Self::Memory => Arc::new(MemoryStore),
+            Self::Sqlite(path) => Arc::new(SqliteStore::open(path)?),
+        })
+    }
+}
+
+/// Store that persists nothing; history lives only in the room's own buffer
+pub struct MemoryStore;
+
+impl HistoryStore for MemoryStore {
+    fn load(&self, _room_hash: &str) -> anyhow::Result<Vec<ClipboardEntry>> {
+        Ok(Vec::new())
+    }
+
+    fn append(
+        &self,
+        _room_hash: &str,
+        _entry: &ClipboardEntry,
+        _blob: Option<&[u8]>,
+        _max_history: usize,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_blob(&self, _room_hash: &str, _hash: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn expire(&self, _cutoff: u64) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    fn purge(&self, _room_hash: &str) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// SQLite-backed history store
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path`
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::init(conn)
+    }
+
+    /// Open a throwaway in-memory database
+    #[cfg(test)]
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> anyhow::Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                room TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                entry TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS history_room ON history (room, id);
+            CREATE INDEX IF NOT EXISTS history_timestamp ON history (timestamp);
+            CREATE TABLE IF NOT EXISTS blobs (
+                room TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (room, hash)
+            );",
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Delete blobs no longer referenced by any history entry of their room
+    fn prune_blobs(conn: &Connection) -> rusqlite::Result<usize> {
+        conn.execute(
+            "DELETE FROM blobs WHERE NOT EXISTS (
+                SELECT 1 FROM history
+                WHERE history.room = blobs.room
+                  AND json_extract(history.entry, '$.item.type') = 'Reference'
+                  AND json_extract(history.entry, '$.item.data.hash') = blobs.hash
+            )",
+            [],
+        )
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic while holding the lock cannot leave SQLite itself inconsistent
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl HistoryStore for SqliteStore {
+    fn load(&self, room_hash: &str) -> anyhow::Result<Vec<ClipboardEntry>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT entry FROM history WHERE room = ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![room_hash], |row| row.get::<_, String>(0))?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            match serde_json::from_str(&row?) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => tracing::warn!("Skipping unreadable history entry: {}", e),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn append(
+        &self,
+        room_hash: &str,
+        entry: &ClipboardEntry,
+        blob: Option<&[u8]>,
+        max_history: usize,
+    ) -> anyhow::Result<()> {
+        let json = serde_json::to_string(entry)?;
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        if let (Some(hash), Some(data)) = (entry.item.reference_hash(), blob) {
+            tx.execute(
+                "INSERT OR IGNORE INTO blobs (room, hash, data) VALUES (?1, ?2, ?3)",
+                params![room_hash, hash, data],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO history (room, timestamp, entry) VALUES (?1, ?2, ?3)",
+            params![room_hash, entry.timestamp as i64, json],
+        )?;
+        tx.execute(
+            "DELETE FROM history WHERE room = ?1 AND id NOT IN (
+                SELECT id FROM history WHERE room = ?1 ORDER BY id DESC LIMIT ?2
+            )",
+            params![room_hash, max_history as i64],
+        )?;
+        Self::prune_blobs(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn load_blob(&self, room_hash: &str, hash: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let conn = self.conn();
+        let blob = conn
+            .query_row(
+                "SELECT data FROM blobs WHERE room = ?1 AND hash = ?2",
+                params![room_hash, hash],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(blob)
+    }
+
+    fn expire(&self, cutoff: u64) -> anyhow::Result<usize> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let removed = tx.execute(
+            "DELETE FROM history WHERE timestamp < ?1",
+            params![cutoff as i64],
+        )?;
+        Self::prune_blobs(&tx)?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    fn purge(&self, room_hash: &str) -> anyhow::Result<usize> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let removed = tx.execute("DELETE FROM history WHERE room = ?1", params![room_hash])?;
+        tx.execute("DELETE FROM blobs WHERE room = ?1", params![room_hash])?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// Entries are committed as they are appended; this moves them from the
+    /// write-ahead log into the database file, so the file alone is complete
+    fn flush(&self) -> anyhow::Result<()> {
+        self.conn()
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clipsync_common::{new_clip_id, ClipboardItem};
+
+    fn entry(text: &str, timestamp: u64) -> ClipboardEntry {
+        ClipboardEntry {
+            id: new_clip_id(),
+            seq: 0,
+            item: ClipboardItem::text(text),
+            timestamp,
+            device_id: Some("device-a".to_string()),
+            device_name: None,
+            device_platform: None,
+            content_hash: None,
+            format: None,
+            fallback: None,
+        }
+    }
+
+    #[test]
+    fn test_sqlite_keeps_newest_entries_per_room() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        for i in 0..5 {
+            store
+                .append("room-a", &entry(&format!("a{}", i), i), None, 3)
+                .unwrap();
+        }
+        store.append("room-b", &entry("b0", 0), None, 3).unwrap();
+
+        let texts: Vec<_> = store
+            .load("room-a")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.item)
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                ClipboardItem::text("a2"),
+                ClipboardItem::text("a3"),
+                ClipboardItem::text("a4"),
+            ]
+        );
+        assert_eq!(store.load("room-b").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_survives_reopen() {
+        let path =
+            std::env::temp_dir().join(format!("clipsync-store-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let persisted = entry("persisted", 1);
+
+        {
+            let store = SqliteStore::open(&path).unwrap();
+            store.append("room-a", &persisted, None, 10).unwrap();
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        let history = store.load("room-a").unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].item, ClipboardItem::text("persisted"));
+        assert_eq!(history[0].id, persisted.id);
+
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sqlite_flush_empties_the_write_ahead_log() {
+        let path =
+            std::env::temp_dir().join(format!("clipsync-flush-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let wal = path.with_extension("db-wal");
+
+        let store = SqliteStore::open(&path).unwrap();
+        store.append("room-a", &entry("a", 1), None, 10).unwrap();
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+        store.flush().unwrap();
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sqlite_expires_old_entries() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store
+            .append("room-a", &entry("old", 100), None, 10)
+            .unwrap();
+        store
+            .append("room-a", &entry("new", 200), None, 10)
+            .unwrap();
+
+        assert_eq!(store.expire(150).unwrap(), 1);
+        let history = store.load("room-a").unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].item, ClipboardItem::text("new"));
+    }
+
+    #[test]
+    fn test_sqlite_purges_one_room() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        store.append("room-a", &entry("a0", 1), None, 10).unwrap();
+        store.append("room-a", &entry("a1", 2), None, 10).unwrap();
+        store.append("room-b", &entry("b0", 1), None, 10).unwrap();
+
+        assert_eq!(store.purge("room-a").unwrap(), 2);
+        assert!(store.load("room-a").unwrap().is_empty());
+        assert_eq!(store.load("room-b").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_drops_blobs_with_their_entries() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let reference = |hash: &str| ClipboardEntry {
+            item: ClipboardItem::Reference {
+                hash: hash.to_string(),
+                size: 4,
+            },
+            ..entry("unused", 1)
+        };
+
+        store
+            .append("room-a", &reference("blob-1"), Some(b"one!"), 1)
+            .unwrap();
+        assert_eq!(
+            store.load_blob("room-a", "blob-1").unwrap(),
+            Some(b"one!".to_vec())
+        );
+        assert_eq!(store.load_blob("room-b", "blob-1").unwrap(), None);
+
+        // Trimming the referencing entry out of history takes the blob with it
+        store
+            .append("room-a", &reference("blob-2"), Some(b"two!"), 1)
+            .unwrap();
+        assert_eq!(store.load_blob("room-a", "blob-1").unwrap(), None);
+        assert!(store.load_blob("room-a", "blob-2").unwrap().is_some());
+    }
+}
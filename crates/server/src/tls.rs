@@ -0,0 +1,56 @@
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Load the certificate and key, then keep reloading them whenever either file changes
+pub async fn load(cert: PathBuf, key: PathBuf) -> anyhow::Result<RustlsConfig> {
+    let config = RustlsConfig::from_pem_file(&cert, &key)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load TLS certificate: {}", e))?;
+    tracing::info!("Loaded TLS certificate from {}", cert.display());
+
+    tokio::spawn(watch_for_changes(config.clone(), cert, key));
+    Ok(config)
+}
+
+/// Make sure the certificate and key can be loaded, without serving them
+pub async fn check(cert: &Path, key: &Path) -> anyhow::Result<()> {
+    RustlsConfig::from_pem_file(cert, key)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to load TLS certificate: {}", e))?;
+    Ok(())
+}
+
+/// Reload the TLS configuration when the certificate or key is replaced on disk
+async fn watch_for_changes(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let mut last_modified = modified(&cert, &key);
+    let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let current = modified(&cert, &key);
+        if current == last_modified {
+            continue;
+        }
+
+        // Keep serving the old certificate if the new files are incomplete or invalid;
+        // the next check retries once they settle
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => {
+                tracing::info!("Reloaded TLS certificate from {}", cert.display());
+                last_modified = current;
+            }
+            Err(e) => tracing::warn!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
+
+fn modified(cert: &Path, key: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (mtime(cert), mtime(key))
+}
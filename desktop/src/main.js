@@ -22,13 +22,20 @@ function initializeApp() {
     const statusText = document.getElementById('statusText');
     const serverUrlInput = document.getElementById('serverUrl');
     const sharedSecretInput = document.getElementById('sharedSecret');
+    const tlsCaBundleInput = document.getElementById('tlsCaBundle');
+    const tlsPinnedSha256Input = document.getElementById('tlsPinnedSha256');
     const connectBtn = document.getElementById('connectBtn');
     const disconnectBtn = document.getElementById('disconnectBtn');
     const pauseBtn = document.getElementById('pauseBtn');
     const historyList = document.getElementById('historyList');
     const messageArea = document.getElementById('messageArea');
+    const transferProgress = document.getElementById('transferProgress');
+    const deviceList = document.getElementById('deviceList');
+    const latencyText = document.getElementById('latencyText');
+    const deviceNameInput = document.getElementById('deviceName');
     const autostartCheck = document.getElementById('autostartCheck');
     const startMinimizedCheck = document.getElementById('startMinimizedCheck');
+    const inboundWhilePausedSelect = document.getElementById('inboundWhilePausedSelect');
     const hideBtn = document.getElementById('hideBtn');
     
     console.log('Connect button found:', !!connectBtn);
@@ -43,8 +50,15 @@ async function loadConfig() {
         if (config.shared_secret) {
             sharedSecretInput.value = config.shared_secret;
         }
+        tlsCaBundleInput.value = config.tls_ca_bundle || '';
+        tlsPinnedSha256Input.value = config.tls_pinned_sha256 || '';
         autostartCheck.checked = config.autostart || false;
         startMinimizedCheck.checked = config.start_minimized || false;
+        inboundWhilePausedSelect.value = config.inbound_while_paused || 'drop';
+        setPaused(config.paused || false);
+        const device = await invoke('get_device');
+        deviceNameInput.value = config.device_name || '';
+        deviceNameInput.placeholder = device.name;
         return config;
     } catch (e) {
         console.error('Failed to load config:', e);
@@ -65,11 +79,50 @@ function showMessage(text, type = 'success') {
     }, 3000);
 }
 
+// What to call a device: its name, or its ID if it has none
+function deviceLabel(name, deviceId) {
+    return name || deviceId || 'unknown device';
+}
+
+// Show which other devices are connected to the room
+function updateDevices(devices) {
+    deviceList.hidden = !isConnected;
+    if (devices.length === 0) {
+        deviceList.textContent = 'No other devices online';
+        return;
+    }
+    const names = devices.map(d => d.platform ? `${deviceLabel(d.name, d.device_id)} (${d.platform})` : deviceLabel(d.name, d.device_id));
+    deviceList.textContent = `Online: ${names.join(', ')}`;
+}
+
+// Reflect pause state in the UI
+function setPaused(paused) {
+    isPaused = paused;
+    pauseBtn.textContent = isPaused ? 'Resume Sync' : 'Pause Sync';
+}
+
 // Update status
-function updateStatus(connected) {
+function updateStatus(connected, reconnectStatus = null) {
     isConnected = connected;
+    statusIndicator.classList.remove('reconnecting');
+    if (!connected) {
+        deviceList.hidden = true;
+        latencyText.hidden = true;
+    }
     
-    if (connected) {
+    if (reconnectStatus) {
+        // Still syncing, just waiting for the server to come back
+        statusIndicator.classList.remove('connected');
+        statusIndicator.classList.add('reconnecting');
+        statusText.textContent = reconnectStatus.charAt(0).toUpperCase() + reconnectStatus.slice(1);
+        connectBtn.disabled = true;
+        disconnectBtn.disabled = false;
+        pauseBtn.disabled = false;
+        serverUrlInput.disabled = true;
+        sharedSecretInput.disabled = true;
+        tlsCaBundleInput.disabled = true;
+        tlsPinnedSha256Input.disabled = true;
+    } else if (connected) {
         statusIndicator.classList.add('connected');
         statusText.textContent = 'Connected';
         connectBtn.disabled = true;
@@ -77,6 +130,8 @@ function updateStatus(connected) {
         pauseBtn.disabled = false;
         serverUrlInput.disabled = true;
         sharedSecretInput.disabled = true;
+        tlsCaBundleInput.disabled = true;
+        tlsPinnedSha256Input.disabled = true;
     } else {
         statusIndicator.classList.remove('connected');
         statusText.textContent = 'Disconnected';
@@ -85,11 +140,19 @@ function updateStatus(connected) {
         pauseBtn.disabled = true;
         serverUrlInput.disabled = false;
         sharedSecretInput.disabled = false;
+        tlsCaBundleInput.disabled = false;
+        tlsPinnedSha256Input.disabled = false;
     }
 }
 
+// Flavor bytes arrive base64-encoded; decode a textual flavor for display
+function flavorText(flavor) {
+    const bytes = Uint8Array.from(atob(flavor.data), (c) => c.charCodeAt(0));
+    return new TextDecoder().decode(bytes);
+}
+
 // Add history item to UI
-function addHistoryItem(item, timestamp) {
+function addHistoryItem(item, timestamp, source) {
     if (historyList.querySelector('p')) {
         historyList.innerHTML = '';
     }
@@ -103,12 +166,27 @@ function addHistoryItem(item, timestamp) {
     } else if (item.type === 'Image') {
         div.className += ' image';
         div.textContent = `[Image: ${item.data.mime_type}]`;
+    } else if (item.type === 'Reference') {
+        div.textContent = `[Large item: ${Math.round(item.data.size / 1024)} KB]`;
+    } else if (item.type === 'Files') {
+        div.textContent = `[Files: ${item.data.map((file) => file.name).join(', ')}]`;
+    } else if (item.type === 'Rich') {
+        const plain = item.data.find((flavor) => flavor.mime_type === 'text/plain');
+        const formats = item.data.map((flavor) => flavor.mime_type).join(', ');
+        if (plain) {
+            const text = flavorText(plain);
+            const preview = text.length > 100 ? text.substring(0, 100) + '...' : text;
+            div.textContent = `${preview} [${formats}]`;
+        } else {
+            div.className += ' image';
+            div.textContent = `[${formats}]`;
+        }
     }
     
     const time = new Date(timestamp);
     const timeDiv = document.createElement('div');
     timeDiv.className = 'timestamp';
-    timeDiv.textContent = time.toLocaleTimeString();
+    timeDiv.textContent = source ? `${time.toLocaleTimeString()} from ${source}` : time.toLocaleTimeString();
     div.appendChild(timeDiv);
     
     historyList.insertBefore(div, historyList.firstChild);
@@ -126,6 +204,8 @@ function addHistoryItem(item, timestamp) {
         alert('Button clicked!'); // Debug
         const serverUrl = serverUrlInput.value.trim();
         const sharedSecret = sharedSecretInput.value.trim();
+        const tlsCaBundle = tlsCaBundleInput.value.trim() || null;
+        const tlsPinnedSha256 = tlsPinnedSha256Input.value.trim() || null;
         
         console.log('Server URL:', serverUrl);
         console.log('Has secret:', !!sharedSecret);
@@ -137,7 +217,7 @@ function addHistoryItem(item, timestamp) {
         
         try {
             console.log('Calling connect_to_server...');
-            await invoke('connect_to_server', { serverUrl, sharedSecret });
+            await invoke('connect_to_server', { serverUrl, sharedSecret, tlsCaBundle, tlsPinnedSha256 });
             console.log('Connect successful');
             updateStatus(true);
             showMessage('Connected successfully');
@@ -161,9 +241,9 @@ function addHistoryItem(item, timestamp) {
     // Toggle pause
     pauseBtn.addEventListener('click', async () => {
     try {
-        isPaused = !isPaused;
-        await invoke('toggle_sync', { paused: isPaused });
-        pauseBtn.textContent = isPaused ? 'Resume Sync' : 'Pause Sync';
+        const paused = !isPaused;
+        await invoke('toggle_sync', { paused });
+        setPaused(paused);
         showMessage(isPaused ? 'Sync paused' : 'Sync resumed');
     } catch (e) {
         showMessage(`Toggle failed: ${e}`, 'error');
@@ -201,6 +281,26 @@ function addHistoryItem(item, timestamp) {
     }
 });
 
+    // Device name, saved when the field loses focus
+    deviceNameInput.addEventListener('change', async () => {
+    try {
+        const name = await invoke('set_device_name', { name: deviceNameInput.value });
+        deviceNameInput.placeholder = name;
+        showMessage('Device name saved; other devices see it from the next connection');
+    } catch (e) {
+        showMessage(`Failed to save device name: ${e}`, 'error');
+    }
+});
+
+    // Inbound-while-paused policy
+    inboundWhilePausedSelect.addEventListener('change', async () => {
+    try {
+        await invoke('set_inbound_while_paused', { policy: inboundWhilePausedSelect.value });
+    } catch (e) {
+        showMessage(`Failed to save pause setting: ${e}`, 'error');
+    }
+});
+
     // Load config and set up event listeners
     loadConfig().then(async (config) => {
         // Auto-connect if server URL and secret are configured
@@ -209,7 +309,9 @@ function addHistoryItem(item, timestamp) {
             try {
                 await invoke('connect_to_server', { 
                     serverUrl: config.server_url, 
-                    sharedSecret: config.shared_secret 
+                    sharedSecret: config.shared_secret,
+                    tlsCaBundle: config.tls_ca_bundle,
+                    tlsPinnedSha256: config.tls_pinned_sha256
                 });
                 console.log('Auto-connect successful');
                 updateStatus(true);
@@ -225,21 +327,49 @@ function addHistoryItem(item, timestamp) {
     const { listen } = window.__TAURI__.event;
     
     listen('clipboard-received', (event) => {
-        const { item, timestamp } = event.payload;
-        addHistoryItem(item, timestamp);
+        const { item, timestamp, device_id, device_name } = event.payload;
+        addHistoryItem(item, timestamp, deviceLabel(device_name, device_id));
     });
     
     listen('connection-status', (event) => {
-        updateStatus(event.payload.connected);
-        if (!event.payload.connected && event.payload.error) {
+        updateStatus(event.payload.connected, event.payload.reconnecting ? event.payload.status : null);
+        if (event.payload.error) {
             showMessage(event.payload.error, 'error');
         }
     });
     
+    listen('transfer-progress', (event) => {
+        const { direction, done, total } = event.payload;
+        if (done >= total) {
+            transferProgress.hidden = true;
+            return;
+        }
+        const verb = direction === 'upload' ? 'Sending' : 'Receiving';
+        transferProgress.textContent = `${verb} large item... ${Math.floor((done / total) * 100)}%`;
+        transferProgress.hidden = false;
+    });
+    
+    listen('connection-latency', (event) => {
+        latencyText.textContent = `${event.payload.latency_ms} ms`;
+        latencyText.hidden = !isConnected;
+    });
+    
+    listen('clip-not-synced', (event) => {
+        showMessage(event.payload.error, 'error');
+    });
+    
+    listen('devices-changed', (event) => {
+        updateDevices(event.payload.devices);
+    });
+    
+    listen('pause-changed', (event) => {
+        setPaused(event.payload.paused);
+    });
+    
     listen('history-loaded', (event) => {
         const history = event.payload.history;
         history.forEach(entry => {
-            addHistoryItem(entry.item, entry.timestamp);
+            addHistoryItem(entry.item, entry.timestamp, deviceLabel(entry.device_name, entry.device_id));
         });
     });
     